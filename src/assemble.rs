@@ -0,0 +1,624 @@
+pub mod assemble {
+use crate::data_types::dt::*;
+use crate::engrave::engrave::engrave;
+use crate::instructions::instructions::*;
+
+/*
+ * One thing an assembly source asks for. Push is a whole number the score has
+ * to end up pushing, the rest are written the way a listing writes them.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    Play(Instruction),
+    Push(i64),
+    Rest,
+    Barline(BarlineKind),
+    Direction(String),
+}
+
+/*
+ * Reads an assembly source, the same text a listing has before its ";". Any
+ * number of items go on a line, push takes the number after it and anything
+ * after a ";" is a comment. Every item comes with the line it was on.
+ */
+pub fn parse_assembly(source: &str) -> Result<Vec<(usize, Item)>, AssembleError> {
+    let mut items = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let number = index + 1;
+        let code = line.split(';').next().unwrap_or("");
+        let mut tokens = code.split_whitespace();
+        while let Some(token) = tokens.next() {
+            let item = match token {
+                "push" => Item::Push(tokens.next()
+                    .and_then(|operand| operand.parse().ok())
+                    .ok_or(AssembleError::MissingOperand(number))?),
+                "rest" => Item::Rest,
+                "|" => Item::Barline(BarlineKind::Single),
+                "||" => Item::Barline(BarlineKind::Final),
+                "|:" => Item::Barline(BarlineKind::RepeatStart),
+                ":|" => Item::Barline(BarlineKind::RepeatEnd { times: None }),
+                _ => match token.strip_prefix(":|x").map(|times| times.parse::<usize>()) {
+                    Some(Ok(times)) => Item::Barline(BarlineKind::RepeatEnd { times: Some(times) }),
+                    Some(Err(_)) => {
+                        return Err(AssembleError::UnknownInstruction(token.to_string(), number));
+                    },
+                    None if token.contains(':') => Item::Direction(token.to_string()),
+                    None => Item::Play(Instruction::from_name(token)
+                        .ok_or_else(|| AssembleError::UnknownInstruction(token.to_string(), number))?),
+                },
+            };
+            items.push((number, item));
+        }
+    }
+    Ok(items)
+}
+
+// Every number a single note can push, with the rhythm that pushes it
+const LITERALS: [(i64, Beats); 10] = [(0, Beats::ThirtySecond), (1, Beats::Sixteenth),
+    (2, Beats::Eighth), (3, Beats::DottedEighth), (4, Beats::Quarter), (6, Beats::DottedQuarter),
+    (8, Beats::Half), (12, Beats::DottedHalf), (16, Beats::Whole), (24, Beats::DottedWhole)];
+
+// What notes and rests are made as long as when nothing says otherwise, longest first
+const FREE_RHYTHMS: [Beats; 4] = [Beats::Quarter, Beats::Eighth, Beats::Sixteenth,
+    Beats::ThirtySecond];
+
+// The literals that fit in a measure capacity ticks long
+fn literals(capacity: usize) -> Vec<i64> {
+    LITERALS.iter()
+        .filter(|(_, rhythm)| rhythm.ticks() <= capacity)
+        .map(|(literal, _)| *literal)
+        .collect()
+}
+
+/*
+ * A number built out of pushes the rhythms can say. Only a few numbers fit in
+ * one note, the rest are put together with add, sub and mul out of the
+ * literals a measure has room for.
+ */
+fn push_number(n: i64, literals: &[i64]) -> Vec<Item> {
+    let largest = literals.iter().copied().max().unwrap_or(0);
+    let join = |parts: Vec<Vec<Item>>| parts.into_iter().flatten().collect();
+    if literals.contains(&n) {
+        vec![Item::Push(n)]
+    } else if n == i64::MIN {
+        // Too big to turn around, half of it twice is fine
+        join(vec![push_number(n / 2, literals), push_number(2, literals),
+            vec![Item::Play(Instruction::Mul)]])
+    } else if n < 0 {
+        join(vec![vec![Item::Push(0)], push_number(-n, literals),
+            vec![Item::Play(Instruction::Sub)]])
+    } else if n > largest {
+        let (times, left) = (n / largest, n % largest);
+        let mut items = match times {
+            1 => vec![Item::Push(largest)],
+            _ => join(vec![push_number(times, literals),
+                vec![Item::Push(largest), Item::Play(Instruction::Mul)]]),
+        };
+        if left > 0 {
+            items.extend(push_number(left, literals));
+            items.push(Item::Play(Instruction::Add));
+        }
+        items
+    } else {
+        let below = literals.iter().copied().filter(|&l| l < n).max().unwrap_or(0);
+        join(vec![vec![Item::Push(below)], push_number(n - below, literals),
+            vec![Item::Play(Instruction::Add)]])
+    }
+}
+
+/*
+ * The pitch class that plays an instruction and whether it has to be a
+ * chord. Single notes win over chords and white keys over black ones since
+ * those need the fewest accidentals.
+ */
+fn pitch_for(table: &InstructionTable, instruction: Instruction) -> Option<(usize, bool)> {
+    const BLACK_KEYS: [usize; 5] = [1, 3, 6, 8, 10];
+    [(&table.notes, false), (&table.chords, true)].into_iter()
+        .flat_map(|(slots, chord)| (0..12)
+            .filter(move |&class| slots[class] == instruction)
+            .map(move |class| (class, chord)))
+        .min_by_key(|&(class, chord)| (chord, BLACK_KEYS.contains(&class)))
+}
+
+/*
+ * The ways to write a pitch class, plain letters first, then sharps, then
+ * flats.
+ */
+fn spellings(class: usize) -> Vec<(&'static str, Accidental)> {
+    let mut found = Vec::new();
+    for (shift, accidental) in [(0, Accidental::Natural), (1, Accidental::Sharp),
+        (11, Accidental::Flat)] {
+        for name in NOTE_ORDER {
+            let natural = Note { accidental: Accidental::Natural, note_name: name, octave: 4,
+                rest: false };
+            if (natural.pitch_class() + shift) % 12 == class {
+                found.push((name, accidental.clone()));
+            }
+        }
+    }
+    found
+}
+
+/*
+ * How to turn an assembly source into a score. Everything goes on one clef in
+ * one time signature, measures_per_line measures to a line.
+ */
+#[derive(Clone, Debug)]
+pub struct Assembler {
+    pub table: InstructionTable,
+    pub clef: StaffType,
+    pub time_signature: TimeSignature,
+    pub measures_per_line: usize,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Assembler {
+            table: InstructionTable::default(),
+            clef: StaffType::Treble,
+            time_signature: TimeSignature { beats: 4, beat_size: Beats::Quarter },
+            measures_per_line: 4,
+        }
+    }
+}
+
+/*
+ * Notes down that a line plays letter with accidental, false if it already
+ * plays it some other way.
+ */
+fn mark(letters: &mut Vec<(&'static str, Accidental)>, letter: &'static str,
+    accidental: Accidental) -> bool {
+    match letters.iter().find(|(other, _)| *other == letter) {
+        Some((_, used)) => *used == accidental,
+        None => {
+            letters.push((letter, accidental));
+            true
+        },
+    }
+}
+
+// The letter a chord stacks a third above name
+fn third_above(name: &'static str) -> &'static str {
+    let index = NOTE_ORDER.iter().position(|&n| n == name).unwrap_or(0);
+    NOTE_ORDER[(index + 2) % 7]
+}
+
+/*
+ * The score as it is being written. Bars is the line being filled, events the
+ * measure being filled and used how much of it is taken. Spelled is every
+ * letter the line has used and how, since a line only has one key signature.
+ * Inherited is the key the parser gives a line that doesn't write one.
+ */
+struct Score<'a> {
+    assembler: &'a Assembler,
+    lines: Vec<(Vec<Bar>, KeySignature)>,
+    bars: Vec<Bar>,
+    events: Vec<Event>,
+    used: usize,
+    spelled: Vec<(&'static str, Accidental)>,
+    inherited: KeySignature,
+    // The source line of a skip_bar in the measure being filled
+    skip: Option<usize>,
+    measure: usize,
+}
+
+impl<'a> Score<'a> {
+    fn new(assembler: &'a Assembler) -> Self {
+        Score {
+            assembler,
+            lines: Vec::new(),
+            bars: Vec::new(),
+            events: Vec::new(),
+            used: 0,
+            spelled: Vec::new(),
+            inherited: KeySignature::default(),
+            skip: None,
+            measure: 1,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        let time = &self.assembler.time_signature;
+        time.beats * time.beat_size.ticks()
+    }
+
+    /*
+     * Fills what is left of the measure with rests and ends it with barline.
+     */
+    fn close(&mut self, kind: BarlineKind) {
+        let mut left = self.capacity() - self.used;
+        for rest in [Beats::Whole, Beats::Half, Beats::Quarter, Beats::Eighth, Beats::Sixteenth,
+            Beats::ThirtySecond] {
+            while rest.ticks() <= left {
+                left -= rest.ticks();
+                self.events.push(Event::Rest(RestEvent { duration: rest.clone(), column: 0 }));
+            }
+        }
+        self.events.push(Event::Barline(Barline { kind, column: 0 }));
+        self.bars.push(Bar {
+            measure_number: self.measure,
+            events: std::mem::take(&mut self.events),
+        });
+        self.measure += 1;
+        self.used = 0;
+        self.skip = None;
+        if self.bars.len() >= self.assembler.measures_per_line {
+            self.new_line();
+        }
+    }
+
+    /*
+     * Ends the line with the key signature its notes need. A line without one
+     * would be read in the key of the line before it, a sharp on a letter the
+     * line never plays keeps that from happening.
+     */
+    fn new_line(&mut self) {
+        if !self.bars.is_empty() {
+            let mut key = KeySignature {
+                accidentals: self.spelled.iter()
+                    .filter(|(_, accidental)| *accidental != Accidental::Natural)
+                    .cloned()
+                    .collect(),
+            };
+            if key.accidentals.is_empty() && !self.inherited.accidentals.is_empty() {
+                let unused = NOTE_ORDER.iter()
+                    .find(|name| self.spelled.iter().all(|(other, _)| other != *name));
+                if let Some(name) = unused {
+                    key.accidentals.push((name, Accidental::Sharp));
+                }
+            }
+            self.inherited = key.clone();
+            self.lines.push((std::mem::take(&mut self.bars), key));
+        }
+        self.spelled.clear();
+    }
+
+    /*
+     * Ends a measure the source didn't ask to end. Anything after a skip_bar
+     * has to stay in its measure or the skip would land somewhere else.
+     */
+    fn break_measure(&mut self) -> Result<(), AssembleError> {
+        if let Some(line) = self.skip {
+            return Err(AssembleError::SkipBarOverflow(line));
+        }
+        self.close(BarlineKind::Single);
+        Ok(())
+    }
+
+    /*
+     * A spelling the line can still use. One letter is always left free when
+     * the line might need a key signature just to not inherit one.
+     */
+    fn spell(&self, class: usize, chord: bool) -> Option<(&'static str, Accidental)> {
+        spellings(class).into_iter().find(|(name, accidental)| {
+            let mut letters = self.spelled.clone();
+            if !mark(&mut letters, name, accidental.clone()) {
+                return false;
+            }
+            if chord {
+                mark(&mut letters, third_above(name), Accidental::Natural);
+            }
+            let natural = letters.iter().all(|(_, used)| *used == Accidental::Natural);
+            !(letters.len() == NOTE_ORDER.len() && natural
+                && !self.inherited.accidentals.is_empty())
+        })
+    }
+
+    // The letter at the nearest spot to the middle of the staff
+    fn place(&self, name: &'static str, accidental: Accidental, above: usize) -> Note {
+        let position = |note: &Note| note.octave * 7
+            + NOTE_ORDER.iter().position(|&n| n == note.note_name).unwrap_or(0);
+        let center = position(match self.assembler.clef {
+            StaffType::Treble => &TREBLE_CENTER,
+            StaffType::Bass => &BASS_CENTER,
+        });
+        let index = NOTE_ORDER.iter().position(|&n| n == name).unwrap_or(0);
+        let spot = (center - 3..=center + 3).find(|spot| spot % 7 == index).unwrap_or(center);
+        let spot = spot + above;
+        Note {
+            accidental,
+            note_name: NOTE_ORDER[spot % 7],
+            octave: spot / 7,
+            rest: false,
+        }
+    }
+
+    /*
+     * Writes a note, chord or (without a pitch) rest. A fixed rhythm moves to
+     * the next measure when it doesn't fit, a free one gets shorter first.
+     * A pitch the line can't spell starts a new line.
+     */
+    fn write(&mut self, pitch: Option<(usize, bool)>, rhythm: &Beats, fixed: bool)
+        -> Result<(), AssembleError> {
+        if let Some((class, chord)) = pitch {
+            if self.spell(class, chord).is_none() {
+                // A repeat sign or direction with nothing after it yet moves along
+                let waiting = match self.events.iter().any(|event| event.duration().is_some()) {
+                    true => Vec::new(),
+                    false => std::mem::take(&mut self.events),
+                };
+                if !self.events.is_empty() {
+                    self.break_measure()?;
+                }
+                self.new_line();
+                self.events = waiting;
+            }
+        }
+
+        let left = self.capacity() - self.used;
+        let duration = if fixed {
+            if rhythm.ticks() > left {
+                self.break_measure()?;
+            }
+            rhythm.clone()
+        } else {
+            if left == 0 {
+                self.break_measure()?;
+            }
+            let left = self.capacity() - self.used;
+            FREE_RHYTHMS.iter()
+                .filter(|free| free.ticks() <= rhythm.ticks())
+                .find(|free| free.ticks() <= left)
+                .cloned()
+                .unwrap_or(Beats::ThirtySecond)
+        };
+        self.used += duration.ticks();
+
+        let event = match pitch {
+            None => Event::Rest(RestEvent { duration, column: 0 }),
+            Some((class, chord)) => {
+                // Always spellable now, a new line has every letter free
+                let (name, accidental) = self.spell(class, chord)
+                    .unwrap_or(("C", Accidental::Natural));
+                let lowest = self.place(name, accidental.clone(), 0);
+                mark(&mut self.spelled, name, accidental);
+                if chord {
+                    // A third above on whatever the line already made that letter
+                    let letter = third_above(name);
+                    let upper = self.spelled.iter()
+                        .find(|(other, _)| *other == letter)
+                        .map_or(Accidental::Natural, |(_, used)| used.clone());
+                    mark(&mut self.spelled, letter, upper.clone());
+                    let pitches = vec![lowest, self.place(name, upper, 2)];
+                    Event::Chord(ChordEvent { pitches, duration, column: 0 })
+                } else {
+                    Event::Note(NoteEvent { pitch: lowest, duration, column: 0 })
+                }
+            },
+        };
+        self.events.push(event);
+        Ok(())
+    }
+}
+
+impl Assembler {
+    /*
+     * The bars an assembly source turns into, split into lines. Notes and
+     * chords come from the table, pushes get the rhythm of their number and
+     * everything else a quarter, or as much of one as the measure has left.
+     * A measure with a skip_bar in it is squeezed into shorter notes so it
+     * stays one measure like its source. Leftover space is filled with rests.
+     */
+    pub fn assemble_lines(&self, source: &str) -> Result<Vec<Line>, AssembleError> {
+        let mut score = Score::new(self);
+        let literals = literals(score.capacity());
+        if !literals.contains(&4) {
+            return Err(AssembleError::ShortMeasures);
+        }
+        let rhythm_of = |literal: i64| LITERALS.iter()
+            .find(|(l, _)| *l == literal)
+            .map_or(Beats::Quarter, |(_, rhythm)| rhythm.clone());
+        let pitch = |instruction: Instruction| pitch_for(&self.table, instruction)
+            .ok_or_else(|| AssembleError::NotInTable(instruction.name().to_string()));
+
+        let items: Vec<(usize, Item)> = parse_assembly(source)?.into_iter()
+            .flat_map(|(line, item)| match item {
+                Item::Push(n) => push_number(n, &literals).into_iter()
+                    .map(|item| (line, item))
+                    .collect(),
+                item => vec![(line, item)],
+            })
+            .collect();
+
+        // Free notes are quarters unless their measure has a skip_bar to keep whole
+        let mut free = vec![Beats::Quarter; items.len()];
+        for section in items.iter().enumerate()
+            .collect::<Vec<_>>()
+            .split(|(_, (_, item))| matches!(item, Item::Barline(_))) {
+            if !section.iter().any(|(_, (_, item))| *item == Item::Play(Instruction::SkipBar)) {
+                continue;
+            }
+            let fixed: usize = section.iter()
+                .filter_map(|(_, (_, item))| match item {
+                    Item::Push(n) => Some(rhythm_of(*n).ticks()),
+                    _ => None,
+                })
+                .sum();
+            let count = section.iter()
+                .filter(|(_, (_, item))| matches!(item, Item::Play(_) | Item::Rest))
+                .count();
+            let fits = FREE_RHYTHMS.iter()
+                .find(|rhythm| fixed + count * rhythm.ticks() <= score.capacity())
+                .unwrap_or(&Beats::ThirtySecond);
+            for (index, _) in section {
+                free[*index] = fits.clone();
+            }
+        }
+
+        for ((line, item), rhythm) in items.into_iter().zip(free) {
+            match item {
+                Item::Push(n) => score.write(Some(pitch(Instruction::Push)?), &rhythm_of(n), true)?,
+                Item::Play(Instruction::Nop) if pitch(Instruction::Nop).is_err() => {
+                    // A nop does nothing, which a rest does just as well
+                    score.write(None, &rhythm, false)?
+                },
+                Item::Play(instruction) => {
+                    score.write(Some(pitch(instruction)?), &rhythm, false)?;
+                    if instruction == Instruction::SkipBar {
+                        score.skip = Some(line);
+                    }
+                },
+                Item::Rest => score.write(None, &rhythm, false)?,
+                Item::Direction(text) => {
+                    score.events.push(Event::Direction(Direction { text, column: 0 }));
+                },
+                Item::Barline(BarlineKind::RepeatStart) => {
+                    if !score.events.is_empty() {
+                        score.close(BarlineKind::Single);
+                    }
+                    score.events.push(Event::Barline(Barline {
+                        kind: BarlineKind::RepeatStart,
+                        column: 0,
+                    }));
+                },
+                Item::Barline(kind @ BarlineKind::RepeatEnd { .. }) => score.close(kind),
+                Item::Barline(kind) if !score.events.is_empty() => score.close(kind),
+                Item::Barline(kind) => {
+                    // Nothing to end, a || still marks the measure before it as the last
+                    let last = score.bars.last_mut()
+                        .or(score.lines.last_mut().and_then(|(bars, _)| bars.last_mut()));
+                    if let (BarlineKind::Final, Some(bar)) = (kind, last) {
+                        if let Some(Event::Barline(barline)) = bar.events.last_mut() {
+                            barline.kind = BarlineKind::Final;
+                        }
+                    }
+                },
+            }
+        }
+        if !score.events.is_empty() || (score.bars.is_empty() && score.lines.is_empty()) {
+            score.close(BarlineKind::Final);
+        }
+        score.new_line();
+
+        Ok(score.lines.into_iter()
+            .enumerate()
+            .map(|(index, (contents, key_signature))| Line {
+                clef_type: self.clef,
+                key_signature,
+                time_signature: (index == 0).then(|| self.time_signature.clone()),
+                layout: Layout::default(),
+                contents,
+            })
+            .collect())
+    }
+
+    /*
+     * An assembly source as a score that parses back to the same program.
+     */
+    pub fn assemble(&self, source: &str) -> Result<String, AssembleError> {
+        Ok(engrave(&self.assemble_lines(source)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disasm::disassemble;
+    use crate::interpreter::interpreter::Interpreter;
+    use crate::parser::parser::{parse_file, parse_str};
+    use crate::streams::streams::{SharedOutput, Streams};
+
+    // How a piece ends, what it leaves on the stack and what it writes
+    fn play(lines: &[Line], input: &'static str) -> (bool, Vec<i64>, String) {
+        let output = SharedOutput::new();
+        let mut interpreter = Interpreter::default()
+            .with_streams(Streams::new(input.as_bytes(), output.clone()));
+        let ended = interpreter.run(lines).is_ok();
+        (ended, interpreter.machine.stack, output.text())
+    }
+
+    // The instructions, repeats and directions of a listing, no rests or plain barlines
+    fn program(lines: &[Line]) -> Vec<String> {
+        disassemble("piece.inst", lines, &InstructionTable::default(), OpcodeMode::Absolute)
+            .into_iter()
+            .map(|line| line.text)
+            .filter(|text| !["rest", "|", "||"].contains(&text.as_str()))
+            .collect()
+    }
+
+    // The same for a source, with its pushes made out of the literals a measure fits
+    fn source_program(source: &str, capacity: usize) -> Vec<String> {
+        parse_assembly(source).unwrap().into_iter()
+            .flat_map(|(_, item)| match item {
+                Item::Push(n) => push_number(n, &literals(capacity)),
+                item => vec![item],
+            })
+            .filter_map(|item| match item {
+                Item::Push(n) => Some(format!("push {}", n)),
+                Item::Play(instruction) => Some(instruction.name().to_string()),
+                Item::Direction(text) => Some(text),
+                Item::Barline(BarlineKind::RepeatStart) => Some("|:".to_string()),
+                Item::Barline(BarlineKind::RepeatEnd { times: None }) => Some(":|".to_string()),
+                Item::Barline(BarlineKind::RepeatEnd { times: Some(times) }) => {
+                    Some(format!(":|x{}", times))
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_assemble_listings_back() {
+        // Skip_and_halt has measures six quarters long
+        for (file, input, beats) in [("arithmetic.inst", "", 4), ("tape_and_repeats.inst", "", 4),
+            ("chords_and_repeats.inst", "", 4), ("skip_and_halt.inst", "", 8),
+            ("echo.inst", "21\nAB", 4), ("hello.inst", "", 4), ("recursion.inst", "", 4)] {
+            let assembler = Assembler {
+                time_signature: TimeSignature { beats, beat_size: Beats::Quarter },
+                ..Assembler::default()
+            };
+            let lines = parse_file(format!("testing_resources/{}", file)).unwrap();
+            let source: String = disassemble(file, &lines, &InstructionTable::default(),
+                OpcodeMode::Absolute).iter()
+                .map(|line| format!("{}\n", line))
+                .collect();
+
+            let score = assembler.assemble(&source).unwrap();
+            let assembled = parse_str(&score).unwrap();
+            assert_eq!(source_program(&source, beats * Beats::Quarter.ticks()),
+                program(&assembled), "{}\n{}", file, score);
+            assert_eq!(play(&lines, input), play(&assembled, input), "{}", file);
+        }
+    }
+
+    #[test]
+    fn test_assemble_numbers_and_layout() {
+        let assembler = Assembler {
+            clef: StaffType::Bass,
+            time_signature: TimeSignature { beats: 3, beat_size: Beats::Quarter },
+            ..Assembler::default()
+        };
+        let source = "push 42 push -7 mul write_number ; 42 * -7\n push 5 push 1 add\n||";
+        let lines = parse_str(&assembler.assemble(source).unwrap()).unwrap();
+        assert_eq!(StaffType::Bass, lines[0].clef_type);
+        assert_eq!(Some(TimeSignature { beats: 3, beat_size: Beats::Quarter }),
+            lines[0].time_signature);
+        let bars = lines.iter().flat_map(|line| &line.contents);
+        for bar in bars.filter(|bar| !bar.events.is_empty()) {
+            let ticks: usize = bar.durations().iter().map(|d| d.ticks()).sum();
+            assert_eq!(3 * Beats::Quarter.ticks(), ticks);
+        }
+        assert_eq!((true, vec![6], "-294".to_string()), play(&lines, ""));
+
+        assert_eq!(vec![Item::Push(0), Item::Push(4), Item::Push(3), Item::Play(Instruction::Add),
+            Item::Play(Instruction::Sub)], push_number(-7, &[0, 1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let assembler = Assembler::default();
+        assert_eq!(Some(AssembleError::UnknownInstruction("jump".to_string(), 2)),
+            assembler.assemble("push 1\njump").err());
+        assert_eq!(Some(AssembleError::MissingOperand(1)), assembler.assemble("push x").err());
+        let pushes = InstructionTable {
+            notes: [Instruction::Push; 12],
+            chords: [Instruction::Push; 12],
+        };
+        assert_eq!(Some(AssembleError::NotInTable("dup".to_string())),
+            Assembler { table: pushes, ..Assembler::default() }.assemble("dup").err());
+        // C, C# and D can't share a key signature, so this can't stay one measure
+        assert_eq!(Some(AssembleError::SkipBarOverflow(1)),
+            assembler.assemble("skip_bar push 4 mul add |").err());
+    }
+}
+}
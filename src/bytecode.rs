@@ -0,0 +1,716 @@
+pub mod bytecode {
+use std::fs;
+use std::path::Path;
+use crate::data_types::dt::*;
+use crate::ensemble::ensemble::DEFAULT_TEMPO;
+use crate::instructions::instructions::*;
+use crate::interpreter::interpreter::{find_call, Program, ProgramCounter};
+use crate::trace::trace::pitch_of;
+
+// Every compiled file starts with these four bytes and then the version of
+// the format as two bytes, low byte first
+pub const MAGIC: [u8; 4] = *b"INBC";
+pub const FORMAT_VERSION: u16 = 3;
+
+// What compiled files are called
+pub const EXTENSION: &str = "instc";
+
+/*
+ * One step of a compiled program. Everything that needed the score to work
+ * out is already worked out: notes are decoded, calls point straight at the
+ * program and op they go to and repeats at the op they go back to. Barlines
+ * and directions that don't do anything are gone.
+ *
+ * Interval mode notes can't be decoded ahead of time since the note before
+ * depends on how the piece got there, so they keep their pitch (in semitones,
+ * see Note::semitone) and get decoded as they play.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    Play { operation: Operation, ticks: usize },
+    Interval { semitone: isize, chord: bool, ticks: usize },
+    Wait { ticks: usize },
+    // An end repeat with a count, times is how often it goes back in total
+    Repeat { times: usize, target: usize },
+    // An end repeat without one, which goes back while the value it pops isn't 0
+    RepeatWhile { target: usize },
+    Call { program: usize, target: usize },
+    // A label or the end of the program, both end the passage being played
+    Return,
+    Send(String),
+    Recv(String),
+}
+
+impl Op {
+    /*
+     * What the op is called in the comments of native programs.
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Play { .. } => "play",
+            Op::Interval { .. } => "interval",
+            Op::Wait { .. } => "wait",
+            Op::Repeat { .. } => "repeat",
+            Op::RepeatWhile { .. } => "repeat_while",
+            Op::Call { .. } => "call",
+            Op::Return => "return",
+            Op::Send(_) => "send",
+            Op::Recv(_) => "recv",
+        }
+    }
+
+    /*
+     * How far the op moves the clock, for the ops that take time.
+     */
+    pub fn ticks(&self) -> Option<usize> {
+        match self {
+            Op::Play { ticks, .. } | Op::Interval { ticks, .. } | Op::Wait { ticks } => Some(*ticks),
+            _ => None,
+        }
+    }
+}
+
+/*
+ * The kind of event on the score an op was compiled from.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Note,
+    Chord,
+    Rest,
+    Barline,
+    Direction,
+}
+
+impl EventKind {
+    /*
+     * What traces call it, the same as the interpreter's records.
+     */
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Note => "note",
+            EventKind::Chord => "chord",
+            EventKind::Rest => "rest",
+            EventKind::Barline => "barline",
+            EventKind::Direction => "direction",
+        }
+    }
+
+    pub fn of(event: &Event) -> Option<EventKind> {
+        match event {
+            Event::Note(_) => Some(EventKind::Note),
+            Event::Chord(_) => Some(EventKind::Chord),
+            Event::Rest(_) => Some(EventKind::Rest),
+            Event::Barline(_) => Some(EventKind::Barline),
+            Event::Direction(_) => Some(EventKind::Direction),
+            Event::Tuplet(_) => None,
+        }
+    }
+}
+
+/*
+ * Where an op came from. Measure is the index into CompiledProgram::measures
+ * and column is counted from the start of that measure, the same as events.
+ * Every event on the score makes exactly one op, so counting the ops with an
+ * event counts events the way the interpreter does. The Return at the end of
+ * a program and the repeats the optimizer adds have none. Pitch and text are
+ * what a trace of the event shows, see TraceRecord.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Origin {
+    pub measure: usize,
+    pub column: usize,
+    pub event: Option<EventKind>,
+    pub pitch: Option<String>,
+    pub text: Option<String>,
+}
+
+/*
+ * A measure of a compiled program, the number printed over it on the score
+ * and its first op.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompiledMeasure {
+    pub number: usize,
+    pub start: usize,
+}
+
+/*
+ * One instrument (or a single piece) compiled. Code always ends with a
+ * Return for the end of the file and there is an origin for every op. The
+ * table is kept for interval mode notes.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompiledProgram {
+    pub name: String,
+    pub file: String,
+    pub table: InstructionTable,
+    pub code: Vec<Op>,
+    pub origins: Vec<Origin>,
+    pub measures: Vec<CompiledMeasure>,
+}
+
+impl CompiledProgram {
+    /*
+     * The measure number an op is in, for errors. The Return at the very end
+     * counts as the last measure.
+     */
+    pub fn measure_number(&self, op: usize) -> usize {
+        let measure = self.origins.get(op).map_or(0, |origin| origin.measure);
+        self.measures.get(measure).or(self.measures.last()).map_or(0, |m| m.number)
+    }
+
+    /*
+     * Where a SkipBar at op goes, the first op of the next measure.
+     */
+    pub fn skip_target(&self, op: usize) -> usize {
+        let measure = self.origins.get(op).map_or(0, |origin| origin.measure);
+        self.measures.get(measure + 1).map_or(self.code.len() - 1, |m| m.start)
+    }
+}
+
+/*
+ * A whole piece or project compiled, ready to be saved or run. Players are
+ * the programs that play when it is run, the rest are only there to be
+ * called.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Module {
+    pub tempo: usize,
+    pub programs: Vec<CompiledProgram>,
+    pub players: Vec<usize>,
+}
+
+/*
+ * A single piece compiled on its own with the default table, the way running
+ * an .inst file plays it.
+ */
+pub fn compile_file(file: &str, lines: &[Line]) -> Result<Module, BytecodeError> {
+    let programs = vec![(String::new(), Program::from_lines(lines))];
+    let name = Path::new(file).file_stem().map_or(String::new(), |s| s.to_string_lossy().into());
+    let mut compiled = compile(&programs, 0, &[(file.to_string(), InstructionTable::default())],
+        OpcodeMode::Absolute)?;
+    compiled[0].name = name;
+    Ok(Module { tempo: DEFAULT_TEMPO, programs: compiled, players: vec![0] })
+}
+
+/*
+ * Compiles programs[first..], each with the file and table in sources at the
+ * same position. Programs are laid out the way the interpreter keeps them
+ * (see find_call) and every call is linked while compiling, a call to
+ * something that isn't there is an error here rather than when it plays.
+ * Calls in the compiled programs count from first.
+ */
+pub fn compile(programs: &[(String, Program)], first: usize, sources: &[(String, InstructionTable)],
+    mode: OpcodeMode) -> Result<Vec<CompiledProgram>, BytecodeError> {
+    // Every event makes at most one op, so where each event's op goes (or the
+    // next op if it makes none) can be worked out before compiling anything
+    let starts: Vec<Vec<Vec<usize>>> = programs.iter()
+        .map(|(_, program)| {
+            let mut count = 0;
+            let mut measures = Vec::new();
+            for bar in &program.measures {
+                let mut events = Vec::new();
+                for event in &bar.events {
+                    events.push(count);
+                    count += emits(event) as usize;
+                }
+                events.push(count);
+                measures.push(events);
+            }
+            measures
+        })
+        .collect();
+    let op_at = |program: usize, pc: ProgramCounter| -> usize {
+        let measures = &starts[program];
+        match measures.get(pc.measure) {
+            Some(events) => events[pc.event.min(events.len() - 1)],
+            None => measures.last().map_or(0, |events| events[events.len() - 1]),
+        }
+    };
+
+    programs.iter()
+        .enumerate()
+        .skip(first)
+        .zip(sources)
+        .map(|((index, (name, program)), (file, table))| {
+            let mut decoder = Decoder::new(table.clone(), OpcodeMode::Absolute);
+            let mut compiled = CompiledProgram {
+                name: name.clone(),
+                file: file.clone(),
+                table: table.clone(),
+                code: Vec::new(),
+                origins: Vec::new(),
+                measures: Vec::new(),
+            };
+
+            for (measure, bar) in program.measures.iter().enumerate() {
+                compiled.measures.push(CompiledMeasure {
+                    number: bar.measure_number,
+                    start: compiled.code.len(),
+                });
+                for (event_index, event) in bar.events.iter().enumerate() {
+                    let pc = ProgramCounter { measure, event: event_index };
+                    let ticks = event.duration().map_or(0, |duration| duration.ticks());
+                    let op = match event {
+                        Event::Note(note) if mode == OpcodeMode::Interval => Op::Interval {
+                            semitone: note.pitch.semitone(),
+                            chord: false,
+                            ticks,
+                        },
+                        Event::Chord(chord) if mode == OpcodeMode::Interval => {
+                            match chord.pitches.first() {
+                                Some(lowest) => Op::Interval {
+                                    semitone: lowest.semitone(),
+                                    chord: true,
+                                    ticks,
+                                },
+                                None => Op::Wait { ticks },
+                            }
+                        },
+                        Event::Note(_) | Event::Chord(_) | Event::Rest(_) => {
+                            // Absolute mode looks at the pitch class alone, so it can't fail
+                            match decoder.decode(event, bar.measure_number) {
+                                Ok(Some(operation)) => Op::Play { operation, ticks },
+                                _ => Op::Wait { ticks },
+                            }
+                        },
+                        Event::Barline(Barline { kind: BarlineKind::RepeatEnd { times }, .. }) => {
+                            let target = op_at(index, program.repeat_start(pc));
+                            match times {
+                                Some(times) => Op::Repeat { times: *times, target },
+                                None => Op::RepeatWhile { target },
+                            }
+                        },
+                        Event::Direction(direction) => {
+                            if let Some(target) = direction.marker("call") {
+                                let (program, pc) = find_call(programs, index, target)
+                                    .ok_or(BytecodeError::UnknownCall(target.to_string(),
+                                        bar.measure_number))?;
+                                Op::Call { program: program - first, target: op_at(program, pc) }
+                            } else if direction.marker("label").is_some() {
+                                Op::Return
+                            } else if let Some(channel) = direction.marker("send") {
+                                Op::Send(channel.to_string())
+                            } else if let Some(channel) = direction.marker("recv") {
+                                Op::Recv(channel.to_string())
+                            } else if let Some(name) = direction.marker("host") {
+                                return Err(BytecodeError::HostCall(name.to_string(),
+                                    bar.measure_number));
+                            } else {
+                                Op::Wait { ticks: 0 }
+                            }
+                        },
+                        Event::Barline(_) => Op::Wait { ticks: 0 },
+                        Event::Tuplet(_) => continue,
+                    };
+                    compiled.code.push(op);
+                    compiled.origins.push(Origin {
+                        measure,
+                        column: event.column(),
+                        event: EventKind::of(event),
+                        pitch: pitch_of(event),
+                        text: match event {
+                            Event::Direction(direction) => Some(direction.text.clone()),
+                            _ => None,
+                        },
+                    });
+                }
+            }
+
+            compiled.code.push(Op::Return);
+            compiled.origins.push(Origin {
+                measure: program.measures.len(),
+                ..Origin::default()
+            });
+            Ok(compiled)
+        })
+        .collect()
+}
+
+/*
+ * Whether an event turns into an op. Has to agree with compile, barlines and
+ * directions that do nothing still make a Wait that takes no time.
+ */
+fn emits(event: &Event) -> bool {
+    !matches!(event, Event::Tuplet(_))
+}
+
+/*
+ * Writes numbers as LEB128, seven bits to a byte with the top bit set on all
+ * but the last, so the small numbers that make up most of a program take a
+ * byte each. Signed numbers are zigzagged first (0, -1, 1, -2 ...).
+ */
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn unsigned(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn size(&mut self, value: usize) {
+        self.unsigned(value as u64);
+    }
+
+    fn signed(&mut self, value: i64) {
+        self.unsigned(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn text(&mut self, text: &str) {
+        self.size(text.len());
+        self.0.extend_from_slice(text.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], BytecodeError> {
+        let end = self.at.checked_add(count).filter(|end| *end <= self.bytes.len())
+            .ok_or(BytecodeError::Truncated)?;
+        let taken = &self.bytes[self.at..end];
+        self.at = end;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn unsigned(&mut self) -> Result<u64, BytecodeError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(BytecodeError::Corrupt("a number is too long".to_string()))
+    }
+
+    fn size(&mut self) -> Result<usize, BytecodeError> {
+        usize::try_from(self.unsigned()?)
+            .map_err(|_| BytecodeError::Corrupt("a number is too big".to_string()))
+    }
+
+    fn signed(&mut self) -> Result<i64, BytecodeError> {
+        let value = self.unsigned()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn text(&mut self) -> Result<String, BytecodeError> {
+        let length = self.size()?;
+        String::from_utf8(self.take(length)?.to_vec())
+            .map_err(|_| BytecodeError::Corrupt("a name isn't UTF-8".to_string()))
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, BytecodeError> {
+        let code = self.byte()?;
+        Instruction::from_code(code)
+            .ok_or_else(|| BytecodeError::Corrupt(format!("no instruction numbered {}", code)))
+    }
+}
+
+impl Module {
+    /*
+     * The module in the on-disk format:
+     *
+     *   "INBC", the format version (2 bytes, low first), the tempo
+     *   the number of programs, then for each of them
+     *     name, file, the 12 note and 12 chord instructions of its table
+     *     the number of measures, then each one's number and first op
+     *     the number of ops, then each op's origin (measure, column, the
+     *     kind of event, 0 for none, then its pitch and text, empty for
+     *     none), a tag byte and whatever that op holds
+     *   the number of players and the program of each
+     *
+     * Numbers are LEB128 and text is its length followed by the UTF-8.
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer::default();
+        out.0.extend_from_slice(&MAGIC);
+        out.0.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        out.size(self.tempo);
+
+        out.size(self.programs.len());
+        for program in &self.programs {
+            out.text(&program.name);
+            out.text(&program.file);
+            for instruction in program.table.notes.iter().chain(program.table.chords.iter()) {
+                out.0.push(instruction.code());
+            }
+            out.size(program.measures.len());
+            for measure in &program.measures {
+                out.size(measure.number);
+                out.size(measure.start);
+            }
+            out.size(program.code.len());
+            for (op, origin) in program.code.iter().zip(&program.origins) {
+                out.size(origin.measure);
+                out.size(origin.column);
+                out.0.push(origin.event.map_or(0, |kind| kind as u8 + 1));
+                out.text(origin.pitch.as_deref().unwrap_or(""));
+                out.text(origin.text.as_deref().unwrap_or(""));
+                match op {
+                    Op::Play { operation, ticks } => {
+                        out.0.extend([0, operation.instruction.code()]);
+                        out.signed(operation.operand);
+                        out.size(*ticks);
+                    },
+                    Op::Interval { semitone, chord, ticks } => {
+                        out.0.push(1);
+                        out.signed(*semitone as i64);
+                        out.0.push(*chord as u8);
+                        out.size(*ticks);
+                    },
+                    Op::Wait { ticks } => {
+                        out.0.push(2);
+                        out.size(*ticks);
+                    },
+                    Op::Repeat { times, target } => {
+                        out.0.push(3);
+                        out.size(*times);
+                        out.size(*target);
+                    },
+                    Op::RepeatWhile { target } => {
+                        out.0.push(4);
+                        out.size(*target);
+                    },
+                    Op::Call { program, target } => {
+                        out.0.push(5);
+                        out.size(*program);
+                        out.size(*target);
+                    },
+                    Op::Return => out.0.push(6),
+                    Op::Send(channel) => {
+                        out.0.push(7);
+                        out.text(channel);
+                    },
+                    Op::Recv(channel) => {
+                        out.0.push(8);
+                        out.text(channel);
+                    },
+                }
+            }
+        }
+
+        out.size(self.players.len());
+        for player in &self.players {
+            out.size(*player);
+        }
+        out.0
+    }
+
+    /*
+     * Reads a module back, checking that every jump and call lands somewhere
+     * so running it can't go out of bounds.
+     */
+    pub fn from_bytes(bytes: &[u8]) -> Result<Module, BytecodeError> {
+        let mut input = Reader { bytes, at: 0 };
+        if input.take(MAGIC.len()).map_err(|_| BytecodeError::BadMagic)? != MAGIC {
+            return Err(BytecodeError::BadMagic);
+        }
+        let version = u16::from_le_bytes([input.byte()?, input.byte()?]);
+        if version != FORMAT_VERSION {
+            return Err(BytecodeError::UnsupportedVersion(version, FORMAT_VERSION));
+        }
+        let corrupt = |what: &str| BytecodeError::Corrupt(what.to_string());
+        let tempo = input.size()?;
+
+        let mut programs = Vec::new();
+        for _ in 0..input.size()? {
+            let name = input.text()?;
+            let file = input.text()?;
+            let mut table = InstructionTable::default();
+            for slot in table.notes.iter_mut().chain(table.chords.iter_mut()) {
+                *slot = input.instruction()?;
+            }
+            let mut measures = Vec::new();
+            for _ in 0..input.size()? {
+                measures.push(CompiledMeasure { number: input.size()?, start: input.size()? });
+            }
+
+            let mut code = Vec::new();
+            let mut origins = Vec::new();
+            for _ in 0..input.size()? {
+                let (measure, column) = (input.size()?, input.size()?);
+                let event = match input.byte()? {
+                    0 => None,
+                    1 => Some(EventKind::Note),
+                    2 => Some(EventKind::Chord),
+                    3 => Some(EventKind::Rest),
+                    4 => Some(EventKind::Barline),
+                    5 => Some(EventKind::Direction),
+                    kind => return Err(BytecodeError::Corrupt(format!("no event kind {}", kind))),
+                };
+                let mut optional = || input.text()
+                    .map(|text| Some(text).filter(|text| !text.is_empty()));
+                let (pitch, text) = (optional()?, optional()?);
+                origins.push(Origin { measure, column, event, pitch, text });
+                code.push(match input.byte()? {
+                    0 => {
+                        let instruction = input.instruction()?;
+                        let operand = input.signed()?;
+                        Op::Play { operation: Operation { instruction, operand }, ticks: input.size()? }
+                    },
+                    1 => {
+                        let semitone = input.signed()?;
+                        if !(-MAX_SEMITONE..=MAX_SEMITONE).contains(&semitone) {
+                            return Err(corrupt("a pitch is out of range"));
+                        }
+                        Op::Interval {
+                            semitone: semitone as isize,
+                            chord: input.byte()? != 0,
+                            ticks: input.size()?,
+                        }
+                    },
+                    2 => Op::Wait { ticks: input.size()? },
+                    3 => Op::Repeat { times: input.size()?, target: input.size()? },
+                    4 => Op::RepeatWhile { target: input.size()? },
+                    5 => Op::Call { program: input.size()?, target: input.size()? },
+                    6 => Op::Return,
+                    7 => Op::Send(input.text()?),
+                    8 => Op::Recv(input.text()?),
+                    tag => return Err(BytecodeError::Corrupt(format!("no op tagged {}", tag))),
+                });
+            }
+            if code.last() != Some(&Op::Return) {
+                return Err(corrupt("a program doesn't end with a return"));
+            }
+            programs.push(CompiledProgram { name, file, table, code, origins, measures });
+        }
+
+        let mut players = Vec::new();
+        for _ in 0..input.size()? {
+            players.push(input.size()?);
+        }
+        if input.at != bytes.len() {
+            return Err(corrupt("there is more after the end"));
+        }
+
+        let module = Module { tempo, programs, players };
+        if module.players.iter().any(|player| *player >= module.programs.len()) {
+            return Err(corrupt("a player isn't one of the programs"));
+        }
+        for program in &module.programs {
+            let lands = |target: &usize| *target < program.code.len();
+            let fits = program.measures.iter().all(|m| lands(&m.start))
+                && program.origins.iter().all(|o| o.measure <= program.measures.len());
+            let jumps = program.code.iter().all(|op| match op {
+                Op::Repeat { target, .. } | Op::RepeatWhile { target } => lands(target),
+                Op::Call { program, target } => module.programs.get(*program)
+                    .is_some_and(|callee| *target < callee.code.len()),
+                _ => true,
+            });
+            if !fits || !jumps {
+                return Err(corrupt("a jump goes nowhere"));
+            }
+        }
+        Ok(module)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), BytecodeError> {
+        let path = path.as_ref();
+        fs::write(path, self.to_bytes()).map_err(|_| BytecodeError::Io(path.display().to_string()))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Module, BytecodeError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|_| BytecodeError::Io(path.display().to_string()))?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parser::parse_file;
+    use crate::project::project::Project;
+
+    fn compiled(file: &str) -> Module {
+        compile_file(file, &parse_file(file.to_string()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_bytecode_compile() {
+        let module = compiled("testing_resources/tape_and_repeats.inst");
+        let program = &module.programs[0];
+        assert_eq!("tape_and_repeats", program.name);
+        assert_eq!(program.code.len(), program.origins.len());
+        assert_eq!(Op::Play {
+            operation: Operation { instruction: Instruction::Push, operand: 4 },
+            ticks: TICKS_PER_WHOLE / 4,
+        }, program.code[0]);
+        assert_eq!(Some(&Op::Return), program.code.last());
+
+        // Both kinds of repeat end up jumping back inside the program
+        let repeats: Vec<&Op> = program.code.iter()
+            .filter(|op| matches!(op, Op::Repeat { .. } | Op::RepeatWhile { .. }))
+            .collect();
+        assert_eq!(2, repeats.len());
+        assert!(matches!(repeats[0], Op::Repeat { times: 3, .. }));
+
+        let mut lines = parse_file("testing_resources/recursion.inst".to_string()).unwrap();
+        lines[0].contents[0].events[0] = Event::Direction(Direction {
+            text: "call:nowhere".to_string(),
+            column: 0,
+        });
+        assert_eq!(Err(BytecodeError::UnknownCall("nowhere".to_string(), 1)),
+            compile_file("recursion.inst", &lines));
+    }
+
+    #[test]
+    fn test_bytecode_round_trip() {
+        for module in [compiled("testing_resources/tape_and_repeats.inst"),
+            compiled("testing_resources/chords_and_repeats.inst"),
+            Project::load("testing_resources/projects/duet").unwrap().compile().unwrap(),
+            Project::load("testing_resources/projects/interval_melody").unwrap().compile().unwrap()] {
+            assert_eq!(module, Module::from_bytes(&module.to_bytes()).unwrap());
+        }
+
+        let mut writer = Writer::default();
+        for value in [0, -1, 1, i64::MIN, i64::MAX] {
+            writer.signed(value);
+        }
+        let mut reader = Reader { bytes: &writer.0, at: 0 };
+        for value in [0, -1, 1, i64::MIN, i64::MAX] {
+            assert_eq!(value, reader.signed().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_bytecode_bad_files() {
+        let bytes = compiled("testing_resources/arithmetic.inst").to_bytes();
+        assert_eq!(Err(BytecodeError::BadMagic), Module::from_bytes(b"==\n"));
+
+        let mut newer = bytes.clone();
+        newer[4] = 9;
+        assert_eq!(Err(BytecodeError::UnsupportedVersion(9, FORMAT_VERSION)),
+            Module::from_bytes(&newer));
+        assert_eq!(Err(BytecodeError::Truncated), Module::from_bytes(&bytes[..bytes.len() - 1]));
+
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert!(matches!(Module::from_bytes(&extra), Err(BytecodeError::Corrupt(_))));
+
+        let mut module = Project::load("testing_resources/projects/interval_melody").unwrap()
+            .compile().unwrap();
+        let interval = module.programs.iter_mut()
+            .flat_map(|program| program.code.iter_mut())
+            .find(|op| matches!(op, Op::Interval { .. }))
+            .unwrap();
+        *interval = Op::Interval { semitone: isize::MIN, chord: false, ticks: 0 };
+        assert_eq!(Err(BytecodeError::Corrupt("a pitch is out of range".to_string())),
+            Module::from_bytes(&module.to_bytes()));
+    }
+}
+}
@@ -0,0 +1,655 @@
+pub mod check {
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use crate::data_types::dt::*;
+use crate::instructions::instructions::*;
+use crate::interpreter::interpreter::{find_call, Frame, Program, ProgramCounter,
+    DEFAULT_CALL_DEPTH};
+
+// How many different places in a piece (counting every repeat count and call
+// it can be inside of) the checker looks at before it gives up
+pub const STATE_LIMIT: usize = 200_000;
+
+/*
+ * A place in a .inst file, the row of the staff's middle line and the column
+ * of the event, both counting from 1.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WarningKind {
+    Underflow,
+    Unreachable,
+    EndlessLoop,
+    UnknownCall,
+    TooComplex,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CheckWarning {
+    pub kind: WarningKind,
+    pub span: Span,
+    pub measure: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for CheckWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: measure {}: {}", self.span, self.measure, self.message)
+    }
+}
+
+/*
+ * What the checker knows about the stack at some point: how deep it is at
+ * least and at most (None when it could be any size) and the values on top
+ * that are the same however the piece got there, topmost last.
+ */
+#[derive(Clone, Debug, PartialEq)]
+struct Depth {
+    min: usize,
+    max: Option<usize>,
+    top: Vec<Option<i64>>,
+}
+
+impl Depth {
+    fn empty() -> Self {
+        Depth { min: 0, max: Some(0), top: Vec::new() }
+    }
+
+    fn unknown() -> Self {
+        Depth { min: 0, max: None, top: Vec::new() }
+    }
+
+    fn push(&mut self, value: Option<i64>) {
+        self.min += 1;
+        self.max = self.max.map(|max| max + 1);
+        self.top.push(value);
+    }
+
+    /*
+     * Takes count values off, bottom one first. The caller has already made
+     * sure there can be that many, from here on there are.
+     */
+    fn take(&mut self, count: usize) -> Vec<Option<i64>> {
+        self.min = self.min.max(count) - count;
+        self.max = self.max.map(|max| max - count);
+        let mut values: Vec<Option<i64>> = (0..count).map(|_| self.top.pop().flatten()).collect();
+        values.reverse();
+        values
+    }
+
+    fn join(&self, other: &Depth) -> Depth {
+        let min = self.min.min(other.min);
+        let shared = self.top.len().min(other.top.len()).min(min);
+        let top = self.top[self.top.len() - shared..].iter()
+            .zip(other.top[other.top.len() - shared..].iter())
+            .map(|(a, b)| if a == b { *a } else { None })
+            .collect();
+        let max = self.max.zip(other.max).map(|(a, b)| a.max(b));
+        Depth { min, max, top }
+    }
+}
+
+/*
+ * Where the piece is, as precisely as the interpreter would know it: the
+ * repeat counts and calls it is inside of are part of it, and so is the
+ * semitone of the last pitch in interval mode.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Place {
+    program: usize,
+    pc: ProgramCounter,
+    repeats: Vec<(ProgramCounter, usize)>,
+    frames: Vec<Frame>,
+    previous: Option<isize>,
+}
+
+#[derive(Default)]
+struct Step {
+    next: Vec<(Place, Depth)>,
+    // The piece can end here, finished or stopped by an error
+    stops: bool,
+    warning: Option<CheckWarning>,
+}
+
+impl Step {
+    fn go(place: Place, depth: Depth) -> Self {
+        Step { next: vec![(place, depth)], ..Step::default() }
+    }
+
+    fn stop(warning: Option<CheckWarning>) -> Self {
+        Step { stops: true, warning, ..Step::default() }
+    }
+}
+
+/*
+ * Where each measure of a program came from, the row of its staff's middle
+ * line (from 1) and the column it starts at (from 0).
+ */
+#[derive(Clone, Debug, Default)]
+struct Source {
+    file: String,
+    measures: Vec<(usize, usize)>,
+}
+
+impl Source {
+    fn new(file: &str, lines: &[Line]) -> Self {
+        let measures = lines.iter()
+            .flat_map(|line| (0..line.contents.len()).map(move |index| (
+                line.layout.first_row + line.layout.center_line + 1,
+                line.layout.measure_columns.get(index).copied().unwrap_or(0))))
+            .collect();
+        Source { file: file.to_string(), measures }
+    }
+}
+
+/*
+ * The values an instruction takes off the stack.
+ */
+fn pops(instruction: Instruction) -> usize {
+    use Instruction::*;
+    match instruction {
+        Nop | Push | ReadByte | ReadNumber | ReadLine | Halt => 0,
+        Pop | Dup | Not | Load | WriteChar | WriteNumber | SkipBar => 1,
+        Swap | Over | Add | Sub | Mul | Div | Mod | Eq | Lt | Gt | Store => 2,
+        Rot => 3,
+    }
+}
+
+/*
+ * Some note with the given semitone, all the decoder needs to measure an
+ * interval from.
+ */
+fn note_at(semitone: isize) -> Note {
+    const NAMES: [(&str, Accidental); 12] = [("C", Accidental::Natural),
+        ("C", Accidental::Sharp), ("D", Accidental::Natural), ("D", Accidental::Sharp),
+        ("E", Accidental::Natural), ("F", Accidental::Natural), ("F", Accidental::Sharp),
+        ("G", Accidental::Natural), ("G", Accidental::Sharp), ("A", Accidental::Natural),
+        ("A", Accidental::Sharp), ("B", Accidental::Natural)];
+    let (note_name, accidental) = NAMES[semitone.rem_euclid(12) as usize].clone();
+    let c_octave = semitone.div_euclid(12);
+    let octave = if note_name == "A" || note_name == "B" { c_octave + 1 } else { c_octave };
+    Note { accidental, note_name, octave: octave.max(0) as usize, rest: false }
+}
+
+/*
+ * Looks for mistakes in a piece without playing it. Every way through the
+ * piece is followed, through repeats and into calls, keeping track of how
+ * deep the stack can be and of any values that are known for sure. It warns
+ * about:
+ *
+ * - instructions that take more off the stack than can possibly be on it,
+ * - measures that nothing ever plays,
+ * - repeats that always go back, so the piece can never get past them,
+ * - calls to things that don't exist.
+ *
+ * The checker only warns about things that are sure to go wrong whenever the
+ * piece gets there, a stack that is only sometimes too short is left alone.
+ * The stack starts out empty and anything read from the input or received on
+ * a channel could be any value.
+ */
+#[derive(Clone, Debug)]
+pub struct Checker {
+    pub decoder: Decoder,
+    pub tables: BTreeMap<String, InstructionTable>,
+    pub max_call_depth: usize,
+    programs: Vec<(String, Program)>,
+    sources: Vec<Source>,
+}
+
+impl Default for Checker {
+    fn default() -> Self {
+        Self::new(InstructionTable::default(), OpcodeMode::default())
+    }
+}
+
+impl Checker {
+    pub fn new(table: InstructionTable, mode: OpcodeMode) -> Self {
+        Checker {
+            decoder: Decoder::new(table, mode),
+            tables: BTreeMap::new(),
+            max_call_depth: DEFAULT_CALL_DEPTH,
+            programs: vec![(String::new(), Program::default())],
+            sources: vec![Source::default()],
+        }
+    }
+
+    /*
+     * Makes an instrument callable by name, file is where it was read from.
+     */
+    pub fn add_instrument(&mut self, name: &str, file: &str, lines: &[Line]) {
+        self.programs.push((name.to_string(), Program::from_lines(lines)));
+        self.sources.push(Source::new(file, lines));
+    }
+
+    /*
+     * Checks a piece, warnings come back in the order of the file.
+     */
+    pub fn check(&mut self, file: &str, lines: &[Line]) -> Vec<CheckWarning> {
+        self.programs[0].1 = Program::from_lines(lines);
+        self.sources[0] = Source::new(file, lines);
+
+        let start = Place {
+            program: 0,
+            pc: self.programs[0].1.start(),
+            repeats: Vec::new(),
+            frames: Vec::new(),
+            previous: None,
+        };
+        let mut states: HashMap<Place, Depth> = HashMap::from([(start.clone(), Depth::empty())]);
+        let mut queue = VecDeque::from([start]);
+        let mut edges: HashMap<Place, Vec<Place>> = HashMap::new();
+        let mut stops: HashSet<Place> = HashSet::new();
+        let mut found: HashMap<Place, CheckWarning> = HashMap::new();
+
+        while let Some(place) = queue.pop_front() {
+            if states.len() > STATE_LIMIT {
+                return vec![self.warning(WarningKind::TooComplex, 0, ProgramCounter::default(),
+                    format!("there are too many ways through the piece to check, stopped after {}",
+                        STATE_LIMIT))];
+            }
+
+            let step = self.step(&place, states[&place].clone());
+            found.remove(&place);
+            if let Some(warning) = step.warning {
+                found.insert(place.clone(), warning);
+            }
+            if step.stops {
+                stops.insert(place.clone());
+            }
+            edges.insert(place.clone(), step.next.iter().map(|(next, _)| next.clone()).collect());
+
+            for (next, incoming) in step.next {
+                let joined = match states.get(&next) {
+                    None => incoming,
+                    Some(old) => {
+                        let mut joined = old.join(&incoming);
+                        // A loop that keeps growing the stack could grow it to any size
+                        if joined.max > old.max {
+                            joined.max = None;
+                        }
+                        if joined == *old {
+                            continue;
+                        }
+                        joined
+                    },
+                };
+                states.insert(next.clone(), joined);
+                queue.push_back(next);
+            }
+        }
+
+        let mut warnings: Vec<CheckWarning> = found.into_values().collect();
+        warnings.extend(self.unreachable(&states));
+        warnings.extend(self.endless(&states, &edges, &stops));
+
+        let mut seen = HashSet::new();
+        warnings.retain(|w| seen.insert((w.kind, w.span.clone())));
+        warnings.sort_by(|a, b| (&a.span.file, a.span.line, a.span.column)
+            .cmp(&(&b.span.file, b.span.line, b.span.column)));
+        warnings
+    }
+
+    fn warning(&self, kind: WarningKind, program: usize, pc: ProgramCounter, message: String)
+        -> CheckWarning {
+        let source = &self.sources[program];
+        let (line, start) = source.measures.get(pc.measure).copied().unwrap_or((0, 0));
+        let column = self.programs[program].1.event(pc).map_or(0, |event| event.column());
+        let measure = self.programs[program].1.measures.get(pc.measure)
+            .map_or(0, |bar| bar.measure_number);
+
+        CheckWarning {
+            kind,
+            span: Span { file: source.file.clone(), line, column: start + column + 1 },
+            measure,
+            message,
+        }
+    }
+
+    /*
+     * Leaves the passage being played, back to whatever called it. None once
+     * the piece is over.
+     */
+    fn end_passage(mut place: Place) -> Option<Place> {
+        let frame = place.frames.pop()?;
+        place.program = frame.program;
+        place.pc = frame.pc;
+        place.repeats = frame.repeats;
+        Some(place)
+    }
+
+    /*
+     * Does to the place and the stack what playing the event there would.
+     */
+    fn step(&self, place: &Place, mut depth: Depth) -> Step {
+        let program = &self.programs[place.program].1;
+        let Some(event) = program.event(place.pc) else {
+            return match Self::end_passage(place.clone()) {
+                Some(next) => Step::go(next, depth),
+                None => Step::stop(None),
+            };
+        };
+        let mut next = place.clone();
+        next.pc = program.next(place.pc);
+
+        let underflow = |count: usize, what: String| -> Option<CheckWarning> {
+            let max = depth.max.filter(|max| *max < count)?;
+            let has = match max {
+                0 => "the stack is always empty here".to_string(),
+                1 => "there is at most 1 value on the stack here".to_string(),
+                max => format!("there are at most {} values on the stack here", max),
+            };
+            Some(self.warning(WarningKind::Underflow, place.program, place.pc,
+                format!("{} takes {} off the stack but {}", what,
+                    if count == 1 { "a value".to_string() } else { format!("{} values", count) },
+                    has)))
+        };
+
+        match event {
+            Event::Barline(Barline { kind: BarlineKind::RepeatEnd { times: Some(times) }, .. }) => {
+                let index = next.repeats.iter().position(|(at, _)| *at == place.pc);
+                let left = match index {
+                    Some(index) => next.repeats.remove(index).1,
+                    None => times.saturating_sub(1),
+                };
+                if left > 0 {
+                    next.repeats.push((place.pc, left - 1));
+                    next.pc = program.repeat_start(place.pc);
+                }
+                Step::go(next, depth)
+            },
+            Event::Barline(Barline { kind: BarlineKind::RepeatEnd { times: None }, .. }) => {
+                if let Some(warning) = underflow(1, "The end repeat".to_string()) {
+                    return Step::stop(Some(warning));
+                }
+                let value = depth.take(1)[0];
+                let mut step = Step::default();
+                if value != Some(0) {
+                    let mut back = next.clone();
+                    back.pc = program.repeat_start(place.pc);
+                    step.next.push((back, depth.clone()));
+                }
+                if value.is_none() || value == Some(0) {
+                    step.next.push((next, depth));
+                }
+                step
+            },
+            Event::Direction(direction) => {
+                if let Some(target) = direction.marker("call") {
+                    if place.frames.len() >= self.max_call_depth {
+                        return Step::stop(None);
+                    }
+                    let Some((program, pc)) = find_call(&self.programs, place.program, target) else {
+                        return Step::stop(Some(self.warning(WarningKind::UnknownCall,
+                            place.program, place.pc, format!(r#"there is nothing called "{}" to call"#,
+                                target))));
+                    };
+                    let frame = Frame {
+                        program: place.program,
+                        call: place.pc,
+                        pc: next.pc,
+                        repeats: std::mem::take(&mut next.repeats),
+                    };
+                    next.frames.push(frame);
+                    next.program = program;
+                    next.pc = pc;
+                    Step::go(next, depth)
+                } else if direction.marker("label").is_some() {
+                    match Self::end_passage(next) {
+                        Some(next) => Step::go(next, depth),
+                        None => Step::stop(None),
+                    }
+                } else if direction.marker("send").is_some() {
+                    if let Some(warning) = underflow(1, format!(r#""{}""#, direction.text)) {
+                        return Step::stop(Some(warning));
+                    }
+                    depth.take(1);
+                    Step::go(next, depth)
+                } else if direction.marker("recv").is_some() {
+                    depth.push(None);
+                    Step::go(next, depth)
+                } else if direction.marker("host").is_some() {
+                    // A host function could take or leave any number of values
+                    Step::go(next, Depth::unknown())
+                } else {
+                    Step::go(next, depth)
+                }
+            },
+            event => {
+                let table = match place.program {
+                    0 => None,
+                    program => self.tables.get(&self.programs[program].0),
+                };
+                let mut decoder = self.decoder.clone();
+                decoder.previous = place.previous.map(note_at);
+                let measure = program.measures[place.pc.measure].measure_number;
+                let operation = match table {
+                    Some(table) => decoder.decode_with(table, event, measure),
+                    None => decoder.decode(event, measure),
+                };
+                next.previous = decoder.previous.as_ref().map(Note::semitone);
+
+                let Ok(operation) = operation else {
+                    return Step::stop(None);
+                };
+                let Some(Operation { instruction, operand }) = operation else {
+                    return Step::go(next, depth);
+                };
+                if let Some(warning) = underflow(pops(instruction), format!("{:?}", instruction)) {
+                    return Step::stop(Some(warning));
+                }
+
+                use Instruction::*;
+                let values = depth.take(pops(instruction));
+                let pair = || values[0].zip(values[1]);
+                match instruction {
+                    Push => depth.push(Some(operand)),
+                    Dup => {
+                        depth.push(values[0]);
+                        depth.push(values[0]);
+                    },
+                    Swap => {
+                        depth.push(values[1]);
+                        depth.push(values[0]);
+                    },
+                    Over => {
+                        depth.push(values[0]);
+                        depth.push(values[1]);
+                        depth.push(values[0]);
+                    },
+                    Rot => {
+                        depth.push(values[1]);
+                        depth.push(values[2]);
+                        depth.push(values[0]);
+                    },
+                    Add => depth.push(pair().map(|(a, b)| a.wrapping_add(b))),
+                    Sub => depth.push(pair().map(|(a, b)| a.wrapping_sub(b))),
+                    Mul => depth.push(pair().map(|(a, b)| a.wrapping_mul(b))),
+                    Div | Mod => {
+                        if values[1] == Some(0) {
+                            return Step::stop(None);
+                        }
+                        depth.push(pair().map(|(a, b)| if instruction == Div {
+                            a.wrapping_div(b)
+                        } else {
+                            a.wrapping_rem(b)
+                        }));
+                    },
+                    Eq => depth.push(pair().map(|(a, b)| (a == b) as i64)),
+                    Lt => depth.push(pair().map(|(a, b)| (a < b) as i64)),
+                    Gt => depth.push(pair().map(|(a, b)| (a > b) as i64)),
+                    Not => depth.push(values[0].map(|a| (a == 0) as i64)),
+                    Load | ReadByte | ReadNumber => depth.push(None),
+                    ReadLine => {
+                        // Pushes the characters and then how many there were
+                        depth.top.clear();
+                        depth.min += 1;
+                        depth.max = None;
+                    },
+                    SkipBar => {
+                        let mut step = Step::default();
+                        if values[0].is_none() || values[0] == Some(0) {
+                            let mut skip = next.clone();
+                            skip.pc = self.programs[place.program].1.next_measure(place.pc);
+                            step.next.push((skip, depth.clone()));
+                        }
+                        if values[0] != Some(0) {
+                            step.next.push((next, depth));
+                        }
+                        return step;
+                    },
+                    Halt => return Step::stop(None),
+                    Nop | Pop | Store | WriteChar | WriteNumber => {},
+                }
+                Step::go(next, depth)
+            },
+        }
+    }
+
+    /*
+     * Measures of the piece being checked that nothing ever plays, a run of
+     * them gets one warning. Empty measures have nothing to play so they
+     * don't count, they don't break up a run either.
+     */
+    fn unreachable(&self, states: &HashMap<Place, Depth>) -> Vec<CheckWarning> {
+        let program = &self.programs[0].1;
+        let played: HashSet<usize> = states.keys()
+            .filter(|place| place.program == 0 && program.event(place.pc).is_some())
+            .map(|place| place.pc.measure)
+            .collect();
+        let empty = |index: usize| program.measures[index].events.is_empty();
+
+        let mut warnings = Vec::new();
+        let mut index = 0;
+        while index < program.measures.len() {
+            if played.contains(&index) || empty(index) {
+                index += 1;
+                continue;
+            }
+            let first = index;
+            let mut last = index;
+            while index < program.measures.len() && !played.contains(&index) {
+                if !empty(index) {
+                    last = index;
+                }
+                index += 1;
+            }
+
+            let pc = ProgramCounter { measure: first, event: 0 };
+            let message = match last - first {
+                0 => "this measure is never played".to_string(),
+                _ => format!("measures {} to {} are never played",
+                    program.measures[first].measure_number,
+                    program.measures[last].measure_number),
+            };
+            let mut warning = self.warning(WarningKind::Unreachable, 0, pc, message);
+            warning.span.column = self.sources[0].measures[first].1 + 1;
+            warnings.push(warning);
+        }
+        warnings
+    }
+
+    /*
+     * End repeats that always go back from places the piece can never get
+     * out of.
+     */
+    fn endless(&self, states: &HashMap<Place, Depth>, edges: &HashMap<Place, Vec<Place>>,
+        stops: &HashSet<Place>) -> Vec<CheckWarning> {
+        let mut leads_to: HashMap<&Place, Vec<&Place>> = HashMap::new();
+        for (from, targets) in edges {
+            for to in targets {
+                leads_to.entry(to).or_default().push(from);
+            }
+        }
+
+        let mut can_stop: HashSet<&Place> = stops.iter().collect();
+        let mut queue: Vec<&Place> = stops.iter().collect();
+        while let Some(place) = queue.pop() {
+            for from in leads_to.get(place).into_iter().flatten() {
+                if can_stop.insert(from) {
+                    queue.push(from);
+                }
+            }
+        }
+
+        states.keys()
+            .filter(|place| !can_stop.contains(place))
+            .filter(|place| matches!(self.programs[place.program].1.event(place.pc),
+                Some(Event::Barline(Barline { kind: BarlineKind::RepeatEnd { times: None }, .. }))))
+            .filter(|place| edges.get(*place).is_some_and(|targets| targets.len() == 1))
+            .map(|place| self.warning(WarningKind::EndlessLoop, place.program, place.pc,
+                "this repeat always goes back, so the piece never gets past it".to_string()))
+            .collect()
+    }
+}
+
+/*
+ * Checks a piece on its own with the default table.
+ */
+pub fn check(file: &str, lines: &[Line]) -> Vec<CheckWarning> {
+    Checker::default().check(file, lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parser::parse_file;
+
+    fn warnings(file: &str) -> Vec<CheckWarning> {
+        check(file, &parse_file(file.to_string()).unwrap())
+    }
+
+    #[test]
+    fn test_check_clean_pieces() {
+        for file in ["arithmetic", "tape_and_repeats", "recursion", "skip_and_halt", "echo",
+            "hello"] {
+            let file = format!("testing_resources/{}.inst", file);
+            assert_eq!(Vec::<CheckWarning>::new(), warnings(&file), "{}", file);
+        }
+    }
+
+    #[test]
+    fn test_check_underflow() {
+        // Push, then Add with only one value to add
+        let found = warnings("testing_resources/check/underflow.inst");
+        assert_eq!(1, found.len());
+        assert_eq!(WarningKind::Underflow, found[0].kind);
+        assert_eq!("testing_resources/check/underflow.inst:7:16: measure 1: Add takes 2 values \
+            off the stack but there is at most 1 value on the stack here", found[0].to_string());
+
+        // The passage is fine on its own but gets called with an empty stack
+        let found = warnings("testing_resources/check/called_underflow.inst");
+        assert_eq!(vec![WarningKind::Underflow], found.iter().map(|w| w.kind).collect::<Vec<_>>());
+        assert_eq!(2, found[0].measure);
+
+        // Writes a number before anything is pushed, so the rest never plays
+        let found = warnings("testing_resources/chords_and_repeats.inst");
+        assert_eq!(vec![WarningKind::Underflow, WarningKind::Unreachable],
+            found.iter().map(|w| w.kind).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_check_unreachable_and_endless() {
+        // Halts in the second measure so the last two never play
+        let found = warnings("testing_resources/check/halted.inst");
+        assert_eq!(1, found.len());
+        assert_eq!(WarningKind::Unreachable, found[0].kind);
+        assert_eq!("measures 3 to 4 are never played", found[0].message);
+
+        // Pushes 4 every time round and goes back on it
+        let found = warnings("testing_resources/check/endless.inst");
+        assert_eq!(vec![WarningKind::EndlessLoop, WarningKind::Unreachable],
+            found.iter().map(|w| w.kind).collect::<Vec<_>>());
+    }
+}
+}
@@ -16,12 +16,27 @@ pub mod dt {
         rest: false,
     };
 
+    pub const REST: Note = Note {
+        accidental: Accidental::Natural,
+        note_name: "R",
+        octave: 0,
+        rest: true,
+    };
+
     pub const NOTE_ORDER: [&str; 7] = ["A", "B", "C", "D", "E", "F", "G"];
 
+    // Small enough that every rhythm, dotted 32nds and triplets included, is a
+    // whole number of ticks
+    pub const TICKS_PER_WHOLE: usize = 192;
+
     #[derive(Error, Debug)]
     pub enum ParsingError {
         #[error("Invalid Staff Identifier at line {0}")]
         InvalidStaffDeclaration(usize),
+        #[error("Could not find a five line staff in line {0}")]
+        MissingStaff(usize),
+        #[error("Invalid Time Signature in line {0}")]
+        InvalidTimeSignature(usize),
         #[error("Invalid Measure Lengths in measure {0}")]
         InvalidMeasureLenghts(usize),
         #[error("Invalid Note Declatation in measure {0} at {1}")]
@@ -30,7 +45,256 @@ pub mod dt {
         FailedFileRead(String),
     }
 
-    #[derive(Debug)]
+    #[derive(Error, Debug, PartialEq)]
+    pub enum RuntimeError {
+        #[error("Stack underflow in measure {0}")]
+        StackUnderflow(usize),
+        #[error("Division by zero in measure {0}")]
+        DivisionByZero(usize),
+        #[error("Invalid memory address {0} in measure {1}")]
+        InvalidAddress(i64, usize),
+        #[error(r#"Expected a number but read "{0}" in measure {1}"#)]
+        InvalidNumber(String, usize),
+        #[error("{0} is not a character in measure {1}")]
+        InvalidCharacter(i64, usize),
+        #[error("Input or output failed in measure {1}: {0}")]
+        Io(String, usize),
+        #[error("Call stack overflow, more than {0} calls deep in measure {1}")]
+        StackOverflow(usize, usize),
+        #[error(r#"Nothing called "{0}" to call in measure {1}"#)]
+        UnknownCall(String, usize),
+        #[error("Every instrument is waiting for a message on {0}")]
+        Deadlock(String),
+        #[error("Played more than {0} events, the events limit, in measure {1}")]
+        EventLimit(u64, usize),
+        #[error("The stack grew past {0} values, the stack limit, in measure {1}")]
+        StackLimit(usize, usize),
+        #[error("A store went outside the first {0} cells, the tape limit, in measure {1}")]
+        TapeLimit(usize, usize),
+        #[error("Ran for more than {0} ms, the millis limit, in measure {1}")]
+        TimeLimit(u64, usize),
+        #[error("The clock went past {0} ticks, the ticks limit, in measure {1}")]
+        TickLimit(usize, usize),
+        #[error(r#"No host function called "{0}" in measure {1}"#)]
+        UnknownHost(String, usize),
+        #[error(r#"The host function "{0}" failed in measure {2}: {1}"#)]
+        Host(String, String, usize),
+        #[error("The pitch table has no instruction for {0} in measure {1}")]
+        InvalidInstruction(String, usize),
+    }
+
+    impl RuntimeError {
+        /*
+         * What the command line exits with for each kind of error, so a
+         * script can tell them apart. 1 is a file that couldn't be read or
+         * parsed and 2 a bad command line, the rest are:
+         *
+         * 3 stack underflow, 4 division by zero, 5 a bad memory address,
+         * 6 input or output that went wrong, 7 a call to nothing, 8 a
+         * deadlock, 9 going past a limit (call depth included), 10 a host
+         * function that failed and 11 a note the pitch table can't decode.
+         */
+        pub fn exit_code(&self) -> i32 {
+            match self {
+                Self::StackUnderflow(_) => 3,
+                Self::DivisionByZero(_) => 4,
+                Self::InvalidAddress(..) => 5,
+                Self::InvalidNumber(..) | Self::InvalidCharacter(..) | Self::Io(..) => 6,
+                Self::UnknownCall(..) | Self::UnknownHost(..) => 7,
+                Self::Deadlock(_) => 8,
+                Self::StackOverflow(..) | Self::EventLimit(..) | Self::StackLimit(..)
+                    | Self::TapeLimit(..) | Self::TimeLimit(..) | Self::TickLimit(..) => 9,
+                Self::Host(..) => 10,
+                Self::InvalidInstruction(..) => 11,
+            }
+        }
+    }
+
+    /*
+     * A place on the score. The column counts from the start of the measure,
+     * the same way traces count it.
+     */
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub struct SourceLocation {
+        pub file: String,
+        pub measure: usize,
+        pub column: usize,
+    }
+
+    impl std::fmt::Display for SourceLocation {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            if !self.file.is_empty() {
+                write!(f, "{} ", self.file)?;
+            }
+            write!(f, "measure {} column {}", self.measure, self.column)
+        }
+    }
+
+    /*
+     * A RuntimeError with the event it happened at and the calls that got
+     * there, innermost first, so a failure in a called instrument says who
+     * called it. Errors that don't belong to one event, like a deadlock,
+     * have no location.
+     */
+    #[derive(Debug, PartialEq)]
+    pub struct RuntimeFailure {
+        pub error: RuntimeError,
+        pub location: Option<SourceLocation>,
+        pub calls: Vec<SourceLocation>,
+    }
+
+    impl RuntimeFailure {
+        pub fn exit_code(&self) -> i32 {
+            self.error.exit_code()
+        }
+    }
+
+    impl From<RuntimeError> for RuntimeFailure {
+        fn from(error: RuntimeError) -> Self {
+            RuntimeFailure { error, location: None, calls: Vec::new() }
+        }
+    }
+
+    impl std::fmt::Display for RuntimeFailure {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "{}", self.error)?;
+            if let Some(location) = &self.location {
+                write!(f, "\n  at {}", location)?;
+            }
+            // A recursive call fails with the same call hundreds of times
+            // over, so calls in a row from one place get one line
+            for calls in self.calls.chunk_by(|a, b| a == b) {
+                write!(f, "\n  called from {}", calls[0])?;
+                if calls.len() > 1 {
+                    write!(f, " ({} times)", calls.len())?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl std::error::Error for RuntimeFailure {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.error)
+        }
+    }
+
+    #[derive(Error, Debug, PartialEq)]
+    pub enum EngraveError {
+        #[error("{0} can't be written with the same key signature as the rest of the line")]
+        ConflictingAccidental(String),
+        #[error(r#"The direction "{0}" has to be a single word"#)]
+        InvalidDirection(String),
+    }
+
+    #[derive(Error, Debug)]
+    pub enum ReplError {
+        #[error(r#"Can't read "{0}" as shorthand, try :help"#)]
+        InvalidShorthand(String),
+        #[error(r#"Unknown REPL command "{0}", try :help"#)]
+        UnknownCommand(String),
+        #[error(transparent)]
+        Engrave(#[from] EngraveError),
+        #[error(transparent)]
+        Parsing(#[from] ParsingError),
+        #[error(transparent)]
+        Runtime(#[from] RuntimeFailure),
+    }
+
+    #[derive(Error, Debug, PartialEq)]
+    pub enum DebugError {
+        #[error(r#"Unknown debugger command "{0}""#)]
+        UnknownCommand(String),
+        #[error(r#"Invalid breakpoint "{0}", expected file:measure or a measure number"#)]
+        InvalidBreakpoint(String),
+        #[error(r#"Invalid address "{0}""#)]
+        InvalidAddress(String),
+        #[error("Nothing left in the history to go back to")]
+        NoHistory,
+        #[error("Measure {0} is not in the recorded history")]
+        NotInHistory(String),
+        #[error("No write to address {0} in the recorded history")]
+        NoWrite(usize),
+        #[error(transparent)]
+        Runtime(#[from] RuntimeFailure),
+    }
+
+    #[derive(Error, Debug, PartialEq)]
+    pub enum BytecodeError {
+        #[error(r#"Nothing called "{0}" to call in measure {1}"#)]
+        UnknownCall(String, usize),
+        #[error(r#""host:{0}" in measure {1} calls the program running the piece, which only the interpreter can do"#)]
+        HostCall(String, usize),
+        #[error("Not a compiled instrument-lang file")]
+        BadMagic,
+        #[error("Compiled for format version {0} but this build reads version {1}")]
+        UnsupportedVersion(u16, u16),
+        #[error("The compiled file ends too early")]
+        Truncated,
+        #[error("The compiled file is damaged: {0}")]
+        Corrupt(String),
+        #[error(r#"Failed to read or write "{0}""#)]
+        Io(String),
+    }
+
+    #[derive(Error, Debug, PartialEq)]
+    pub enum AssembleError {
+        #[error(r#"Line {1}: unknown instruction "{0}""#)]
+        UnknownInstruction(String, usize),
+        #[error("Line {0}: push needs a whole number after it")]
+        MissingOperand(usize),
+        #[error("Nothing in the instruction table plays {0}")]
+        NotInTable(String),
+        #[error("Line {0}: the measure with this skip_bar doesn't fit in one measure, end it sooner or pick a longer time signature")]
+        SkipBarOverflow(usize),
+        #[error("A measure has to hold at least a quarter note")]
+        ShortMeasures,
+        #[error(transparent)]
+        Engrave(#[from] EngraveError),
+    }
+
+    #[derive(Error, Debug)]
+    pub enum ProjectError {
+        #[error(r#"Failed to read project: "{0}""#)]
+        FailedProjectRead(String),
+        #[error("Invalid expression.toml: {0}")]
+        InvalidExpression(String),
+        #[error(r#""{0}" in {1} isn't a pitch name like C, F# or Bb"#)]
+        InvalidPitchName(String, String),
+        #[error("{0} and {1} are the same pitch but play different instructions in {2}")]
+        ConflictingPitches(String, String, String),
+        #[error(r#"{0} starts from base = "empty" but leaves out {1}"#)]
+        TableGap(String, String),
+        #[error(r#"expression.toml has settings for "{0}" but there is no instruments/{0}.inst"#)]
+        UnknownInstrument(String),
+        #[error(transparent)]
+        Parsing(#[from] ParsingError),
+        #[error(transparent)]
+        Runtime(#[from] RuntimeFailure),
+        #[error(transparent)]
+        Bytecode(#[from] BytecodeError),
+    }
+
+    /*
+     * A problem the parser noticed but could work around. Rows and columns are
+     * relative to the music line and measure they were found in.
+     */
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Diagnostic {
+        pub measure: usize,
+        pub row: usize,
+        pub column: usize,
+        pub message: String,
+        pub suggestion: Option<String>,
+    }
+
+    #[derive(Clone, Debug, Default)]
+    pub struct ParseOptions {
+        // Pad ragged rows and line up barlines instead of rejecting the measure
+        pub tolerant: bool,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub enum StaffType {
         Treble,
         Bass,
@@ -62,19 +326,113 @@ pub mod dt {
         Natural,
     }
 
-    #[derive(Debug)]
+    /*
+     * The letters that are sharp or flat for a whole line of music, in the
+     * order they were written.
+     */
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct KeySignature {
+        pub accidentals: Vec<(&'static str, Accidental)>,
+    }
+
+    #[derive(Clone, Debug)]
     pub struct Line {
         pub clef_type: StaffType,
+        pub key_signature: KeySignature,
+        pub time_signature: Option<TimeSignature>,
+        pub layout: Layout,
+        pub contents: Vec<Bar>,
+    }
+
+    /*
+     * Where a line of music sits on the page. Rows are the grid the notes were
+     * read from (text rows already dropped), first_row is the row of the file
+     * the grid starts on (counting from 0) and measure_columns is the column of
+     * the first character of each measure in those rows.
+     */
+    #[derive(Clone, Debug, Default)]
+    pub struct Layout {
         pub line_height: usize,
+        pub first_row: usize,
         pub center_line: usize,
-        pub contents: Vec<Bar>,
+        pub rows: Vec<String>,
+        pub measure_columns: Vec<usize>,
     }
 
-    #[derive(Debug)]
+    #[derive(Clone, Debug)]
     pub struct Bar {
-        pub pitches: Vec<Note>,
-        pub durations: Vec<Beats>,
         pub measure_number: usize,
+        pub events: Vec<Event>,
+    }
+
+    /*
+     * Everything that can happen in a measure, in the order it is written.
+     * Columns are counted from the start of the measure.
+     */
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Event {
+        Note(NoteEvent),
+        Rest(RestEvent),
+        Chord(ChordEvent),
+        Barline(Barline),
+        Direction(Direction),
+        Tuplet(TupletGroup),
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct NoteEvent {
+        pub pitch: Note,
+        pub duration: Beats,
+        pub column: usize,
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct RestEvent {
+        pub duration: Beats,
+        pub column: usize,
+    }
+
+    /*
+     * Several note heads on one stem, lowest pitch first.
+     */
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct ChordEvent {
+        pub pitches: Vec<Note>,
+        pub duration: Beats,
+        pub column: usize,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum BarlineKind {
+        Single,
+        Final,
+        RepeatStart,
+        RepeatEnd { times: Option<usize> },
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Barline {
+        pub kind: BarlineKind,
+        pub column: usize,
+    }
+
+    /*
+     * A word of text written above or below the staff.
+     */
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Direction {
+        pub text: String,
+        pub column: usize,
+    }
+
+    /*
+     * The notes between a "<" and a ">". Their durations are already the
+     * triplet versions.
+     */
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct TupletGroup {
+        pub events: Vec<Event>,
+        pub column: usize,
     }
 
     #[derive(Clone, Debug)]
@@ -85,6 +443,7 @@ pub mod dt {
         pub rest: bool,
     }
 
+    #[derive(Clone, Debug, PartialEq)]
     pub struct TimeSignature {
         pub beats: usize,
         pub beat_size: Beats,
@@ -104,6 +463,17 @@ pub mod dt {
         }
     }
 
+    impl std::fmt::Display for Diagnostic {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "measure {}, row {}, column {}: {}",
+                self.measure, self.row, self.column, self.message)?;
+            if let Some(fix) = &self.suggestion {
+                write!(f, " ({})", fix)?;
+            }
+            Ok(())
+        }
+    }
+
     impl PartialEq for Line {
         fn eq(&self, other: &Self) -> bool {
             self.contents == other.contents
@@ -112,45 +482,267 @@ pub mod dt {
 
     impl PartialEq for Bar {
         fn eq(&self, other: &Self) -> bool {
-            self.pitches == other.pitches && self.durations == other.durations
+            self.events == other.events
+        }
+    }
+
+    impl Event {
+        pub fn column(&self) -> usize {
+            match self {
+                Self::Note(note) => note.column,
+                Self::Rest(rest) => rest.column,
+                Self::Chord(chord) => chord.column,
+                Self::Barline(barline) => barline.column,
+                Self::Direction(direction) => direction.column,
+                Self::Tuplet(tuplet) => tuplet.column,
+            }
+        }
+
+        /*
+         * How long the event lasts, tuplet groups and anything that takes no
+         * time have no duration.
+         */
+        pub fn duration(&self) -> Option<Beats> {
+            match self {
+                Self::Note(note) => Some(note.duration.clone()),
+                Self::Rest(rest) => Some(rest.duration.clone()),
+                Self::Chord(chord) => Some(chord.duration.clone()),
+                _ => None,
+            }
+        }
+    }
+
+    impl Bar {
+        /*
+         * The notes, rests and chords of the measure in order, with the ones
+         * inside tuplet groups pulled out.
+         */
+        pub fn timed_events(&self) -> Vec<&Event> {
+            fn flatten<'a>(events: &'a [Event], found: &mut Vec<&'a Event>) {
+                for event in events {
+                    match event {
+                        Event::Tuplet(tuplet) => flatten(&tuplet.events, found),
+                        e if e.duration().is_some() => found.push(e),
+                        _ => {},
+                    }
+                }
+            }
+
+            let mut found = Vec::new();
+            flatten(&self.events, &mut found);
+            found
+        }
+
+        pub fn durations(&self) -> Vec<Beats> {
+            self.timed_events().iter().filter_map(|e| e.duration()).collect()
+        }
+
+        /*
+         * Every pitch in the measure in order. Rests show up as REST and chords
+         * add all of their notes.
+         */
+        pub fn pitches(&self) -> Vec<Note> {
+            self.timed_events().iter()
+                .flat_map(|e| match e {
+                    Event::Note(note) => vec![note.pitch.clone()],
+                    Event::Chord(chord) => chord.pitches.clone(),
+                    _ => vec![REST],
+                })
+                .collect()
+        }
+
+        pub fn directions(&self) -> Vec<&Direction> {
+            self.events.iter()
+                .filter_map(|e| match e {
+                    Event::Direction(direction) => Some(direction),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    impl Direction {
+        /*
+         * The part after "name:" when the text is a marker like "call:motif",
+         * None for any other text.
+         */
+        pub fn marker(&self, name: &str) -> Option<&str> {
+            self.text.strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix(':'))
+                .filter(|argument| !argument.is_empty())
+        }
+    }
+
+    impl Note {
+        /*
+         * Semitones above the C of the octave the note sounds in, before
+         * wrapping, so Cb is -1 and B# is 12.
+         */
+        fn semitones_above_c(&self) -> isize {
+            let natural: isize = match self.note_name {
+                "C" => 0,
+                "D" => 2,
+                "E" => 4,
+                "F" => 5,
+                "G" => 7,
+                "A" => 9,
+                _ => 11,
+            };
+            let shift = match self.accidental {
+                Accidental::Sharp => 1,
+                Accidental::Flat => -1,
+                Accidental::Natural => 0,
+            };
+
+            natural + shift
+        }
+
+        /*
+         * Semitones above C, wrapping around so Cb is 11 and B# is 0.
+         */
+        pub fn pitch_class(&self) -> usize {
+            self.semitones_above_c().rem_euclid(12) as usize
+        }
+
+        /*
+         * Semitones above the C of octave 0. Octaves here start on A, so A and
+         * B sound in the same octave as the C of the octave number below them.
+         */
+        pub fn semitone(&self) -> isize {
+            let c_octave = match self.note_name {
+                "A" | "B" => self.octave as isize - 1,
+                _ => self.octave as isize,
+            };
+
+            c_octave * 12 + self.semitones_above_c()
+        }
+
+        /*
+         * How many semitones it is from this note up to other, negative when
+         * other is lower.
+         */
+        pub fn interval_to(&self, other: &Note) -> isize {
+            other.semitone() - self.semitone()
+        }
+    }
+
+    impl KeySignature {
+        /*
+         * Gives a note the accidental its letter has in this key.
+         */
+        pub fn apply(&self, mut note: Note) -> Note {
+            if let Some((_, accidental)) = self.accidentals.iter()
+                .find(|(name, _)| *name == note.note_name) {
+                note.accidental = accidental.clone();
+            }
+
+            note
         }
     }
 
     impl Beats {
+        /*
+         * The rhythm that the bottom number of a time signature stands for.
+         */
+        pub fn from_denominator(denominator: usize) -> Option<Beats> {
+            match denominator {
+                1 => Some(Self::Whole),
+                2 => Some(Self::Half),
+                4 => Some(Self::Quarter),
+                8 => Some(Self::Eighth),
+                16 => Some(Self::Sixteenth),
+                32 => Some(Self::ThirtySecond),
+                _ => None,
+            }
+        }
+
+        /*
+         * How long the rhythm lasts in ticks, TICKS_PER_WHOLE to a whole note.
+         */
+        pub fn ticks(&self) -> usize {
+            let sixty_fourths = match self {
+                Self::ThirtySecond => 2,
+                Self::Sixteenth => 4,
+                Self::Eighth => 8,
+                Self::Quarter => 16,
+                Self::Half => 32,
+                Self::Whole => 64,
+                Self::DottedThirtySecond => 3,
+                Self::DottedSixteenth => 6,
+                Self::DottedEighth => 12,
+                Self::DottedQuarter => 24,
+                Self::DottedHalf => 48,
+                Self::DottedWhole => 96,
+                Self::EighthTriplet => return TICKS_PER_WHOLE / 12,
+                Self::QuarterTriplet => return TICKS_PER_WHOLE / 6,
+                Self::HalfTriplet => return TICKS_PER_WHOLE / 3,
+            };
+
+            sixty_fourths * TICKS_PER_WHOLE / 64
+        }
+
+        /*
+         * The number a note of this rhythm stands for when it is used as an
+         * operand. It is the length in sixteenths rounded down, so a quarter
+         * is 4, a dotted half is 12 and a 32nd is 0.
+         */
+        pub fn literal(&self) -> i64 {
+            (self.ticks() / (TICKS_PER_WHOLE / 16)) as i64
+        }
+
+        /*
+         * Returns the dotted version of this rhythm if there is one.
+         */
+        pub fn dotted(&self) -> Option<Beats> {
+            match self {
+                Self::ThirtySecond => Some(Self::DottedThirtySecond),
+                Self::Sixteenth => Some(Self::DottedSixteenth),
+                Self::Eighth => Some(Self::DottedEighth),
+                Self::Quarter => Some(Self::DottedQuarter),
+                Self::Half => Some(Self::DottedHalf),
+                Self::Whole => Some(Self::DottedWhole),
+                _ => None,
+            }
+        }
+
+        /*
+         * Returns the triplet version of this rhythm if there is one.
+         */
+        pub fn triplet(&self) -> Option<Beats> {
+            match self {
+                Self::Eighth => Some(Self::EighthTriplet),
+                Self::Quarter => Some(Self::QuarterTriplet),
+                Self::Half => Some(Self::HalfTriplet),
+                _ => None,
+            }
+        }
+
         pub fn equivalent(&self, other: Beats, num :usize) -> bool {
             match self {
-                Self::Eighth => match (other, num) {
-                    (Self::ThirtySecond, 4) => true,
-                    (Self::Sixteenth, 2) => true,
-                    _ => false,
-                },
-                Self::Quarter => match (other, num) {
-                    (Self::EighthTriplet, 3) => true,
-                    (Self::Eighth, 2) => true,
-                    (Self::Sixteenth, 4) => true,
-                    (Self::ThirtySecond, 8) => true,
-                    _ => false,
-                },
-                Self::Half => match (other, num) {
-                    (Self::EighthTriplet, 6) => true,
-                    (Self::QuarterTriplet, 3) => true,
-                    (Self::Quarter, 2) => true,
-                    (Self::Eighth, 4) => true,
-                    (Self::Sixteenth, 8) => true,
-                    (Self::ThirtySecond, 16) => true,
-                    _ => false,
-                },
-                Self::Whole => match (other, num) {
-                    (Self::EighthTriplet, 12) => true,
-                    (Self::QuarterTriplet, 6) => true,
-                    (Self::HalfTriplet, 3) => true,
-                    (Self::Half, 2) => true,
-                    (Self::Quarter, 4) => true,
-                    (Self::Eighth, 8) => true,
-                    (Self::Sixteenth, 16) => true,
-                    (Self::ThirtySecond, 32) => true,
-                    _ => false,
-                },
+                Self::Eighth => matches!((other, num),
+                    (Self::ThirtySecond, 4)
+                    | (Self::Sixteenth, 2)),
+                Self::Quarter => matches!((other, num),
+                    (Self::EighthTriplet, 3)
+                    | (Self::Eighth, 2)
+                    | (Self::Sixteenth, 4)
+                    | (Self::ThirtySecond, 8)),
+                Self::Half => matches!((other, num),
+                    (Self::EighthTriplet, 6)
+                    | (Self::QuarterTriplet, 3)
+                    | (Self::Quarter, 2)
+                    | (Self::Eighth, 4)
+                    | (Self::Sixteenth, 8)
+                    | (Self::ThirtySecond, 16)),
+                Self::Whole => matches!((other, num),
+                    (Self::EighthTriplet, 12)
+                    | (Self::QuarterTriplet, 6)
+                    | (Self::HalfTriplet, 3)
+                    | (Self::Half, 2)
+                    | (Self::Quarter, 4)
+                    | (Self::Eighth, 8)
+                    | (Self::Sixteenth, 16)
+                    | (Self::ThirtySecond, 32)),
                 _ => false,
             }
         }
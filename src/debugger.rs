@@ -0,0 +1,551 @@
+pub mod debugger {
+use std::io;
+use std::path::Path;
+use crate::data_types::dt::*;
+use crate::interpreter::interpreter::{Delta, Interpreter, ProgramCounter};
+
+// How many steps the debugger remembers unless it is told otherwise
+pub const DEFAULT_HISTORY: usize = 100_000;
+
+/*
+ * Stops the debugger when a measure with this number starts playing in the
+ * named file. Files are compared by name without the folder or the .inst, so
+ * "main", "main.inst" and "instruments/main.inst" are all the same file.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub file: String,
+    pub measure_number: usize,
+}
+
+/*
+ * Where the debugger is, the file and measure number of the event that
+ * plays next.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub measure_number: usize,
+    pub pc: ProgramCounter,
+}
+
+/*
+ * Why the debugger handed control back.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stop {
+    Paused,
+    Breakpoint(Breakpoint),
+    Finished,
+}
+
+/*
+ * Runs a piece a bit at a time so it can be looked at in between. Files
+ * keeps the parsed music of every program the interpreter knows about, in
+ * the same order, so the staff being played can be printed.
+ *
+ * The interpreter remembers its last steps (DEFAULT_HISTORY of them unless
+ * with_history says otherwise) so the debugger can also go backwards, as far
+ * back as that history reaches.
+ *
+ * Everything can be driven from code or with the text commands in execute,
+ * which is what interact reads from the piece's input.
+ */
+#[derive(Debug)]
+pub struct Debugger {
+    pub interpreter: Interpreter,
+    pub breakpoints: Vec<Breakpoint>,
+    files: Vec<(String, Vec<Line>)>,
+    started: bool,
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    Path::new(a).file_stem() == Path::new(b).file_stem()
+}
+
+impl Debugger {
+    pub fn new(interpreter: Interpreter) -> Self {
+        Debugger {
+            interpreter: interpreter.with_history(DEFAULT_HISTORY),
+            breakpoints: Vec::new(),
+            files: vec![(String::new(), Vec::new())],
+            started: false,
+        }
+    }
+
+    pub fn with_history(mut self, limit: usize) -> Self {
+        self.interpreter.history_limit = limit;
+        self
+    }
+
+    /*
+     * Loads the piece to debug, file is only used to name it.
+     */
+    pub fn load(&mut self, file: &str, lines: &[Line]) {
+        self.interpreter.load(lines);
+        self.interpreter.file = file.to_string();
+        self.files[0] = (file.to_string(), lines.to_vec());
+        self.started = false;
+    }
+
+    pub fn add_instrument(&mut self, name: &str, lines: &[Line]) {
+        self.interpreter.add_instrument(name, lines);
+        let index = self.interpreter.programs.iter().skip(1)
+            .position(|(n, _)| n == name)
+            .map_or(0, |index| index + 1);
+        match self.files.get_mut(index) {
+            Some(file) => *file = (name.to_string(), lines.to_vec()),
+            None => self.files.push((name.to_string(), lines.to_vec())),
+        }
+    }
+
+    pub fn location(&self) -> Option<Location> {
+        if self.interpreter.finished() {
+            return None;
+        }
+
+        let pc = self.interpreter.machine.pc;
+        let bar = self.interpreter.program().measures.get(pc.measure)?;
+        Some(Location {
+            file: self.files[self.interpreter.machine.program].0.clone(),
+            measure_number: bar.measure_number,
+            pc,
+        })
+    }
+
+    fn position(&self) -> (usize, usize) {
+        (self.interpreter.machine.program, self.interpreter.machine.pc.measure)
+    }
+
+    fn breakpoint_here(&self) -> Option<Breakpoint> {
+        let location = self.location()?;
+        self.breakpoints.iter()
+            .find(|b| b.measure_number == location.measure_number && same_file(&b.file, &location.file))
+            .cloned()
+    }
+
+    /*
+     * Plays one event. A "recv:" can never be answered with nobody else
+     * playing, so that is reported instead of waiting forever.
+     */
+    fn play(&mut self) -> Result<(), DebugError> {
+        self.started = true;
+        self.interpreter.step().map_err(|error| self.interpreter.locate(error))?;
+        if self.interpreter.blocked() {
+            let channel = self.interpreter.machine.waiting.clone().unwrap_or_default();
+            return Err(RuntimeFailure::from(RuntimeError::Deadlock(channel)).into());
+        }
+        Ok(())
+    }
+
+    /*
+     * Plays up to and including the next note, chord or rest.
+     */
+    pub fn step(&mut self) -> Result<Stop, DebugError> {
+        while !self.interpreter.finished() {
+            let pc = self.interpreter.machine.pc;
+            let timed = self.interpreter.program().event(pc)
+                .is_some_and(|event| event.duration().is_some());
+            self.play()?;
+            if timed {
+                return Ok(Stop::Paused);
+            }
+        }
+        Ok(Stop::Finished)
+    }
+
+    /*
+     * Plays until a different measure is reached.
+     */
+    pub fn step_measure(&mut self) -> Result<Stop, DebugError> {
+        let start = self.position();
+        while !self.interpreter.finished() {
+            self.play()?;
+            if self.position() != start {
+                return Ok(Stop::Paused);
+            }
+        }
+        Ok(Stop::Finished)
+    }
+
+    /*
+     * Plays until a measure with a breakpoint on it starts, or the piece is
+     * over. A breakpoint on the very first measure stops before anything is
+     * played.
+     */
+    pub fn resume(&mut self) -> Result<Stop, DebugError> {
+        let mut entered = !self.started;
+        while !self.interpreter.finished() {
+            if let Some(breakpoint) = self.breakpoint_here().filter(|_| entered) {
+                return Ok(Stop::Breakpoint(breakpoint));
+            }
+
+            let before = self.position();
+            self.play()?;
+            entered = self.position() != before;
+        }
+        Ok(Stop::Finished)
+    }
+
+    /*
+     * The rows of the staff being played with a ^ under the event that plays
+     * next.
+     */
+    pub fn staff(&self) -> Option<String> {
+        let pc = self.interpreter.machine.pc;
+        let event = self.interpreter.program().event(pc)?;
+
+        let mut measure = pc.measure;
+        let lines = &self.files[self.interpreter.machine.program].1;
+        let line = lines.iter().find(|line| {
+            let here = measure < line.contents.len();
+            if !here {
+                measure -= line.contents.len();
+            }
+            here
+        })?;
+
+        let column = line.layout.measure_columns.get(measure)? + event.column();
+        let mut staff = String::new();
+        for row in line.layout.rows.iter() {
+            staff.push_str(row.trim_end());
+            staff.push('\n');
+        }
+        staff.push_str(&" ".repeat(column));
+        staff.push('^');
+        Some(staff)
+    }
+
+    /*
+     * Goes back to just before the last note, chord or rest that was played.
+     */
+    pub fn step_back(&mut self) -> Result<Stop, DebugError> {
+        if !self.interpreter.undo() {
+            return Err(DebugError::NoHistory);
+        }
+
+        loop {
+            let pc = self.interpreter.machine.pc;
+            let timed = self.interpreter.program().event(pc)
+                .is_some_and(|event| event.duration().is_some());
+            if timed || !self.interpreter.undo() {
+                return Ok(Stop::Paused);
+            }
+        }
+    }
+
+    fn measure_number(&self, delta: &Delta) -> Option<usize> {
+        self.interpreter.programs.get(delta.program)
+            .and_then(|(_, program)| program.measures.get(delta.pc.measure))
+            .map(|bar| bar.measure_number)
+    }
+
+    fn undo_to(&mut self, index: usize) {
+        while self.interpreter.history.len() > index {
+            self.interpreter.undo();
+        }
+    }
+
+    /*
+     * Goes back to the last time the measure started playing.
+     */
+    pub fn rewind(&mut self, measure: &Breakpoint) -> Result<Stop, DebugError> {
+        let history = &self.interpreter.history;
+        let entered = (0..history.len()).rev().find(|&index| {
+            let delta = &history[index];
+            let starts = index == 0 || (history[index - 1].program, history[index - 1].pc.measure)
+                != (delta.program, delta.pc.measure);
+            starts && self.measure_number(delta) == Some(measure.measure_number)
+                && same_file(&measure.file, &self.files[delta.program].0)
+        });
+
+        let index = entered.ok_or_else(|| DebugError::NotInHistory(format!("{}:{}",
+            measure.file, measure.measure_number)))?;
+        self.undo_to(index);
+        Ok(Stop::Paused)
+    }
+
+    /*
+     * Goes back to just before the last Store to address, so it is the next
+     * thing played.
+     */
+    pub fn last_write(&mut self, address: usize) -> Result<Stop, DebugError> {
+        let index = self.interpreter.history.iter()
+            .rposition(|delta| delta.write.is_some_and(|(written, _)| written == address))
+            .ok_or(DebugError::NoWrite(address))?;
+        self.undo_to(index);
+        Ok(Stop::Paused)
+    }
+
+    fn describe(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Breakpoint(breakpoint) => format!("Breakpoint {}:{}\n{}", breakpoint.file,
+                breakpoint.measure_number, self.describe(Stop::Paused)),
+            _ => match self.location() {
+                Some(location) => format!("{}:{} event {}, clock {}", location.file,
+                    location.measure_number, location.pc.event, self.interpreter.machine.clock),
+                None => "Finished".to_string(),
+            },
+        }
+    }
+
+    fn parse_breakpoint(&self, argument: Option<&str>) -> Result<Breakpoint, DebugError> {
+        let argument = argument.unwrap_or_default();
+        let invalid = || DebugError::InvalidBreakpoint(argument.to_string());
+        let (file, measure) = match argument.rsplit_once(':') {
+            Some((file, measure)) => (file.to_string(), measure),
+            None => (self.files[0].0.clone(), argument),
+        };
+        let measure_number = measure.parse().map_err(|_| invalid())?;
+        Ok(Breakpoint { file, measure_number })
+    }
+
+    /*
+     * Runs one debugger command and returns what it has to say.
+     *
+     *   step, s               play the next note, chord or rest
+     *   measure, m            play to the next measure
+     *   continue, c           play to the next breakpoint
+     *   back, bs              go back to before the last note, chord or rest
+     *   rewind, r [file:]N    go back to the last time measure N started
+     *   lastwrite, lw A       go back to just before the last write to A
+     *   break, b [file:]N     stop when measure N starts
+     *   delete, d [file:]N    remove that breakpoint
+     *   breakpoints           list the breakpoints
+     *   where, w              where the piece is
+     *   staff, l              print the staff with the next event marked
+     *   stack                 print the stack, top last
+     *   memory, x [start [n]] print the tape, n cells or every cell written
+     */
+    pub fn execute(&mut self, command: &str) -> Result<String, DebugError> {
+        let mut words = command.split_whitespace();
+        let Some(name) = words.next() else { return Ok(String::new()) };
+        let argument = words.next();
+
+        Ok(match name {
+            "step" | "s" => {
+                let stop = self.step()?;
+                self.describe(stop)
+            },
+            "measure" | "m" => {
+                let stop = self.step_measure()?;
+                self.describe(stop)
+            },
+            "continue" | "c" => {
+                let stop = self.resume()?;
+                self.describe(stop)
+            },
+            "back" | "bs" => {
+                let stop = self.step_back()?;
+                self.describe(stop)
+            },
+            "rewind" | "r" => {
+                let measure = self.parse_breakpoint(argument)?;
+                let stop = self.rewind(&measure)?;
+                self.describe(stop)
+            },
+            "lastwrite" | "lw" => {
+                let argument = argument.unwrap_or_default();
+                let address = argument.parse()
+                    .map_err(|_| DebugError::InvalidAddress(argument.to_string()))?;
+                let stop = self.last_write(address)?;
+                self.describe(stop)
+            },
+            "break" | "b" => {
+                let breakpoint = self.parse_breakpoint(argument)?;
+                let set = format!("Breakpoint set at {}:{}", breakpoint.file,
+                    breakpoint.measure_number);
+                if !self.breakpoints.contains(&breakpoint) {
+                    self.breakpoints.push(breakpoint);
+                }
+                set
+            },
+            "delete" | "d" => {
+                let breakpoint = self.parse_breakpoint(argument)?;
+                self.breakpoints.retain(|b| *b != breakpoint);
+                format!("Breakpoint removed from {}:{}", breakpoint.file,
+                    breakpoint.measure_number)
+            },
+            "breakpoints" => self.breakpoints.iter()
+                .map(|b| format!("{}:{}", b.file, b.measure_number))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            "where" | "w" => self.describe(Stop::Paused),
+            "staff" | "l" => self.staff().unwrap_or_else(|| "Finished".to_string()),
+            "stack" => format!("{:?}", self.interpreter.machine.stack),
+            "memory" | "x" => {
+                let number = |word: Option<&str>, default: usize| word.map_or(Ok(default), |w| {
+                    w.parse().map_err(|_| DebugError::InvalidAddress(w.to_string()))
+                });
+                let tape = &self.interpreter.machine.tape;
+                let start = number(argument, 0)?;
+                let cells: Vec<(usize, i64)> = match words.next() {
+                    // The tape can have cells written anywhere, so without a
+                    // count only the ones that were are printed
+                    None => tape.range(start..).map(|(&address, &value)| (address, value))
+                        .collect(),
+                    count => (start..start.saturating_add(number(count, 0)?))
+                        .map(|address| (address, tape.get(&address).copied().unwrap_or(0)))
+                        .collect(),
+                };
+                cells.iter()
+                    .map(|(address, value)| format!("{}: {}", address, value))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            },
+            _ => return Err(DebugError::UnknownCommand(name.to_string())),
+        })
+    }
+
+    /*
+     * Runs a command on every line of a script and collects what they print.
+     * Blank lines and lines starting with # are skipped.
+     */
+    pub fn run_script(&mut self, script: &str) -> Result<String, DebugError> {
+        let mut output = Vec::new();
+        for command in script.lines().map(str::trim) {
+            if command.is_empty() || command.starts_with('#') {
+                continue;
+            }
+            output.push(self.execute(command)?);
+        }
+        Ok(output.join("\n"))
+    }
+
+    /*
+     * Reads commands from the piece's input and prints what they say to its
+     * output until the input runs out or "quit" is read. The piece reads from
+     * the same input so anything it asks for comes from between commands.
+     */
+    pub fn interact(&mut self) -> io::Result<()> {
+        loop {
+            write!(self.interpreter.streams.output, "(debug) ")?;
+            self.interpreter.streams.output.flush()?;
+            let Some(command) = self.interpreter.streams.read_line()? else { break };
+            if matches!(command.trim(), "quit" | "q") {
+                break;
+            }
+
+            let reply = match self.execute(&command) {
+                Ok(reply) => reply,
+                Err(e) => e.to_string(),
+            };
+            if !reply.is_empty() {
+                writeln!(self.interpreter.streams.output, "{}", reply)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::parser::parser::parse_file;
+    use crate::streams::streams::{SharedOutput, Streams};
+
+    fn debugger(file: &str) -> Debugger {
+        let mut debugger = Debugger::new(Interpreter::default());
+        debugger.load(file, &parse_file(file.to_string()).unwrap());
+        debugger
+    }
+
+    #[test]
+    fn test_debugger_stepping() {
+        let mut debugger = debugger("testing_resources/arithmetic.inst");
+        assert_eq!(Stop::Paused, debugger.step().unwrap());
+        assert_eq!(1, debugger.interpreter.machine.stack.len());
+        assert_eq!(Stop::Paused, debugger.step_measure().unwrap());
+        assert_eq!(Some(2), debugger.location().map(|l| l.measure_number));
+
+        while debugger.step().unwrap() != Stop::Finished {}
+        assert_eq!(vec![16], debugger.interpreter.machine.stack);
+        assert_eq!(None, debugger.location());
+    }
+
+    #[test]
+    fn test_debugger_script() {
+        let mut debugger = debugger("testing_resources/tape_and_repeats.inst");
+        let output = debugger.run_script("
+            # the repeat in measure 3 plays three times
+            b tape_and_repeats.inst:3
+            c
+            stack
+            x
+            c
+            c
+            c
+        ").unwrap();
+        let output: Vec<&str> = output.lines().collect();
+
+        assert_eq!("Breakpoint set at tape_and_repeats.inst:3", output[0]);
+        assert_eq!("Breakpoint tape_and_repeats.inst:3", output[1]);
+        assert!(output[2].starts_with("testing_resources/tape_and_repeats.inst:3 event"));
+        assert_eq!("Finished", *output.last().unwrap());
+        assert_eq!(Err(DebugError::UnknownCommand("jump".to_string())), debugger.execute("jump"));
+        assert_eq!(Err(DebugError::InvalidBreakpoint("main:x".to_string())),
+            debugger.execute("b main:x"));
+    }
+
+    #[test]
+    fn test_debugger_time_travel() {
+        let mut forgetful = debugger("testing_resources/tape_and_repeats.inst").with_history(0);
+        forgetful.step().unwrap();
+        assert_eq!(Err(DebugError::NoHistory), forgetful.step_back());
+
+        let mut debugger = debugger("testing_resources/tape_and_repeats.inst");
+        while debugger.step().unwrap() != Stop::Finished {}
+        assert_eq!(BTreeMap::from([(1, 4)]), debugger.interpreter.machine.tape);
+
+        // The only write puts the 4 at address 1 in the first measure
+        debugger.last_write(1).unwrap();
+        assert_eq!(Some(1), debugger.location().map(|l| l.measure_number));
+        assert!(debugger.interpreter.machine.tape.is_empty());
+        assert_eq!(vec![4, 1], debugger.interpreter.machine.stack);
+        assert_eq!(Err(DebugError::NoWrite(0)), debugger.last_write(0));
+
+        while debugger.step().unwrap() != Stop::Finished {}
+        let output = debugger.run_script("
+            r 3
+            stack
+            bs
+            w
+        ").unwrap();
+        let output: Vec<&str> = output.lines().collect();
+        assert!(output[0].starts_with("testing_resources/tape_and_repeats.inst:3 event 0"));
+        assert_eq!("[4]", output[1]);
+        assert!(output[2].starts_with("testing_resources/tape_and_repeats.inst:2"));
+        assert_eq!(output[2], output[3]);
+
+        assert_eq!(Err(DebugError::NotInHistory("tape_and_repeats:9".to_string())),
+            debugger.execute("r tape_and_repeats:9"));
+    }
+
+    #[test]
+    fn test_debugger_staff() {
+        let mut debugger = debugger("testing_resources/arithmetic.inst");
+        debugger.step().unwrap();
+        let staff = debugger.staff().unwrap();
+        let rows: Vec<&str> = staff.lines().collect();
+
+        // The caret sits right under the next note's head
+        let caret = rows.last().unwrap().len() - 1;
+        assert!(rows.iter().any(|row| row.chars().nth(caret) == Some('@')));
+    }
+
+    #[test]
+    fn test_debugger_interact() {
+        let output = SharedOutput::new();
+        let mut debugger = Debugger::new(Interpreter::default()
+            .with_streams(Streams::new("s\nstack\nnope\nq\n".as_bytes(), output.clone())));
+        let file = "testing_resources/arithmetic.inst";
+        debugger.load(file, &parse_file(file.to_string()).unwrap());
+        debugger.interact().unwrap();
+
+        let text = output.text();
+        assert!(text.contains("(debug) [4]\n"));
+        assert!(text.contains(r#"Unknown debugger command "nope""#));
+    }
+}
+}
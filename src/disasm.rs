@@ -0,0 +1,206 @@
+pub mod disasm {
+use std::fmt;
+use crate::data_types::dt::*;
+use crate::instructions::instructions::*;
+use crate::interpreter::interpreter::Program;
+use crate::trace::trace::pitch_name;
+
+/*
+ * One line of a listing: what an event does, written the way the REPL
+ * shorthand writes it, and where it is on the score. Notes and chords are the
+ * instruction they decode to ("push 4", "write_char"), rests are "rest" and
+ * barlines and directions are written as they are ("|", ":|x3", "call:name").
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListingLine {
+    pub text: String,
+    pub file: String,
+    pub measure: usize,
+    // Counted in beats of the time signature from 1 at the start of the measure
+    pub beat: String,
+    pub pitch: Option<String>,
+    pub rhythm: Option<String>,
+}
+
+impl fmt::Display for ListingLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = format!("{:<16}; {}  m{:<3} beat {:<7}", self.text, self.file, self.measure,
+            self.beat);
+        let source = [&self.pitch, &self.rhythm].into_iter().flatten().cloned()
+            .collect::<Vec<_>>()
+            .join(" ");
+        write!(f, "{}", format!("{} {}", text, source).trim_end())
+    }
+}
+
+/*
+ * A rhythm in shorthand letters, with a 3 after it for a triplet.
+ */
+fn rhythm_name(duration: &Beats) -> String {
+    let name = |beats: &Beats| match beats {
+        Beats::Whole => "w",
+        Beats::Half => "h",
+        Beats::Quarter => "q",
+        Beats::Eighth => "e",
+        Beats::Sixteenth => "s",
+        _ => "t",
+    };
+    [Beats::Whole, Beats::Half, Beats::Quarter, Beats::Eighth, Beats::Sixteenth, Beats::ThirtySecond]
+        .iter()
+        .find_map(|plain| {
+            if plain == duration {
+                Some(name(plain).to_string())
+            } else if plain.dotted().as_ref() == Some(duration) {
+                Some(format!("{}.", name(plain)))
+            } else if plain.triplet().as_ref() == Some(duration) {
+                Some(format!("{}3", name(plain)))
+            } else {
+                None
+            }
+        })
+        .unwrap_or_default()
+}
+
+/*
+ * Where in a measure something falls, in beats from 1. Halves, quarters and
+ * eighths of a beat are written as decimals ("2.5"), anything else as a
+ * fraction ("1+1/3").
+ */
+pub fn beat_position(ticks: usize, beat: usize) -> String {
+    let (whole, part) = (ticks / beat + 1, ticks % beat);
+    if part == 0 {
+        return whole.to_string();
+    }
+    let gcd = |mut a: usize, mut b: usize| {
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        a
+    };
+    let divisor = gcd(part, beat);
+    let (numerator, denominator) = (part / divisor, beat / divisor);
+    if [2, 4, 8, 16].contains(&denominator) {
+        format!("{}", whole as f64 + numerator as f64 / denominator as f64)
+    } else {
+        format!("{}+{}/{}", whole, numerator, denominator)
+    }
+}
+
+/*
+ * The text a listing gives an event with whatever it decoded to.
+ */
+fn event_text(event: &Event, operation: Option<Operation>) -> String {
+    match (event, operation) {
+        (Event::Barline(barline), _) => match barline.kind {
+            BarlineKind::Single => "|".to_string(),
+            BarlineKind::Final => "||".to_string(),
+            BarlineKind::RepeatStart => "|:".to_string(),
+            BarlineKind::RepeatEnd { times: None } => ":|".to_string(),
+            BarlineKind::RepeatEnd { times: Some(times) } => format!(":|x{}", times),
+        },
+        (Event::Direction(direction), _) => direction.text.clone(),
+        (_, Some(operation)) if operation.instruction == Instruction::Push => {
+            format!("push {}", operation.operand)
+        },
+        (_, Some(operation)) => operation.instruction.name().to_string(),
+        (Event::Rest(_), None) => "rest".to_string(),
+        // The first note in interval mode only sets the pitch
+        (_, None) => "nop".to_string(),
+    }
+}
+
+/*
+ * Lists every event of a piece in the order it is written, decoded with
+ * table. In interval mode each note is measured from the note written before
+ * it, which is the one played before it unless a repeat, skip or call says
+ * otherwise.
+ */
+pub fn disassemble(file: &str, lines: &[Line], table: &InstructionTable, mode: OpcodeMode)
+    -> Vec<ListingLine> {
+    let mut decoder = Decoder::new(table.clone(), mode);
+    let mut beat = Beats::Quarter.ticks();
+    let mut listing = Vec::new();
+
+    for line in lines {
+        if let Some(time_signature) = &line.time_signature {
+            beat = time_signature.beat_size.ticks();
+        }
+        for bar in Program::from_lines(std::slice::from_ref(line)).measures {
+            let mut ticks = 0;
+            for event in &bar.events {
+                let pitch = match event {
+                    Event::Note(note) => Some(pitch_name(&note.pitch)),
+                    Event::Chord(chord) => Some(chord.pitches.iter()
+                        .map(pitch_name)
+                        .collect::<Vec<_>>()
+                        .join("+")),
+                    _ => None,
+                };
+                listing.push(ListingLine {
+                    text: event_text(event,
+                        decoder.decode(event, bar.measure_number).ok().flatten()),
+                    file: file.to_string(),
+                    measure: bar.measure_number,
+                    beat: beat_position(ticks, beat),
+                    pitch,
+                    rhythm: event.duration().map(|duration| rhythm_name(&duration)),
+                });
+                ticks += event.duration().map_or(0, |duration| duration.ticks());
+            }
+        }
+    }
+
+    listing
+}
+
+/*
+ * A listing as text, one line each.
+ */
+pub fn listing(lines: &[ListingLine]) -> String {
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parser::parse_file;
+
+    fn disassembled(file: &str) -> Vec<ListingLine> {
+        disassemble("piece.inst", &parse_file(file.to_string()).unwrap(),
+            &InstructionTable::default(), OpcodeMode::Absolute)
+    }
+
+    #[test]
+    fn test_disasm_listing() {
+        let listing = disassembled("testing_resources/tape_and_repeats.inst");
+        assert_eq!(ListingLine {
+            text: "push 4".to_string(),
+            file: "piece.inst".to_string(),
+            measure: 1,
+            beat: "1".to_string(),
+            pitch: Some("C4".to_string()),
+            rhythm: Some("q".to_string()),
+        }, listing[0]);
+        assert_eq!("push 4          ; piece.inst  m1   beat 1       C4 q", listing[0].to_string());
+        assert_eq!("store", listing[2].text);
+        assert!(listing.iter().any(|line| line.text == ":|x3"));
+        assert!(listing.iter().any(|line| line.text == "|:" && line.pitch.is_none()));
+    }
+
+    #[test]
+    fn test_disasm_beats() {
+        assert_eq!("1", beat_position(0, Beats::Quarter.ticks()));
+        assert_eq!("2.5", beat_position(72, Beats::Quarter.ticks()));
+        assert_eq!("1+1/3", beat_position(Beats::EighthTriplet.ticks(), Beats::Quarter.ticks()));
+        assert_eq!("q3", rhythm_name(&Beats::QuarterTriplet));
+        assert_eq!("e.", rhythm_name(&Beats::DottedEighth));
+
+        let listing = disassembled("testing_resources/triplets.inst");
+        let beats: Vec<&str> = listing.iter()
+            .filter(|line| line.measure == 1 && line.pitch.is_some())
+            .map(|line| line.beat.as_str())
+            .collect();
+        assert!(beats.contains(&"1+1/3"), "{:?}", beats);
+    }
+}
+}
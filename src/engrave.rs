@@ -0,0 +1,493 @@
+pub mod engrave {
+use crate::data_types::dt::*;
+
+// The clefs as the parser expects to find them, the first row is the space
+// above the top staff line
+const TREBLE_ART: [&str; 12] = ["   /\\", "   | \\", "   | /", "   |/", "  /|", " / |",
+    "|  |\\", " \\ | |", "  \\|/", "/@ |", "\\_/", ""];
+const BASS_ART: [&str; 10] = [" __", "/  \\", "|   \\ @", "\\@@ |", " @@ / @", "   /", "  /",
+    " /", "/", ""];
+const ART_WIDTH: usize = 8;
+
+// Columns left blank after every note, rest and tuplet mark
+const GAP: usize = 2;
+
+/*
+ * Something drawn in a measure. Rows are counted from the middle line of the
+ * staff, negative is above it, columns from the start of the measure.
+ */
+#[derive(Default)]
+struct Drawing {
+    cells: Vec<(isize, usize, char)>,
+    texts: Vec<(usize, String)>,
+    width: usize,
+}
+
+impl Drawing {
+    fn put(&mut self, row: isize, column: usize, c: char) {
+        self.cells.push((row, column, c));
+    }
+
+    fn write(&mut self, row: isize, column: usize, text: &str) {
+        for (i, c) in text.chars().enumerate() {
+            self.put(row, column + i, c);
+        }
+    }
+}
+
+// How far below the middle line a pitch sits, in lines and spaces
+fn staff_offset(note: &Note, clef: &StaffType) -> isize {
+    let position = |note: &Note| note.octave as isize * 7
+        + NOTE_ORDER.iter().position(|&name| name == note.note_name).unwrap_or(0) as isize;
+    let center = match clef {
+        StaffType::Bass => BASS_CENTER,
+        StaffType::Treble => TREBLE_CENTER,
+    };
+    position(&center) - position(note)
+}
+
+// The letter that sits at an offset from the middle line
+fn letter_at(offset: isize, clef: &StaffType) -> &'static str {
+    let center = match clef {
+        StaffType::Bass => BASS_CENTER,
+        StaffType::Treble => TREBLE_CENTER,
+    };
+    let index = NOTE_ORDER.iter().position(|&name| name == center.note_name).unwrap_or(0);
+    NOTE_ORDER[(index as isize - offset).rem_euclid(7) as usize]
+}
+
+// A rhythm without its dot or triplet, and whether it had a dot
+fn plain_rhythm(duration: &Beats) -> (Beats, bool) {
+    match duration {
+        Beats::DottedThirtySecond => (Beats::ThirtySecond, true),
+        Beats::DottedSixteenth => (Beats::Sixteenth, true),
+        Beats::DottedEighth => (Beats::Eighth, true),
+        Beats::DottedQuarter => (Beats::Quarter, true),
+        Beats::DottedHalf => (Beats::Half, true),
+        Beats::DottedWhole => (Beats::Whole, true),
+        Beats::EighthTriplet => (Beats::Eighth, false),
+        Beats::QuarterTriplet => (Beats::Quarter, false),
+        Beats::HalfTriplet => (Beats::Half, false),
+        plain => (plain.clone(), false),
+    }
+}
+
+fn flags(rhythm: &Beats) -> usize {
+    match rhythm {
+        Beats::Eighth => 1,
+        Beats::Sixteenth => 2,
+        Beats::ThirtySecond => 3,
+        _ => 0,
+    }
+}
+
+/*
+ * Draws a note or chord with its first column at column and returns the last
+ * column it takes up. Stems point up from notes on or below the middle line
+ * with the heads left of them and down otherwise.
+ */
+fn draw_note(drawing: &mut Drawing, column: usize, rows: &[isize], duration: &Beats) -> usize {
+    let (rhythm, dotted) = plain_rhythm(duration);
+    let head = if matches!(rhythm, Beats::Half | Beats::Whole) { 'O' } else { '@' };
+    let high = rows.iter().copied().min().unwrap_or(0);
+    let low = rows.iter().copied().max().unwrap_or(0);
+
+    let last = if rhythm == Beats::Whole {
+        for &row in rows {
+            drawing.put(row, column, head);
+        }
+        column
+    } else if low >= 0 {
+        for &row in rows {
+            drawing.put(row, column, head);
+        }
+        for row in high - 3..=low {
+            drawing.put(row, column + 1, '|');
+        }
+        for flag in 0..flags(&rhythm) {
+            drawing.put(high - 3 + flag as isize, column + 2, '\\');
+        }
+        if flags(&rhythm) > 0 { column + 2 } else { column + 1 }
+    } else {
+        for row in high..=low + 3 {
+            drawing.put(row, column, '|');
+        }
+        for &row in rows {
+            drawing.put(row, column + 1, head);
+        }
+        for flag in 0..flags(&rhythm) {
+            drawing.put(low + 3 - flag as isize, column + 1, '/');
+        }
+        column + 1
+    };
+
+    if dotted {
+        drawing.put(low, last + 1, '.');
+        return last + 1;
+    }
+    last
+}
+
+fn draw_rest(drawing: &mut Drawing, column: usize, duration: &Beats) -> usize {
+    let (rhythm, dotted) = plain_rhythm(duration);
+    let last = match rhythm {
+        Beats::Quarter => {
+            drawing.put(-1, column, 'Z');
+            drawing.put(0, column, 'C');
+            column
+        },
+        Beats::Half => {
+            drawing.write(-1, column, "/===\\");
+            column + 4
+        },
+        Beats::Whole => {
+            drawing.write(-1, column, "\\===/");
+            column + 4
+        },
+        short => {
+            // One "*_/" per flag going down to the left, the parser reads the
+            // rest as starting one column left of the lowest star
+            let stars = flags(&short);
+            for star in 0..stars {
+                drawing.write(-2 + star as isize, column + stars - star, "*_/");
+            }
+            drawing.put(-2 + stars as isize, column + 2, '/');
+            drawing.put(-1 + stars as isize, column + 1, '/');
+            column + stars + 2
+        },
+    };
+
+    if dotted {
+        drawing.put(0, last + 1, '.');
+        return last + 1;
+    }
+    last
+}
+
+/*
+ * A direction has to look like a word to the parser or its row would be read
+ * as part of the staff.
+ */
+fn is_word(text: &str) -> bool {
+    let repeat_count = text.len() >= 2 && text.starts_with('x')
+        && text[1..].chars().all(|c| c.is_ascii_digit());
+    let chars: Vec<char> = text.chars().collect();
+    !text.contains(char::is_whitespace) && (repeat_count || chars.split(|c| !c.is_alphabetic())
+        .any(|word| word.len() >= 2 && word.iter().any(|c| !"lOZC".contains(*c))))
+}
+
+fn draw_events(drawing: &mut Drawing, events: &[Event], clef: &StaffType, key: &KeySignature,
+    column: &mut usize) -> Result<(), EngraveError> {
+    for event in events {
+        match event {
+            Event::Note(NoteEvent { pitch, duration, .. }) => {
+                check_spelling(pitch, key)?;
+                let last = draw_note(drawing, *column, &[staff_offset(pitch, clef)], duration);
+                *column = last + 1 + GAP;
+            },
+            Event::Chord(ChordEvent { pitches, duration, .. }) => {
+                for pitch in pitches {
+                    check_spelling(pitch, key)?;
+                }
+                let rows: Vec<isize> = pitches.iter().map(|p| staff_offset(p, clef)).collect();
+                let last = draw_note(drawing, *column, &rows, duration);
+                *column = last + 1 + GAP;
+            },
+            Event::Rest(RestEvent { duration, .. }) => {
+                let last = draw_rest(drawing, *column, duration);
+                *column = last + 1 + GAP;
+            },
+            Event::Tuplet(tuplet) => {
+                drawing.put(-5, *column, '<');
+                *column += 1 + GAP;
+                draw_events(drawing, &tuplet.events, clef, key, column)?;
+                drawing.put(-5, *column, '>');
+                *column += 1 + GAP;
+            },
+            Event::Direction(direction) => {
+                if !is_word(&direction.text) {
+                    return Err(EngraveError::InvalidDirection(direction.text.clone()));
+                }
+                // Sharing a column with the next event puts it right before it
+                drawing.texts.push((*column, direction.text.clone()));
+                drawing.width = drawing.width.max(*column + 1);
+            },
+            Event::Barline(_) => {},
+        }
+    }
+    Ok(())
+}
+
+fn check_spelling(pitch: &Note, key: &KeySignature) -> Result<(), EngraveError> {
+    if key.apply(pitch.clone()).accidental != pitch.accidental {
+        return Err(EngraveError::ConflictingAccidental(format!("{}{}", pitch.note_name,
+            pitch.octave)));
+    }
+    Ok(())
+}
+
+// The barline a bar ends with and where it is in its events
+fn closing(bar: &Bar) -> Option<(usize, &BarlineKind)> {
+    bar.events.iter().enumerate().rev().find_map(|(i, event)| match event {
+        Event::Barline(barline) if barline.kind != BarlineKind::RepeatStart => {
+            Some((i, &barline.kind))
+        },
+        _ => None,
+    })
+}
+
+/*
+ * Draws one bar. Directions that come after the closing barline can only be
+ * written past it at the end of a line or after a final barline, anywhere
+ * else they end up just before it.
+ */
+fn draw_bar(bar: &Bar, clef: &StaffType, key: &KeySignature, last: bool)
+    -> Result<Drawing, EngraveError> {
+    let mut drawing = Drawing::default();
+    // An empty measure is just the barline that ends it
+    if bar.events.is_empty() {
+        return Ok(drawing);
+    }
+    let starts_repeat = matches!(bar.events.first(),
+        Some(Event::Barline(Barline { kind: BarlineKind::RepeatStart, .. })));
+    let mut column = if starts_repeat { 2 } else { 1 };
+    if starts_repeat {
+        drawing.put(-1, 0, ':');
+        drawing.put(1, 0, ':');
+    }
+
+    let close = closing(bar);
+    let trails = last || matches!(close, Some((_, BarlineKind::Final)));
+    let (events, trailing) = match close {
+        Some((i, _)) if trails => bar.events.split_at(i + 1),
+        _ => (&bar.events[..], &[][..]),
+    };
+    draw_events(&mut drawing, events, clef, key, &mut column)?;
+
+    if let Some((_, BarlineKind::RepeatEnd { times })) = close {
+        drawing.put(-1, column, ':');
+        drawing.put(1, column, ':');
+        if let Some(times) = times {
+            drawing.texts.push((column, format!("x{}", times)));
+        }
+        column += 1;
+    }
+
+    drawing.width = drawing.width.max(column).max(4);
+    let mut after = drawing.width + 1;
+    let mut end = Drawing::default();
+    draw_events(&mut end, trailing, clef, key, &mut after)?;
+    drawing.texts.extend(end.texts);
+    Ok(drawing)
+}
+
+/*
+ * The key signature that spells every note in the bars, every letter that is
+ * played sharp or flat. A letter played two different ways can't be written.
+ */
+pub fn key_for(bars: &[Bar]) -> Result<KeySignature, EngraveError> {
+    let mut key = KeySignature::default();
+    let mut naturals: Vec<&'static str> = Vec::new();
+    for pitch in bars.iter().flat_map(|bar| bar.pitches()) {
+        let spelled = key.accidentals.iter().find(|(name, _)| *name == pitch.note_name)
+            .map(|(_, accidental)| accidental.clone())
+            .or(naturals.contains(&pitch.note_name).then_some(Accidental::Natural));
+
+        match spelled {
+            Some(accidental) if accidental != pitch.accidental => {
+                return Err(EngraveError::ConflictingAccidental(format!("{}{}",
+                    pitch.note_name, pitch.octave)));
+            },
+            Some(_) => {},
+            None if pitch.accidental == Accidental::Natural => naturals.push(pitch.note_name),
+            None => key.accidentals.push((pitch.note_name, pitch.accidental.clone())),
+        }
+    }
+    Ok(key)
+}
+
+/*
+ * Draws a line of music the way the parser reads it: the full clef, the key
+ * and time signature, then every bar with its notes, rests, repeat signs and
+ * directions. Reading the rows back gives the same bars. Layout is ignored,
+ * everything is spaced out evenly.
+ */
+pub fn engrave_line(line: &Line) -> Result<Vec<String>, EngraveError> {
+    let clef = &line.clef_type;
+    let key = &line.key_signature;
+    // The "ll" every line ends with reads back as two empty measures, so a
+    // line that already ends with them doesn't get them twice
+    let bars = match line.contents.as_slice() {
+        [bars @ .., a, b] if a.events.is_empty() && b.events.is_empty() => bars,
+        bars => bars,
+    };
+    let drawings = bars.iter()
+        .enumerate()
+        .map(|(i, bar)| draw_bar(bar, clef, key, i + 1 == bars.len()))
+        .collect::<Result<Vec<Drawing>, EngraveError>>()?;
+
+    let (art, art_top): (&[&str], isize) = match clef {
+        StaffType::Treble => (&TREBLE_ART, -5),
+        StaffType::Bass => (&BASS_ART, -5),
+    };
+    let rows_used = drawings.iter().flat_map(|d| d.cells.iter().map(|(row, _, _)| *row));
+    let top = rows_used.clone().min().unwrap_or(0).min(art_top);
+    let bottom = rows_used.max().unwrap_or(0).max(art_top + art.len() as isize - 1);
+
+    let time = line.time_signature.as_ref().and_then(|time| {
+        let size = [Beats::Whole, Beats::Half, Beats::Quarter, Beats::Eighth, Beats::Sixteenth,
+            Beats::ThirtySecond].iter().position(|b| *b == time.beat_size)?;
+        Some((time.beats.to_string(), (1usize << size).to_string()))
+    });
+    let time_width = time.as_ref().map_or(0, |(beats, size)| beats.len().max(size.len()));
+    let prefix_width = ART_WIDTH + key.accidentals.len() + time_width + 1;
+
+    let mut rows = Vec::new();
+    for row in top..=bottom {
+        let mut prefix: Vec<char> = art.get((row - art_top) as usize)
+            .map_or("", |art| *art)
+            .chars()
+            .collect();
+        prefix.resize(prefix_width, ' ');
+
+        for (i, (name, accidental)) in key.accidentals.iter().enumerate() {
+            let sits = (-5..=5).find(|&offset| letter_at(offset, clef) == *name);
+            if sits == Some(row) {
+                prefix[ART_WIDTH + i] = if *accidental == Accidental::Flat { 'b' } else { '#' };
+            }
+        }
+        if let Some((beats, size)) = &time {
+            let digits = match row { -2 => Some(beats), 2 => Some(size), _ => None };
+            for (i, c) in digits.into_iter().flat_map(|d| d.chars()).enumerate() {
+                prefix[ART_WIDTH + key.accidentals.len() + i] = c;
+            }
+        }
+
+        let mut text: String = prefix.into_iter().collect();
+        let last_bar = bars.len().saturating_sub(1);
+        for (i, (bar, drawing)) in bars.iter().zip(drawings.iter()).enumerate() {
+            let staff_line = row % 2 == 0 && (-4..=4).contains(&row);
+            let mut cells = vec![if staff_line { '-' } else { ' ' }; drawing.width];
+            for &(r, column, c) in drawing.cells.iter() {
+                if r == row {
+                    cells[column] = c;
+                }
+            }
+
+            text.push('l');
+            text.extend(cells);
+            if matches!(closing(bar), Some((_, BarlineKind::Final))) && i < last_bar {
+                text.push('l');
+            }
+        }
+        text.push_str("ll");
+        rows.push(text);
+    }
+
+    // Directions go on rows of their own above the staff, a new row whenever
+    // a word would run into the one before it
+    let mut texts: Vec<(usize, String)> = Vec::new();
+    let mut start = prefix_width + 1;
+    for (bar, drawing) in bars.iter().zip(drawings.iter()) {
+        texts.extend(drawing.texts.iter().map(|(column, text)| (start + column, text.clone())));
+        start += drawing.width + 1;
+        if matches!(closing(bar), Some((_, BarlineKind::Final))) {
+            start += 1;
+        }
+    }
+
+    let mut text_rows: Vec<String> = Vec::new();
+    for (column, text) in texts {
+        let free = text_rows.iter().position(|row| row.chars().count() + 1 < column);
+        let row = match free {
+            Some(free) => &mut text_rows[free],
+            None => {
+                text_rows.push(String::new());
+                text_rows.last_mut().unwrap()
+            },
+        };
+        let padding = column - row.chars().count();
+        row.push_str(&" ".repeat(padding));
+        row.push_str(&text);
+    }
+
+    text_rows.extend(rows);
+    Ok(text_rows)
+}
+
+/*
+ * A whole piece, every line between rows of "=".
+ */
+pub fn engrave(lines: &[Line]) -> Result<String, EngraveError> {
+    let mut score = String::new();
+    for line in lines {
+        let rows = engrave_line(line)?;
+        let width = rows.iter().map(|row| row.chars().count()).max().unwrap_or(0);
+        if score.is_empty() {
+            score.push_str(&"=".repeat(width));
+            score.push('\n');
+        }
+        for row in rows {
+            score.push_str(&row);
+            score.push('\n');
+        }
+        score.push_str(&"=".repeat(width));
+        score.push('\n');
+    }
+    Ok(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parser::{parse_file, parse_str};
+
+    #[test]
+    fn test_engrave_round_trip() {
+        for file in ["chords_and_repeats", "tape_and_repeats", "triplets", "dotted_rhythms",
+            "rests", "rhythms", "recursion", "bass_cmaj_scale", "annotated_cmaj_scale",
+            "continuation_systems"] {
+            let file = format!("testing_resources/{}.inst", file);
+            // The headings in some of these share a row with the clef
+            let mut lines = parse_file(file.clone()).unwrap();
+            for bar in lines.iter_mut().flat_map(|line| line.contents.iter_mut()) {
+                bar.events.retain(|e| !matches!(e, Event::Direction(d) if !is_word(&d.text)));
+            }
+            let score = engrave(&lines).unwrap();
+            let again = parse_str(&score).unwrap();
+            assert_eq!(lines.len(), again.len(), "{}", file);
+            for (line, other) in lines.iter().zip(again.iter()) {
+                assert_eq!(line.key_signature, other.key_signature, "{}", file);
+                for (bar, other) in line.contents.iter().zip(other.contents.iter()) {
+                    assert_eq!(bar.events.len(), other.events.len(), "{}\n{}", file, score);
+                    for (event, other) in bar.events.iter().zip(other.events.iter()) {
+                        assert_eq!(std::mem::discriminant(event), std::mem::discriminant(other),
+                            "{}\n{}", file, score);
+                        assert_eq!(event.duration(), other.duration(), "{}\n{}", file, score);
+                    }
+                    assert_eq!(bar.pitches(), other.pitches(), "{}\n{}", file, score);
+                    let texts = |bar: &Bar| bar.directions().into_iter().map(|d| d.text.clone())
+                        .collect::<Vec<_>>();
+                    assert_eq!(texts(bar), texts(other), "{}\n{}", file, score);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_engrave_key_signature() {
+        let pitch = |note_name: &'static str, accidental: Accidental| Event::Note(NoteEvent {
+            pitch: Note { accidental, note_name, octave: 4, rest: false },
+            duration: Beats::Quarter,
+            column: 0,
+        });
+        let bar = |events: Vec<Event>| Bar { measure_number: 1, events };
+
+        let key = key_for(&[bar(vec![pitch("F", Accidental::Sharp), pitch("C",
+            Accidental::Natural), pitch("F", Accidental::Sharp)])]).unwrap();
+        assert_eq!(vec![("F", Accidental::Sharp)], key.accidentals);
+        assert_eq!(Err(EngraveError::ConflictingAccidental("F4".to_string())),
+            key_for(&[bar(vec![pitch("F", Accidental::Natural), pitch("F", Accidental::Sharp)])]));
+    }
+}
+}
@@ -0,0 +1,199 @@
+pub mod ensemble {
+use crate::data_types::dt::*;
+use crate::interpreter::interpreter::{Channels, Interpreter};
+use crate::streams::streams::Streams;
+use crate::trace::trace::Tracer;
+
+pub const DEFAULT_TEMPO: usize = 120;
+
+/*
+ * One instrument playing its part in an ensemble.
+ */
+#[derive(Debug)]
+pub struct Player {
+    pub name: String,
+    pub interpreter: Interpreter,
+}
+
+/*
+ * Several instruments playing at once. Nothing actually runs in parallel,
+ * every step goes to whichever player is furthest behind on its clock (the
+ * one added first if there is a tie), so the same piece always plays out in
+ * the same order. A player waiting on a "recv:" sits out until something is
+ * sent down that channel.
+ *
+ * All of the players share one set of channels and one set of streams.
+ */
+#[derive(Debug)]
+pub struct Ensemble {
+    pub players: Vec<Player>,
+    pub channels: Channels,
+    pub streams: Streams,
+    // Quarter notes per minute
+    pub tempo: usize,
+}
+
+impl Default for Ensemble {
+    fn default() -> Self {
+        Self::new(Streams::default())
+    }
+}
+
+impl Ensemble {
+    pub fn new(streams: Streams) -> Self {
+        Ensemble {
+            players: Vec::new(),
+            channels: Channels::default(),
+            streams,
+            tempo: DEFAULT_TEMPO,
+        }
+    }
+
+    /*
+     * Adds a player with a piece already loaded into its interpreter.
+     */
+    pub fn add_player(&mut self, name: &str, mut interpreter: Interpreter) {
+        interpreter.channels = self.channels.clone();
+        self.players.push(Player { name: name.to_string(), interpreter });
+    }
+
+    /*
+     * Writes every player's events to one trace, each under its own name
+     * plus .inst.
+     */
+    pub fn trace(&mut self, tracer: &Tracer) {
+        for player in self.players.iter_mut() {
+            player.interpreter.tracer = Some(tracer.for_file(&format!("{}.inst", player.name)));
+        }
+    }
+
+    /*
+     * The player that goes next, None once nobody can.
+     */
+    pub fn next_player(&self) -> Option<usize> {
+        self.players.iter()
+            .enumerate()
+            .filter(|(_, player)| !player.interpreter.finished() && !player.interpreter.blocked())
+            .min_by_key(|(index, player)| (player.interpreter.machine.clock, *index))
+            .map(|(index, _)| index)
+    }
+
+    pub fn finished(&self) -> bool {
+        self.players.iter().all(|player| player.interpreter.finished())
+    }
+
+    /*
+     * Plays one event of the next player. Returns false once every player
+     * is done and fails if the ones left are all waiting on each other.
+     */
+    pub fn step(&mut self) -> Result<bool, RuntimeError> {
+        let Some(index) = self.next_player() else {
+            if self.finished() {
+                return Ok(false);
+            }
+
+            let waiting: Vec<String> = self.players.iter()
+                .filter_map(|player| player.interpreter.machine.waiting.clone())
+                .collect();
+            return Err(RuntimeError::Deadlock(waiting.join(", ")));
+        };
+
+        let interpreter = &mut self.players[index].interpreter;
+        std::mem::swap(&mut self.streams, &mut interpreter.streams);
+        let result = interpreter.step();
+        std::mem::swap(&mut self.streams, &mut interpreter.streams);
+        result.map(|_| true)
+    }
+
+    /*
+     * Plays until every player is done. A failure is located in the player
+     * that was playing when it happened.
+     */
+    pub fn run(&mut self) -> Result<(), RuntimeFailure> {
+        loop {
+            let player = self.next_player();
+            match self.step() {
+                Ok(true) => {},
+                Ok(false) => return Ok(()),
+                Err(error) => return Err(match player {
+                    Some(index) => self.players[index].interpreter.locate(error),
+                    None => error.into(),
+                }),
+            }
+        }
+    }
+
+    /*
+     * How long a number of ticks lasts at the ensemble's tempo.
+     */
+    pub fn seconds(&self, ticks: usize) -> f64 {
+        let quarters = ticks as f64 / (TICKS_PER_WHOLE / 4) as f64;
+        quarters * 60.0 / self.tempo as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parser::parse_file;
+    use crate::streams::streams::SharedOutput;
+    use std::io;
+
+    fn ensemble(files: &[&str], output: &SharedOutput) -> Ensemble {
+        let mut ensemble = Ensemble::new(Streams::new(io::empty(), output.clone()));
+        for file in files {
+            let mut interpreter = Interpreter::default().with_file(file);
+            interpreter.load(&parse_file(file.to_string()).unwrap());
+            ensemble.add_player(file, interpreter);
+        }
+        ensemble
+    }
+
+    #[test]
+    fn test_ensemble_plays_in_clock_order() {
+        // The half note finishes after the quarter note, so its 8 comes second
+        let output = SharedOutput::new();
+        let mut players = ensemble(&["testing_resources/projects/clock/instruments/a_half.inst",
+            "testing_resources/projects/clock/instruments/b_quarter.inst"], &output);
+        players.run().unwrap();
+
+        assert_eq!("48", output.text());
+        assert_eq!(1.0, players.seconds(TICKS_PER_WHOLE / 2));
+    }
+
+    #[test]
+    fn test_ensemble_channels() {
+        let output = SharedOutput::new();
+        let mut players = ensemble(&["testing_resources/projects/duet/instruments/a_receiver.inst",
+            "testing_resources/projects/duet/instruments/b_sender.inst"], &output);
+        players.run().unwrap();
+
+        // The receiver waits for the sender's quarter note and picks up from there
+        assert_eq!("8", output.text());
+        assert_eq!(240, players.players[0].interpreter.machine.clock);
+
+        let output = SharedOutput::new();
+        let mut alone = ensemble(&["testing_resources/projects/duet/instruments/a_receiver.inst"],
+            &output);
+        assert_eq!(Err(RuntimeError::Deadlock("x".to_string()).into()), alone.run());
+    }
+
+    #[test]
+    fn test_ensemble_failure() {
+        // The scale runs out of values before the half note is done, so
+        // nothing gets written and the failure is put on the scale's file
+        let scale = "testing_resources/annotated_cmaj_scale.inst";
+        let output = SharedOutput::new();
+        let mut players = ensemble(&["testing_resources/projects/clock/instruments/a_half.inst",
+            scale], &output);
+        let failure = players.run().unwrap_err();
+        assert_eq!(RuntimeFailure {
+            error: RuntimeError::StackUnderflow(1),
+            location: Some(SourceLocation { file: scale.to_string(), measure: 1, column: 4 }),
+            calls: Vec::new(),
+        }, failure);
+        assert_eq!(3, failure.exit_code());
+        assert_eq!("", output.text());
+    }
+}
+}
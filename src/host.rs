@@ -0,0 +1,362 @@
+pub mod host {
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use crate::data_types::dt::*;
+use crate::interpreter::interpreter::Machine;
+
+/*
+ * What a host function gives back, the values to push or why it failed.
+ */
+pub type HostResult = Result<Vec<i64>, String>;
+
+/*
+ * A value a host function can take as an argument, popped off the stack.
+ * Numbers are taken as they are, bools are anything but 0 and a String is
+ * laid out the way ReadLine leaves a line, its length on top with the first
+ * character just under it.
+ */
+pub trait FromStack: Sized {
+    fn from_stack(machine: &mut Machine, measure: usize) -> Result<Self, RuntimeError>;
+}
+
+impl FromStack for i64 {
+    fn from_stack(machine: &mut Machine, measure: usize) -> Result<Self, RuntimeError> {
+        machine.pop(measure)
+    }
+}
+
+impl FromStack for bool {
+    fn from_stack(machine: &mut Machine, measure: usize) -> Result<Self, RuntimeError> {
+        Ok(machine.pop(measure)? != 0)
+    }
+}
+
+impl FromStack for char {
+    fn from_stack(machine: &mut Machine, measure: usize) -> Result<Self, RuntimeError> {
+        let value = machine.pop(measure)?;
+        u32::try_from(value).ok()
+            .and_then(char::from_u32)
+            .ok_or(RuntimeError::InvalidCharacter(value, measure))
+    }
+}
+
+impl FromStack for String {
+    fn from_stack(machine: &mut Machine, measure: usize) -> Result<Self, RuntimeError> {
+        // A negative length is an empty string
+        let length = machine.pop(measure)?.max(0);
+        (0..length).map(|_| char::from_stack(machine, measure)).collect()
+    }
+}
+
+/*
+ * What a host function can give back, pushed onto the stack the opposite
+ * way FromStack takes it off. A Result that is an Err fails the piece with
+ * the error's message.
+ */
+pub trait IntoStack {
+    fn into_stack(self, values: &mut Vec<i64>) -> Result<(), String>;
+}
+
+impl IntoStack for () {
+    fn into_stack(self, _: &mut Vec<i64>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl IntoStack for i64 {
+    fn into_stack(self, values: &mut Vec<i64>) -> Result<(), String> {
+        values.push(self);
+        Ok(())
+    }
+}
+
+impl IntoStack for bool {
+    fn into_stack(self, values: &mut Vec<i64>) -> Result<(), String> {
+        values.push(self as i64);
+        Ok(())
+    }
+}
+
+impl IntoStack for char {
+    fn into_stack(self, values: &mut Vec<i64>) -> Result<(), String> {
+        values.push(self as i64);
+        Ok(())
+    }
+}
+
+impl IntoStack for String {
+    fn into_stack(self, values: &mut Vec<i64>) -> Result<(), String> {
+        let chars: Vec<i64> = self.chars().map(|c| c as i64).collect();
+        values.extend(chars.iter().rev());
+        values.push(chars.len() as i64);
+        Ok(())
+    }
+}
+
+impl IntoStack for Vec<i64> {
+    fn into_stack(self, values: &mut Vec<i64>) -> Result<(), String> {
+        values.extend(self);
+        Ok(())
+    }
+}
+
+impl<T: IntoStack, E: Display> IntoStack for Result<T, E> {
+    fn into_stack(self, values: &mut Vec<i64>) -> Result<(), String> {
+        self.map_err(|e| e.to_string())?.into_stack(values)
+    }
+}
+
+fn host_result(value: impl IntoStack) -> HostResult {
+    let mut values = Vec::new();
+    value.into_stack(&mut values)?;
+    Ok(values)
+}
+
+/*
+ * A closure that can be bound to a name, Args is its arguments as a tuple.
+ * The first argument is the deepest on the stack and the last one is on top,
+ * so they are written in the same order the piece pushes them.
+ */
+pub trait HostFn<Args>: 'static {
+    type Output;
+    fn pop_and_call(&mut self, machine: &mut Machine, measure: usize)
+        -> Result<Self::Output, RuntimeError>;
+}
+
+macro_rules! host_fn {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg: FromStack),*> HostFn<($($arg,)*)> for F
+        where F: FnMut($($arg),*) -> R + 'static {
+            type Output = R;
+
+            #[allow(non_snake_case, unused_variables)]
+            fn pop_and_call(&mut self, machine: &mut Machine, measure: usize)
+                -> Result<R, RuntimeError> {
+                host_fn!(@pop machine, measure; $($arg)*);
+                Ok(self($($arg),*))
+            }
+        }
+    };
+    // Pops the last argument first since it is on top
+    (@pop $machine:ident, $measure:ident;) => {};
+    (@pop $machine:ident, $measure:ident; $first:ident $($rest:ident)*) => {
+        host_fn!(@pop $machine, $measure; $($rest)*);
+        let $first = $first::from_stack($machine, $measure)?;
+    };
+}
+
+host_fn!();
+host_fn!(A);
+host_fn!(A, B);
+host_fn!(A, B, C);
+host_fn!(A, B, C, D);
+
+/*
+ * What calling a host function started, either done already or a future
+ * for an async one.
+ */
+pub enum HostCall {
+    Done(HostResult),
+    Pending(Pin<Box<dyn Future<Output = HostResult>>>),
+}
+
+type Binding = Box<dyn FnMut(&mut Machine, usize) -> Result<HostCall, RuntimeError>>;
+
+/*
+ * The Rust functions a piece can call with "host:name", by name.
+ */
+#[derive(Default)]
+pub struct HostFunctions {
+    functions: BTreeMap<String, Binding>,
+}
+
+impl std::fmt::Debug for HostFunctions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
+}
+
+impl HostFunctions {
+    /*
+     * Binds a closure to name. Its arguments are popped off the stack and
+     * what it returns is pushed back, so the closure's argument types have
+     * to be written out for Rust to know what to pop.
+     */
+    pub fn register<Args, F>(&mut self, name: &str, mut function: F)
+    where F: HostFn<Args>, F::Output: IntoStack {
+        self.functions.insert(name.to_string(), Box::new(move |machine, measure| {
+            let output = function.pop_and_call(machine, measure)?;
+            Ok(HostCall::Done(host_result(output)))
+        }));
+    }
+
+    /*
+     * Binds a closure that returns a future. The arguments are popped when
+     * the piece gets to the call and the results are pushed once the future
+     * is done, nothing else plays in between.
+     */
+    pub fn register_async<Args, F, Fut>(&mut self, name: &str, mut function: F)
+    where F: HostFn<Args, Output = Fut>, Fut: Future + 'static, Fut::Output: IntoStack {
+        self.functions.insert(name.to_string(), Box::new(move |machine, measure| {
+            let future = function.pop_and_call(machine, measure)?;
+            Ok(HostCall::Pending(Box::pin(async move { host_result(future.await) })))
+        }));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    /*
+     * Starts the function bound to name, taking its arguments off the
+     * machine's stack.
+     */
+    pub fn call(&mut self, name: &str, machine: &mut Machine, measure: usize)
+        -> Result<HostCall, RuntimeError> {
+        let function = self.functions.get_mut(name)
+            .ok_or_else(|| RuntimeError::UnknownHost(name.to_string(), measure))?;
+        function(machine, measure)
+    }
+}
+
+/*
+ * A host call that is still running, held until its results are pushed.
+ */
+pub struct PendingCall {
+    pub name: String,
+    pub measure: usize,
+    pub future: Pin<Box<dyn Future<Output = HostResult>>>,
+}
+
+impl std::fmt::Debug for PendingCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "PendingCall({} in measure {})", self.name, self.measure)
+    }
+}
+
+struct Unpark(std::thread::Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/*
+ * Waits for a future on this thread, for pieces played without an executor.
+ * Futures that need one of their own (a tokio timer, say) have to be played
+ * with run_async instead.
+ */
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::bytecode::compile_file;
+    use crate::check::check::check;
+    use crate::instructions::instructions::{Instruction, Operation};
+    use crate::interpreter::interpreter::Interpreter;
+    use crate::parser::parser::parse_str;
+    use crate::repl::repl::translate;
+    use crate::streams::streams::Streams;
+
+    fn lines(shorthand: &str) -> Vec<Line> {
+        parse_str(&translate(shorthand).unwrap()).unwrap()
+    }
+
+    // Not ready the first time it is polled
+    struct Later {
+        value: i64,
+        polled: bool,
+    }
+
+    impl Future for Later {
+        type Output = i64;
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<i64> {
+            if self.polled {
+                return Poll::Ready(self.value);
+            }
+            self.polled = true;
+            context.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_host_functions() {
+        // A quarter note pushes 4 and a half note 8, the first argument is
+        // the deeper one
+        let mut interpreter = Interpreter::default();
+        interpreter.register("sub", |a: i64, b: i64| a - b);
+        interpreter.register("nothing", || ());
+        interpreter.run(&lines("C4q C4h host:sub host:nothing")).unwrap();
+        assert_eq!(vec![-4], interpreter.machine.stack);
+
+        let failing = |value: i64| if value > 5 { Err("too big") } else { Ok(value) };
+        interpreter.register("small", failing);
+        let failure = interpreter.run(&lines("C4q | C4h host:small")).unwrap_err();
+        assert_eq!(RuntimeError::Host("small".to_string(), "too big".to_string(), 2), failure.error);
+        assert_eq!(10, failure.exit_code());
+        assert_eq!(RuntimeError::UnknownHost("missing".to_string(), 1),
+            interpreter.run(&lines("host:missing")).unwrap_err().error);
+    }
+
+    #[test]
+    fn test_host_async() {
+        let mut interpreter = Interpreter::default();
+        interpreter.register_async("later", |a: i64| Later { value: a * 10, polled: false });
+        let piece = lines("C4q host:later C4q");
+        interpreter.run(&piece).unwrap();
+        assert_eq!(vec![40, 4], interpreter.machine.stack);
+
+        let mut interpreter = Interpreter::default();
+        interpreter.register_async("later", |a: i64| Later { value: a + 1, polled: false });
+        block_on(interpreter.run_async(&piece)).unwrap();
+        assert_eq!(vec![5, 4], interpreter.machine.stack);
+    }
+
+    #[test]
+    fn test_host_values() {
+        // Strings go on the stack the way ReadLine puts a line there
+        let mut machine = Machine::default();
+        let mut streams = Streams::new("ok\n".as_bytes(), std::io::sink());
+        let read_line = Operation { instruction: Instruction::ReadLine, operand: 0 };
+        machine.execute(read_line, 1, &mut streams).unwrap();
+        let mut values = Vec::new();
+        "ok".to_string().into_stack(&mut values).unwrap();
+        assert_eq!(machine.stack, values);
+        assert_eq!("ok", String::from_stack(&mut machine, 1).unwrap());
+        assert!(machine.stack.is_empty());
+
+        machine.stack = vec![0x1F600, 0];
+        assert!(!bool::from_stack(&mut machine, 1).unwrap());
+        assert_eq!('😀', char::from_stack(&mut machine, 1).unwrap());
+        assert_eq!(Err(RuntimeError::StackUnderflow(3)), i64::from_stack(&mut machine, 3));
+    }
+
+    #[test]
+    fn test_host_calls_outside_the_interpreter() {
+        // The checker can't know what a host function leaves on the stack
+        // and compiled pieces can't call one at all
+        assert_eq!(1, check("piece.inst", &lines("D4q")).len());
+        assert!(check("piece.inst", &lines("host:two D4q")).is_empty());
+        assert!(matches!(compile_file("piece.inst", &lines("C4q host:two")),
+            Err(BytecodeError::HostCall(name, 1)) if name == "two"));
+    }
+}
+}
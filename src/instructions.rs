@@ -0,0 +1,398 @@
+pub mod instructions {
+use std::collections::BTreeMap;
+use serde::Deserialize;
+use crate::data_types::dt::*;
+use crate::trace::trace::pitch_name;
+
+// The default table written out the way expression.toml declares one
+pub const DEFAULT_TABLE: &str = include_str!("default_instructions.toml");
+
+/*
+ * Everything the machine knows how to do. Which note plays which instruction
+ * is decided by an InstructionTable.
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Instruction {
+    Nop,
+    Push,
+    Pop,
+    Dup,
+    Swap,
+    Over,
+    Rot,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Lt,
+    Gt,
+    Not,
+    Load,
+    Store,
+    ReadByte,
+    ReadNumber,
+    ReadLine,
+    WriteChar,
+    WriteNumber,
+    SkipBar,
+    Halt,
+}
+
+impl Instruction {
+    // Every instruction, in the order they are numbered in compiled files
+    pub const ALL: [Instruction; 25] = {
+        use Instruction::*;
+        [Nop, Push, Pop, Dup, Swap, Over, Rot, Add, Sub, Mul, Div, Mod, Eq, Lt, Gt, Not, Load,
+            Store, ReadByte, ReadNumber, ReadLine, WriteChar, WriteNumber, SkipBar, Halt]
+    };
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_code(code: u8) -> Option<Instruction> {
+        Self::ALL.get(code as usize).copied()
+    }
+
+    /*
+     * The name expression.toml and listings use, "push" or "write_char".
+     */
+    pub fn name(self) -> &'static str {
+        const NAMES: [&str; 25] = ["nop", "push", "pop", "dup", "swap", "over", "rot", "add", "sub",
+            "mul", "div", "mod", "eq", "lt", "gt", "not", "load", "store", "read_byte",
+            "read_number", "read_line", "write_char", "write_number", "skip_bar", "halt"];
+        NAMES[self as usize]
+    }
+
+    pub fn from_name(name: &str) -> Option<Instruction> {
+        Self::ALL.into_iter().find(|instruction| instruction.name() == name)
+    }
+}
+
+/*
+ * How notes are turned into instructions. Absolute looks at the pitch of each
+ * note on its own. Interval looks at how far each note is from the one before
+ * it, so a piece does the same thing in any key.
+ */
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OpcodeMode {
+    #[default]
+    Absolute,
+    Interval,
+}
+
+/*
+ * An instruction together with the number it works with, only Push uses the
+ * operand for now. In absolute mode the operand is the rhythm of the note
+ * (see Beats::literal), in interval mode it is the size of the leap.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Operation {
+    pub instruction: Instruction,
+    pub operand: i64,
+}
+
+/*
+ * Maps pitches to instructions. A single note looks up its pitch class (C is
+ * 0, C# is 1 and so on up to B at 11) in `notes` and a chord looks up its
+ * lowest pitch in `chords`. Octave does not matter and rests never do
+ * anything.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstructionTable {
+    pub notes: [Instruction; 12],
+    pub chords: [Instruction; 12],
+}
+
+impl Default for InstructionTable {
+    /*
+     * The white keys get the instructions that get used the most since the
+     * black keys need a key signature to reach.
+     *
+     *        note      chord
+     *   C    Push      WriteNumber
+     *   C#   Mul       Over
+     *   D    Add       WriteChar
+     *   D#   Div       Rot
+     *   E    Sub       ReadNumber
+     *   F    Dup       ReadByte
+     *   F#   Mod       ReadLine
+     *   G    Swap      Eq
+     *   G#   Load      Gt
+     *   A    Pop       Lt
+     *   A#   Store     Not
+     *   B    SkipBar   Halt
+     */
+    fn default() -> Self {
+        use Instruction::*;
+        InstructionTable {
+            notes: [Push, Mul, Add, Div, Sub, Dup, Mod, Swap, Load, Pop, Store, SkipBar],
+            chords: [WriteNumber, Over, WriteChar, Rot, ReadNumber, ReadByte, ReadLine, Eq, Gt, Lt,
+                Not, Halt],
+        }
+    }
+}
+
+impl InstructionTable {
+    /*
+     * The instruction an event plays, if it plays one at all. Rests, barlines
+     * and directions don't.
+     */
+    pub fn lookup(&self, event: &Event) -> Option<Instruction> {
+        match event {
+            Event::Note(note) => Some(self.notes[note.pitch.pitch_class()]),
+            Event::Chord(chord) => chord.pitches.first()
+                .map(|lowest| self.chords[lowest.pitch_class()]),
+            _ => None,
+        }
+    }
+}
+
+/*
+ * Where a table declared in expression.toml starts from before its own
+ * entries go on top. Inherit is the project's table for an instrument and the
+ * default table for the project, Empty has nothing so every pitch has to be
+ * given.
+ */
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TableBase {
+    #[default]
+    Inherit,
+    Default,
+    Empty,
+}
+
+/*
+ * An instruction table as expression.toml declares it, pitch names like "C",
+ * "F#" or "Bb" mapped to instruction names like "push" or "write_char".
+ */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TableSpec {
+    pub base: TableBase,
+    pub notes: BTreeMap<String, Instruction>,
+    pub chords: BTreeMap<String, Instruction>,
+}
+
+impl TableSpec {
+    /*
+     * Builds the table, starting from inherited unless base says otherwise.
+     * Two names for the same pitch (C# and Db) that disagree are a conflict,
+     * and a table built from empty has to cover all twelve pitches. Name is
+     * where the table was declared ("instructions"), for the errors.
+     */
+    pub fn resolve(&self, inherited: &InstructionTable, name: &str)
+        -> Result<InstructionTable, ProjectError> {
+        let mut table = match self.base {
+            TableBase::Inherit => inherited.clone(),
+            TableBase::Default => InstructionTable::default(),
+            TableBase::Empty => InstructionTable {
+                notes: [Instruction::Nop; 12],
+                chords: [Instruction::Nop; 12],
+            },
+        };
+
+        for (kind, entries, slots) in [("notes", &self.notes, &mut table.notes),
+            ("chords", &self.chords, &mut table.chords)] {
+            let section = format!("[{}.{}]", name, kind);
+            let mut given: [Option<&str>; 12] = [None; 12];
+            for (pitch, instruction) in entries {
+                let class = pitch_class(pitch)
+                    .ok_or_else(|| ProjectError::InvalidPitchName(pitch.clone(), section.clone()))?;
+                if let Some(other) = given[class].filter(|other| entries[*other] != *instruction) {
+                    return Err(ProjectError::ConflictingPitches(other.to_string(), pitch.clone(),
+                        section));
+                }
+                given[class] = Some(pitch);
+                slots[class] = *instruction;
+            }
+
+            if self.base == TableBase::Empty && given.contains(&None) {
+                let missing: Vec<&str> = (0..12)
+                    .filter(|class| given[*class].is_none())
+                    .map(|class| PITCH_NAMES[class])
+                    .collect();
+                return Err(ProjectError::TableGap(section, missing.join(", ")));
+            }
+        }
+
+        Ok(table)
+    }
+}
+
+// Interval mode only measures leaps from notes this many semitones from C0 or
+// closer. No staff gets anywhere near it but a piece built in Rust or a
+// damaged compiled file could hold any pitch, and the leap has to fit in an
+// operand.
+pub const MAX_SEMITONE: i64 = 12 * 1024;
+
+/*
+ * Whether interval mode can measure a leap from note. The octave is looked at
+ * first so working out the semitone can't overflow.
+ */
+pub fn measurable(note: &Note) -> bool {
+    note.octave <= 1024 && (note.semitone() as i64).abs() <= MAX_SEMITONE
+}
+
+const PITCH_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/*
+ * The pitch class of a name like "C", "F#" or "Bb", the same way the parser
+ * spells pitches.
+ */
+fn pitch_class(name: &str) -> Option<usize> {
+    let (letter, accidental) = name.split_at(name.len().min(1));
+    let note_name = NOTE_ORDER.iter().find(|&&n| n == letter)?;
+    let accidental = match accidental {
+        "" => Accidental::Natural,
+        "#" => Accidental::Sharp,
+        "b" => Accidental::Flat,
+        _ => return None,
+    };
+    Some(Note { accidental, note_name, octave: 4, rest: false }.pitch_class())
+}
+
+/*
+ * Reads the instructions out of a piece one event at a time. In interval mode
+ * it remembers the last pitch it saw, the very first note only sets that
+ * pitch and plays nothing. Intervals are counted in semitones and wrap at the
+ * octave to pick the table slot, each whole octave of the leap adds one to
+ * the operand. Chords are measured from their lowest note. A note further
+ * than MAX_SEMITONE from C0 fails with InvalidInstruction.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Decoder {
+    pub table: InstructionTable,
+    pub mode: OpcodeMode,
+    pub previous: Option<Note>,
+}
+
+impl Decoder {
+    pub fn new(table: InstructionTable, mode: OpcodeMode) -> Self {
+        Decoder {
+            table,
+            mode,
+            previous: None,
+        }
+    }
+
+    pub fn decode(&mut self, event: &Event, measure: usize)
+        -> Result<Option<Operation>, RuntimeError> {
+        let table = self.table.clone();
+        self.decode_with(&table, event, measure)
+    }
+
+    /*
+     * Decodes with some other table, for instruments that declare their own.
+     */
+    pub fn decode_with(&mut self, table: &InstructionTable, event: &Event, measure: usize)
+        -> Result<Option<Operation>, RuntimeError> {
+        if self.mode == OpcodeMode::Absolute {
+            let operand = event.duration().map_or(0, |duration| duration.literal());
+            return Ok(table.lookup(event)
+                .map(|instruction| Operation { instruction, operand }));
+        }
+
+        let (pitch, slots) = match event {
+            Event::Note(note) => (&note.pitch, &table.notes),
+            Event::Chord(chord) => match chord.pitches.first() {
+                Some(lowest) => (lowest, &table.chords),
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        if !measurable(pitch) {
+            return Err(RuntimeError::InvalidInstruction(pitch_name(pitch), measure));
+        }
+
+        let Some(previous) = self.previous.replace(pitch.clone()) else {
+            return Ok(None);
+        };
+        let interval = previous.interval_to(pitch);
+        Ok(Some(Operation {
+            instruction: slots[interval.rem_euclid(12) as usize],
+            operand: (interval.abs() / 12) as i64 + 1,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(note_name: &'static str, accidental: Accidental) -> Note {
+        Note { accidental, note_name, octave: 4, rest: false }
+    }
+
+    #[test]
+    fn test_instructions_lookup() {
+        let table = InstructionTable::default();
+        let single = |pitch: Note| Event::Note(NoteEvent {
+            pitch,
+            duration: Beats::Quarter,
+            column: 0,
+        });
+
+        assert_eq!(Some(Instruction::Push), table.lookup(&single(note("C", Accidental::Natural))));
+        assert_eq!(Some(Instruction::Mul), table.lookup(&single(note("C", Accidental::Sharp))));
+        assert_eq!(Some(Instruction::Mul), table.lookup(&single(note("D", Accidental::Flat))));
+        assert_eq!(Some(Instruction::SkipBar), table.lookup(&single(note("C", Accidental::Flat))));
+
+        let chord = Event::Chord(ChordEvent {
+            pitches: vec![note("B", Accidental::Natural), note("D", Accidental::Natural)],
+            duration: Beats::Quarter,
+            column: 0,
+        });
+        assert_eq!(Some(Instruction::Halt), table.lookup(&chord));
+
+        let rest = Event::Rest(RestEvent { duration: Beats::Quarter, column: 0 });
+        assert_eq!(None, table.lookup(&rest));
+    }
+
+    #[test]
+    fn test_instructions_decoding() {
+        let single = |note_name: &'static str, octave: usize| Event::Note(NoteEvent {
+            pitch: Note { accidental: Accidental::Natural, note_name, octave, rest: false },
+            duration: Beats::Quarter,
+            column: 0,
+        });
+        let operation = |instruction: Instruction, operand: i64| {
+            Ok(Some(Operation { instruction, operand }))
+        };
+
+        // Absolute mode takes the operand from the rhythm instead
+        let mut decoder = Decoder::default();
+        let dotted_half = Event::Note(NoteEvent {
+            pitch: Note { accidental: Accidental::Natural, note_name: "C", octave: 4, rest: false },
+            duration: Beats::DottedHalf,
+            column: 0,
+        });
+        assert_eq!(operation(Instruction::Push, 12), decoder.decode(&dotted_half, 1));
+        assert_eq!(5, Beats::HalfTriplet.literal());
+        assert_eq!(0, Beats::ThirtySecond.literal());
+
+        let mut decoder = Decoder::new(InstructionTable::default(), OpcodeMode::Interval);
+        assert_eq!(Ok(None), decoder.decode(&single("G", 4), 1));
+        // G up to A is a whole step even though the octave number changes
+        assert_eq!(operation(Instruction::Add, 1), decoder.decode(&single("A", 5), 1));
+        assert_eq!(operation(Instruction::Push, 2), decoder.decode(&single("A", 6), 1));
+        // Down a fourth wraps around to the fifth slot
+        assert_eq!(operation(Instruction::Swap, 1), decoder.decode(&single("E", 5), 1));
+        assert_eq!(Ok(None), decoder.decode(&Event::Rest(RestEvent {
+            duration: Beats::Quarter,
+            column: 0,
+        }), 1));
+
+        // A note too high to measure a leap from fails instead of overflowing
+        assert_eq!(Err(RuntimeError::InvalidInstruction("G1024".to_string(), 3)),
+            decoder.decode(&single("G", 1024), 3));
+        assert!(matches!(decoder.decode(&single("C", usize::MAX), 3),
+            Err(RuntimeError::InvalidInstruction(..))));
+    }
+}
+}
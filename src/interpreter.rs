@@ -0,0 +1,1200 @@
+pub mod interpreter {
+use crate::data_types::dt::*;
+use crate::host::host::{block_on, HostCall, HostFn, HostFunctions, HostResult,
+    IntoStack, PendingCall};
+use crate::instructions::instructions::*;
+use crate::streams::streams::Streams;
+use crate::trace::trace::{TraceRecord, Tracer};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::future::Future;
+use std::rc::Rc;
+use std::time::Instant;
+use serde::Deserialize;
+
+// How deep calls can nest before the piece is stopped with a stack overflow
+pub const DEFAULT_CALL_DEPTH: usize = 256;
+
+/*
+ * How far a piece may go before it is stopped, None is no limit at all.
+ * Events counts everything played (notes, rests, barlines and directions),
+ * stack and tape are the most values each may hold, calls is how deep calls
+ * may nest, millis is wall-clock time from the first step and ticks is how
+ * far the clock may get, 192 to a whole note. Any of them can be set in the
+ * [limits] table of an expression.toml.
+ */
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub events: Option<u64>,
+    pub stack: Option<usize>,
+    pub tape: Option<usize>,
+    pub calls: usize,
+    pub millis: Option<u64>,
+    pub ticks: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            events: None,
+            stack: None,
+            tape: None,
+            calls: DEFAULT_CALL_DEPTH,
+            millis: None,
+            ticks: None,
+        }
+    }
+}
+
+impl Limits {
+    /*
+     * Fails if a run that has played events so far, started at started and
+     * got its clock to clock has gone past any of the limits on how long it
+     * may run.
+     */
+    pub fn check_progress(&self, events: u64, started: Instant, clock: usize, measure: usize)
+        -> Result<(), RuntimeError> {
+        if let Some(limit) = self.events.filter(|limit| events > *limit) {
+            return Err(RuntimeError::EventLimit(limit, measure));
+        }
+        if let Some(limit) = self.ticks.filter(|limit| clock > *limit) {
+            return Err(RuntimeError::TickLimit(limit, measure));
+        }
+        match self.millis {
+            Some(limit) if started.elapsed().as_millis() > u128::from(limit) => {
+                Err(RuntimeError::TimeLimit(limit, measure))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+// No event takes more than this many values off the stack (Rot takes three),
+// so that is all a Delta has to keep to put the stack back
+const MAX_POPS: usize = 3;
+
+/*
+ * What an instruction wants the interpreter to do next.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    Next,
+    SkipBar,
+}
+
+/*
+ * Where the interpreter is in a program. Measure is the index into
+ * Program::measures (not the measure number printed on the score) and event
+ * is the index into that measure's events.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProgramCounter {
+    pub measure: usize,
+    pub event: usize,
+}
+
+/*
+ * A piece flattened into the order it is played in. Tuplet groups are opened
+ * up so every event can be reached with a ProgramCounter.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Program {
+    pub measures: Vec<Bar>,
+}
+
+impl Program {
+    pub fn from_lines(lines: &[Line]) -> Self {
+        fn flatten(events: &[Event], found: &mut Vec<Event>) {
+            for event in events {
+                match event {
+                    Event::Tuplet(tuplet) => flatten(&tuplet.events, found),
+                    e => found.push(e.clone()),
+                }
+            }
+        }
+
+        let measures = lines.iter()
+            .flat_map(|line| line.contents.iter())
+            .map(|bar| {
+                let mut events = Vec::new();
+                flatten(&bar.events, &mut events);
+                Bar { measure_number: bar.measure_number, events }
+            })
+            .collect();
+
+        Program { measures }
+    }
+
+    pub fn event(&self, pc: ProgramCounter) -> Option<&Event> {
+        self.measures.get(pc.measure).and_then(|bar| bar.events.get(pc.event))
+    }
+
+    /*
+     * The event after pc, moving on to the next measure at the end of one.
+     */
+    pub fn next(&self, pc: ProgramCounter) -> ProgramCounter {
+        self.settle(ProgramCounter { measure: pc.measure, event: pc.event + 1 })
+    }
+
+    /*
+     * The first event of the measure after the one pc is in, where a SkipBar
+     * goes.
+     */
+    pub fn next_measure(&self, pc: ProgramCounter) -> ProgramCounter {
+        self.settle(ProgramCounter { measure: pc.measure + 1, event: 0 })
+    }
+
+    /*
+     * The first event of the piece.
+     */
+    pub fn start(&self) -> ProgramCounter {
+        self.settle(ProgramCounter::default())
+    }
+
+    // Empty measures have nothing to play, so a pc past the end of a measure
+    // moves on to the first one after it that has something in it
+    fn settle(&self, mut pc: ProgramCounter) -> ProgramCounter {
+        while self.measures.get(pc.measure).is_some_and(|bar| pc.event >= bar.events.len()) {
+            pc = ProgramCounter { measure: pc.measure + 1, event: 0 };
+        }
+        pc
+    }
+
+    /*
+     * Where a repeat that ends at pc starts again, just after the closest
+     * start repeat sign or label before it, or the top of the piece if there
+     * is neither.
+     */
+    pub fn repeat_start(&self, pc: ProgramCounter) -> ProgramCounter {
+        let mut found = self.start();
+        for measure in (0..=pc.measure).rev() {
+            let events = &self.measures[measure].events;
+            let end = if measure == pc.measure { pc.event } else { events.len() };
+            let start = events[..end].iter().rposition(|e| match e {
+                Event::Barline(barline) => barline.kind == BarlineKind::RepeatStart,
+                Event::Direction(direction) => direction.marker("label").is_some(),
+                _ => false,
+            });
+
+            if let Some(event) = start {
+                found = self.next(ProgramCounter { measure, event });
+                break;
+            }
+        }
+
+        found
+    }
+
+    /*
+     * Where the passage marked with "label:name" starts, the event right
+     * after the label.
+     */
+    pub fn label(&self, name: &str) -> Option<ProgramCounter> {
+        self.measures.iter()
+            .enumerate()
+            .find_map(|(measure, bar)| bar.events.iter()
+                .position(|e| matches!(e, Event::Direction(d) if d.marker("label") == Some(name)))
+                .map(|event| self.next(ProgramCounter { measure, event })))
+    }
+}
+
+/*
+ * Where "call:target" goes from inside programs[current]: a label in the same
+ * program, an instrument from the top or "instrument.label". Programs after
+ * the first are the instruments that can be called by name.
+ */
+pub fn find_call(programs: &[(String, Program)], current: usize, target: &str)
+    -> Option<(usize, ProgramCounter)> {
+    if let Some((instrument, label)) = target.split_once('.') {
+        let program = programs.iter().skip(1).position(|(n, _)| n == instrument)? + 1;
+        return Some((program, programs[program].1.label(label)?));
+    }
+
+    if let Some(pc) = programs[current].1.label(target) {
+        return Some((current, pc));
+    }
+    programs.iter()
+        .skip(1)
+        .position(|(n, _)| n == target)
+        .map(|program| (program + 1, programs[program + 1].1.start()))
+}
+
+/*
+ * Named queues of values that instruments use to talk to each other. Every
+ * value is sent with the time on the sender's clock. Clones share the same
+ * queues.
+ */
+type Queue = VecDeque<(usize, i64)>;
+
+#[derive(Clone, Debug, Default)]
+pub struct Channels(Rc<RefCell<BTreeMap<String, Queue>>>);
+
+impl Channels {
+    pub fn send(&self, name: &str, time: usize, value: i64) {
+        self.0.borrow_mut().entry(name.to_string()).or_default().push_back((time, value));
+    }
+
+    pub fn receive(&self, name: &str) -> Option<(usize, i64)> {
+        self.0.borrow_mut().get_mut(name).and_then(|queue| queue.pop_front())
+    }
+
+    pub fn has_message(&self, name: &str) -> bool {
+        self.0.borrow().get(name).is_some_and(|queue| !queue.is_empty())
+    }
+}
+
+/*
+ * What a call leaves behind so the caller can carry on once it returns. Call
+ * is where the call was, for saying how a failure got where it did.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Frame {
+    pub program: usize,
+    pub call: ProgramCounter,
+    pub pc: ProgramCounter,
+    pub repeats: Vec<(ProgramCounter, usize)>,
+}
+
+/*
+ * Everything a running piece can change. The stack holds the numbers
+ * instructions work on, the tape is memory that Load and Store reach by
+ * address (only the cells that were written are kept, the rest read as 0,
+ * so storing far out costs no more than storing at 0) and pc is
+ * the next event to play in the program numbered program. Both the stack and
+ * the tape are shared with anything called, frames only keep where to go
+ * back to. Clock is how far into the piece the machine is, in ticks. Limits
+ * go with the machine so anything that runs it keeps to the same ones.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Machine {
+    pub stack: Vec<i64>,
+    pub tape: BTreeMap<usize, i64>,
+    pub pc: ProgramCounter,
+    pub program: usize,
+    pub halted: bool,
+    // How many more times each counted repeat, by the pc of its end sign,
+    // still has to go back
+    pub repeats: Vec<(ProgramCounter, usize)>,
+    pub frames: Vec<Frame>,
+    pub clock: usize,
+    // The channel a "recv:" is stuck on until something gets sent
+    pub waiting: Option<String>,
+    pub limits: Limits,
+}
+
+/*
+ * What one step changed, enough to put the machine back the way it was
+ * before it. Everything small is just copied. The stack only keeps the few
+ * values on top that the step could have taken off along with how long it
+ * was, the tape only keeps the one cell a Store overwrote and the frames only
+ * keep the top one, since no step touches more than that.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delta {
+    pub pc: ProgramCounter,
+    pub program: usize,
+    pub clock: usize,
+    pub halted: bool,
+    pub waiting: Option<String>,
+    pub repeats: Vec<(ProgramCounter, usize)>,
+    pub frames: usize,
+    pub top_frame: Option<Frame>,
+    pub stack: usize,
+    pub top: Vec<i64>,
+    // The address a Store wrote to and what was there before, if anything
+    pub write: Option<(usize, Option<i64>)>,
+    pub previous: Option<Note>,
+}
+
+impl Machine {
+    pub fn pop(&mut self, measure: usize) -> Result<i64, RuntimeError> {
+        self.stack.pop().ok_or(RuntimeError::StackUnderflow(measure))
+    }
+
+    // Pops the top two values, returning them in the order they were pushed
+    fn pop_pair(&mut self, measure: usize) -> Result<(i64, i64), RuntimeError> {
+        let b = self.pop(measure)?;
+        let a = self.pop(measure)?;
+        Ok((a, b))
+    }
+
+    /*
+     * Fails if the stack holds more than the stack limit allows.
+     */
+    pub fn check_stack(&self, measure: usize) -> Result<(), RuntimeError> {
+        match self.limits.stack {
+            Some(limit) if self.stack.len() > limit => Err(RuntimeError::StackLimit(limit, measure)),
+            _ => Ok(()),
+        }
+    }
+
+    fn address(&mut self, measure: usize) -> Result<usize, RuntimeError> {
+        let address = self.pop(measure)?;
+        usize::try_from(address).map_err(|_| RuntimeError::InvalidAddress(address, measure))
+    }
+
+    /*
+     * Runs one instruction, reading and writing through streams. Measure is
+     * only used to say where things went wrong.
+     */
+    pub fn execute(&mut self, operation: Operation, measure: usize, streams: &mut Streams)
+        -> Result<Flow, RuntimeError> {
+        let io_error = |e: std::io::Error| RuntimeError::Io(e.to_string(), measure);
+        let instruction = operation.instruction;
+        match instruction {
+            Instruction::Nop => {},
+            Instruction::Push => self.stack.push(operation.operand),
+            Instruction::Pop => { self.pop(measure)?; },
+            Instruction::Dup => {
+                let a = self.pop(measure)?;
+                self.stack.extend([a, a]);
+            },
+            Instruction::Swap => {
+                let (a, b) = self.pop_pair(measure)?;
+                self.stack.extend([b, a]);
+            },
+            Instruction::Over => {
+                let (a, b) = self.pop_pair(measure)?;
+                self.stack.extend([a, b, a]);
+            },
+            Instruction::Rot => {
+                let (b, c) = self.pop_pair(measure)?;
+                let a = self.pop(measure)?;
+                self.stack.extend([b, c, a]);
+            },
+            Instruction::Add => {
+                let (a, b) = self.pop_pair(measure)?;
+                self.stack.push(a.wrapping_add(b));
+            },
+            Instruction::Sub => {
+                let (a, b) = self.pop_pair(measure)?;
+                self.stack.push(a.wrapping_sub(b));
+            },
+            Instruction::Mul => {
+                let (a, b) = self.pop_pair(measure)?;
+                self.stack.push(a.wrapping_mul(b));
+            },
+            Instruction::Div | Instruction::Mod => {
+                let (a, b) = self.pop_pair(measure)?;
+                if b == 0 {
+                    return Err(RuntimeError::DivisionByZero(measure));
+                }
+                self.stack.push(if instruction == Instruction::Div {
+                    a.wrapping_div(b)
+                } else {
+                    a.wrapping_rem(b)
+                });
+            },
+            Instruction::Eq | Instruction::Lt | Instruction::Gt => {
+                let (a, b) = self.pop_pair(measure)?;
+                let result = match instruction {
+                    Instruction::Eq => a == b,
+                    Instruction::Lt => a < b,
+                    _ => a > b,
+                };
+                self.stack.push(result as i64);
+            },
+            Instruction::Not => {
+                let a = self.pop(measure)?;
+                self.stack.push((a == 0) as i64);
+            },
+            Instruction::Load => {
+                let address = self.address(measure)?;
+                self.stack.push(self.tape.get(&address).copied().unwrap_or(0));
+            },
+            Instruction::Store => {
+                let address = self.address(measure)?;
+                let value = self.pop(measure)?;
+                if let Some(limit) = self.limits.tape.filter(|limit| address >= *limit) {
+                    return Err(RuntimeError::TapeLimit(limit, measure));
+                }
+                self.tape.insert(address, value);
+            },
+            Instruction::ReadByte => {
+                let byte = streams.read_byte().map_err(io_error)?;
+                self.stack.push(byte.map_or(-1, i64::from));
+            },
+            Instruction::ReadNumber => {
+                let number = match streams.read_word().map_err(io_error)? {
+                    Some(word) => word.parse::<i64>()
+                        .map_err(|_| RuntimeError::InvalidNumber(word, measure))?,
+                    None => 0,
+                };
+                self.stack.push(number);
+            },
+            Instruction::ReadLine => {
+                let line = streams.read_line().map_err(io_error)?.unwrap_or_default();
+                let chars: Vec<i64> = line.chars().map(|c| c as i64).collect();
+                self.stack.extend(chars.iter().rev());
+                self.stack.push(chars.len() as i64);
+            },
+            Instruction::WriteChar => {
+                let value = self.pop(measure)?;
+                let c = u32::try_from(value).ok()
+                    .and_then(char::from_u32)
+                    .ok_or(RuntimeError::InvalidCharacter(value, measure))?;
+                write!(streams.output, "{}", c).and_then(|_| streams.output.flush())
+                    .map_err(io_error)?;
+            },
+            Instruction::WriteNumber => {
+                let value = self.pop(measure)?;
+                write!(streams.output, "{}", value).and_then(|_| streams.output.flush())
+                    .map_err(io_error)?;
+            },
+            Instruction::SkipBar => {
+                if self.pop(measure)? == 0 {
+                    return Ok(Flow::SkipBar);
+                }
+            },
+            Instruction::Halt => self.halted = true,
+        }
+
+        self.check_stack(measure)?;
+        Ok(Flow::Next)
+    }
+}
+
+/*
+ * Plays a program one event at a time, running every note and chord through
+ * the decoder. Repeat signs are the loops: an end repeat with a count over it
+ * goes back that many times in total, one without a count pops a value and
+ * goes back as long as it isn't 0.
+ *
+ * "call:name" plays the passage marked "label:name" in the same program, or
+ * the instrument called name from the top, and comes back when it ends.
+ * "call:instrument.label" reaches a label in another instrument. A passage
+ * ends at the next label or the end of its file, which is also where the
+ * program that was loaded stops.
+ *
+ * "send:name" pops a value and sends it down the channel called name,
+ * "recv:name" waits for a value on it and pushes it. Receiving moves the
+ * clock forward to the time the value was sent if that is later.
+ *
+ * "host:name" calls the Rust function registered as name, see register.
+ */
+#[derive(Debug)]
+pub struct Interpreter {
+    pub decoder: Decoder,
+    pub machine: Machine,
+    // Index 0 is the program that was loaded, the rest are instruments that
+    // can be called by name
+    pub programs: Vec<(String, Program)>,
+    pub streams: Streams,
+    pub channels: Channels,
+    // The file the loaded program came from, for saying where errors are.
+    // Instruments are in their name plus .inst
+    pub file: String,
+    // Events played since the piece was loaded and when the first of them
+    // was, for the limits
+    pub events: u64,
+    started: Option<Instant>,
+    // The program and event being played, or last played
+    playing: (usize, ProgramCounter),
+    // Instruments that play with a table other than the decoder's, by name
+    pub tables: BTreeMap<String, InstructionTable>,
+    // The most recent steps, oldest first, for undo. At most history_limit
+    // are kept and none at all when it is 0
+    pub history: VecDeque<Delta>,
+    pub history_limit: usize,
+    // Where a record of every event played goes, if anywhere
+    pub tracer: Option<Tracer>,
+    pub host: HostFunctions,
+    // An async host call that hasn't given back its results yet
+    pending: Option<PendingCall>,
+    recording: Option<Delta>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new(InstructionTable::default(), OpcodeMode::default())
+    }
+}
+
+impl Interpreter {
+    pub fn new(table: InstructionTable, mode: OpcodeMode) -> Self {
+        Interpreter {
+            decoder: Decoder::new(table, mode),
+            machine: Machine::default(),
+            programs: vec![(String::new(), Program::default())],
+            streams: Streams::default(),
+            channels: Channels::default(),
+            file: String::new(),
+            events: 0,
+            started: None,
+            playing: (0, ProgramCounter::default()),
+            tables: BTreeMap::new(),
+            history: VecDeque::new(),
+            history_limit: 0,
+            tracer: None,
+            host: HostFunctions::default(),
+            pending: None,
+            recording: None,
+        }
+    }
+
+    pub fn with_streams(mut self, streams: Streams) -> Self {
+        self.streams = streams;
+        self
+    }
+
+    /*
+     * Keeps the last limit steps so they can be undone.
+     */
+    pub fn with_history(mut self, limit: usize) -> Self {
+        self.history_limit = limit;
+        self
+    }
+
+    pub fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.machine.limits = limits;
+        self
+    }
+
+    pub fn with_file(mut self, file: &str) -> Self {
+        self.file = file.to_string();
+        self
+    }
+
+    /*
+     * Lets the piece call function with "host:name". The arguments are
+     * popped off the stack, the last one from the top, and whatever it
+     * returns is pushed back (see FromStack and IntoStack for how). An Err
+     * it returns fails the piece in the measure that called it.
+     *
+     *     interpreter.register("add", |a: i64, b: i64| a + b);
+     */
+    pub fn register<Args, F>(&mut self, name: &str, function: F)
+    where F: HostFn<Args>, F::Output: IntoStack {
+        self.host.register(name, function);
+    }
+
+    /*
+     * The same for a function that returns a future. Step and run wait for
+     * it on this thread, step_async and run_async await it instead.
+     */
+    pub fn register_async<Args, F, Fut>(&mut self, name: &str, function: F)
+    where F: HostFn<Args, Output = Fut>, Fut: Future + 'static, Fut::Output: IntoStack {
+        self.host.register_async(name, function);
+    }
+
+    /*
+     * Swaps in a new piece and moves back to its first event. The stack and
+     * tape are left as they are.
+     */
+    pub fn load(&mut self, lines: &[Line]) {
+        self.programs[0].1 = Program::from_lines(lines);
+        self.machine.pc = self.programs[0].1.start();
+        self.machine.program = 0;
+        self.machine.repeats.clear();
+        self.machine.frames.clear();
+        self.history.clear();
+        self.events = 0;
+        self.started = None;
+        self.pending = None;
+    }
+
+    /*
+     * Makes a piece callable with "call:name".
+     */
+    pub fn add_instrument(&mut self, name: &str, lines: &[Line]) {
+        let program = Program::from_lines(lines);
+        match self.programs.iter_mut().skip(1).find(|(n, _)| n == name) {
+            Some(existing) => existing.1 = program,
+            None => self.programs.push((name.to_string(), program)),
+        }
+    }
+
+    /*
+     * The program the program counter points into.
+     */
+    pub fn program(&self) -> &Program {
+        &self.programs[self.machine.program].1
+    }
+
+    pub fn finished(&self) -> bool {
+        self.machine.halted || (self.machine.frames.is_empty()
+            && self.program().event(self.machine.pc).is_none())
+    }
+
+    /*
+     * True while a "recv:" has nothing to receive, stepping won't get
+     * anywhere until another instrument sends something.
+     */
+    pub fn blocked(&self) -> bool {
+        self.machine.waiting.as_ref().is_some_and(|channel| !self.channels.has_message(channel))
+    }
+
+    fn call(&mut self, target: &str, measure: usize) -> Result<(), RuntimeError> {
+        let limit = self.machine.limits.calls;
+        if self.machine.frames.len() >= limit {
+            return Err(RuntimeError::StackOverflow(limit, measure));
+        }
+        let (program, pc) = find_call(&self.programs, self.machine.program, target)
+            .ok_or(RuntimeError::UnknownCall(target.to_string(), measure))?;
+
+        let frame = Frame {
+            program: self.machine.program,
+            call: self.playing.1,
+            pc: self.machine.pc,
+            repeats: std::mem::take(&mut self.machine.repeats),
+        };
+        self.machine.frames.push(frame);
+        self.machine.program = program;
+        self.machine.pc = pc;
+        Ok(())
+    }
+
+    /*
+     * Ends the passage being played, going back to whatever called it. With
+     * nothing to go back to the piece is over.
+     */
+    fn end_passage(&mut self) {
+        match self.machine.frames.pop() {
+            Some(frame) => {
+                self.machine.program = frame.program;
+                self.machine.pc = frame.pc;
+                self.machine.repeats = frame.repeats;
+            },
+            None => {
+                self.machine.pc = ProgramCounter { measure: self.program().measures.len(), event: 0 };
+            },
+        }
+    }
+
+    /*
+     * Where an event of a program is on the score.
+     */
+    fn location(&self, program: usize, pc: ProgramCounter) -> SourceLocation {
+        let (name, program_lines) = &self.programs[program];
+        let bar = program_lines.measures.get(pc.measure);
+        SourceLocation {
+            file: match program {
+                0 => self.file.clone(),
+                _ => format!("{}.inst", name),
+            },
+            measure: bar.map_or(0, |bar| bar.measure_number),
+            column: bar.and_then(|bar| bar.events.get(pc.event)).map_or(0, Event::column),
+        }
+    }
+
+    /*
+     * Puts an error from the last step together with the event it happened
+     * at and the calls that led there.
+     */
+    pub fn locate(&self, error: RuntimeError) -> RuntimeFailure {
+        if let RuntimeError::Deadlock(_) = error {
+            return error.into();
+        }
+        let (program, pc) = self.playing;
+        RuntimeFailure {
+            error,
+            location: Some(self.location(program, pc)),
+            calls: self.machine.frames.iter()
+                .rev()
+                .map(|frame| self.location(frame.program, frame.call))
+                .collect(),
+        }
+    }
+
+    fn delta(&self) -> Delta {
+        let machine = &self.machine;
+        Delta {
+            pc: machine.pc,
+            program: machine.program,
+            clock: machine.clock,
+            halted: machine.halted,
+            waiting: machine.waiting.clone(),
+            repeats: machine.repeats.clone(),
+            frames: machine.frames.len(),
+            top_frame: machine.frames.last().cloned(),
+            stack: machine.stack.len(),
+            top: machine.stack[machine.stack.len().saturating_sub(MAX_POPS)..].to_vec(),
+            write: None,
+            previous: self.decoder.previous.clone(),
+        }
+    }
+
+    /*
+     * Plays the event at the program counter. Returns false once there is
+     * nothing left to play. With a history limit set, what the step changed
+     * is remembered, even if it failed part of the way through.
+     */
+    pub fn step(&mut self) -> Result<bool, RuntimeError> {
+        if let Some(PendingCall { name, measure, future }) = self.pending.take() {
+            self.finish_host(&name, block_on(future), measure)?;
+        }
+        if self.finished() {
+            return Ok(false);
+        }
+
+        if self.history_limit > 0 {
+            self.recording = Some(self.delta());
+        }
+        let result = self.advance();
+        if let Some(delta) = self.recording.take() {
+            if self.history.len() >= self.history_limit {
+                self.history.pop_front();
+            }
+            self.history.push_back(delta);
+        }
+        result
+    }
+
+    /*
+     * Step, awaiting an async host call on whatever is running this instead
+     * of waiting for it on the thread.
+     */
+    pub async fn step_async(&mut self) -> Result<bool, RuntimeError> {
+        if let Some(PendingCall { name, measure, future }) = self.pending.take() {
+            let result = future.await;
+            self.finish_host(&name, result, measure)?;
+        }
+        self.step()
+    }
+
+    /*
+     * Pushes what a host call gave back.
+     */
+    fn finish_host(&mut self, name: &str, result: HostResult, measure: usize)
+        -> Result<(), RuntimeError> {
+        let values = result.map_err(|message| RuntimeError::Host(name.to_string(), message,
+            measure))?;
+        self.machine.stack.extend(values);
+        self.machine.check_stack(measure)
+    }
+
+    /*
+     * Puts the machine back the way it was before the last step it
+     * remembers. Input that was read stays read and output stays written.
+     * Returns false if there was nothing to undo.
+     */
+    pub fn undo(&mut self) -> bool {
+        let Some(delta) = self.history.pop_back() else { return false };
+        let machine = &mut self.machine;
+        machine.pc = delta.pc;
+        machine.program = delta.program;
+        machine.clock = delta.clock;
+        machine.halted = delta.halted;
+        machine.waiting = delta.waiting;
+        machine.repeats = delta.repeats;
+
+        machine.frames.truncate(delta.frames.saturating_sub(1));
+        machine.frames.extend(delta.top_frame);
+        machine.frames.truncate(delta.frames);
+
+        machine.stack.truncate(delta.stack - delta.top.len());
+        machine.stack.extend(delta.top);
+
+        match delta.write {
+            Some((address, Some(value))) => {
+                machine.tape.insert(address, value);
+            },
+            Some((address, None)) => {
+                machine.tape.remove(&address);
+            },
+            None => {},
+        }
+
+        self.decoder.previous = delta.previous;
+        true
+    }
+
+    fn advance(&mut self) -> Result<bool, RuntimeError> {
+        let pc = self.machine.pc;
+        let Some(event) = self.program().event(pc).cloned() else {
+            self.end_passage();
+            return Ok(true);
+        };
+        let measure = self.program().measures[pc.measure].measure_number;
+        self.playing = (self.machine.program, pc);
+        let started = *self.started.get_or_insert_with(Instant::now);
+        self.events += 1;
+        self.machine.pc = self.program().next(pc);
+        let before = self.tracer.as_ref().map(|_| (self.machine.stack.clone(), self.machine.clock));
+        let mut decoded = None;
+
+        match &event {
+            Event::Barline(Barline { kind: BarlineKind::RepeatEnd { times }, .. }) => {
+                let again = match times {
+                    Some(times) => {
+                        let idx = self.machine.repeats.iter().position(|(at, _)| *at == pc);
+                        let left = match idx {
+                            Some(idx) => self.machine.repeats.remove(idx).1,
+                            None => times.saturating_sub(1),
+                        };
+                        if left > 0 {
+                            self.machine.repeats.push((pc, left - 1));
+                        }
+                        left > 0
+                    },
+                    None => self.machine.pop(measure)? != 0,
+                };
+
+                if again {
+                    self.machine.pc = self.program().repeat_start(pc);
+                }
+            },
+            Event::Direction(direction) => {
+                if let Some(target) = direction.marker("call") {
+                    self.call(target, measure)?;
+                } else if direction.marker("label").is_some() {
+                    self.end_passage();
+                } else if let Some(channel) = direction.marker("send") {
+                    let value = self.machine.pop(measure)?;
+                    self.channels.send(channel, self.machine.clock, value);
+                } else if let Some(name) = direction.marker("host") {
+                    // A host function can take any number of values, so the
+                    // whole stack is kept to undo it
+                    if let Some(delta) = self.recording.as_mut() {
+                        delta.top = self.machine.stack.clone();
+                    }
+                    match self.host.call(name, &mut self.machine, measure)? {
+                        HostCall::Done(result) => self.finish_host(name, result, measure)?,
+                        HostCall::Pending(future) => {
+                            self.pending = Some(PendingCall { name: name.to_string(), measure,
+                                future });
+                        },
+                    }
+                } else if let Some(channel) = direction.marker("recv") {
+                    match self.channels.receive(channel) {
+                        Some((sent, value)) => {
+                            self.machine.stack.push(value);
+                            self.machine.check_stack(measure)?;
+                            self.machine.clock = self.machine.clock.max(sent);
+                            self.machine.waiting = None;
+                        },
+                        None => {
+                            self.machine.pc = pc;
+                            self.machine.waiting = Some(channel.to_string());
+                        },
+                    }
+                }
+            },
+            event => {
+                let duration = event.duration().map_or(0, |duration| duration.ticks());
+                let table = match self.machine.program {
+                    0 => None,
+                    program => self.tables.get(&self.programs[program].0),
+                };
+                let operation = match table {
+                    Some(table) => self.decoder.decode_with(table, event, measure)?,
+                    None => self.decoder.decode(event, measure)?,
+                };
+                decoded = operation;
+                if let (Some(delta), Some(Instruction::Store)) = (self.recording.as_mut(),
+                    operation.map(|o| o.instruction)) {
+                    delta.write = self.machine.stack.last()
+                        .and_then(|address| usize::try_from(*address).ok())
+                        .map(|address| (address, self.machine.tape.get(&address).copied()));
+                }
+
+                let flow = match operation {
+                    Some(operation) => self.machine.execute(operation, measure, &mut self.streams)?,
+                    None => Flow::Next,
+                };
+                self.machine.clock += duration;
+
+                if flow == Flow::SkipBar {
+                    self.machine.pc = self.program().next_measure(pc);
+                }
+            },
+        }
+
+        if let (Some(tracer), Some((stack_before, time))) = (&self.tracer, before) {
+            if self.machine.waiting.is_none() {
+                // Under the program the event is in, a call has already
+                // moved on to the one it calls
+                let file = match self.playing.0 {
+                    0 => tracer.file.clone(),
+                    program => format!("{}.inst", self.programs[program].0),
+                };
+                let record = TraceRecord {
+                    stack_before,
+                    stack_after: self.machine.stack.clone(),
+                    time,
+                    ..TraceRecord::new(&file, measure, &event, decoded)
+                };
+                tracer.record(&record).map_err(|e| RuntimeError::Io(e.to_string(), measure))?;
+            }
+        }
+
+        self.machine.limits.check_progress(self.events, started, self.machine.clock, measure)?;
+        Ok(true)
+    }
+
+    pub fn run(&mut self, lines: &[Line]) -> Result<(), RuntimeFailure> {
+        self.load(lines);
+        while self.step().map_err(|error| self.locate(error))? {
+            self.check_deadlock()?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Run, awaiting async host calls instead of waiting for them on the
+     * thread, for pieces played inside an async program.
+     */
+    pub async fn run_async(&mut self, lines: &[Line]) -> Result<(), RuntimeFailure> {
+        self.load(lines);
+        while self.step_async().await.map_err(|error| self.locate(error))? {
+            self.check_deadlock()?;
+        }
+        Ok(())
+    }
+
+    // Playing on its own, a "recv:" with nothing to receive never will
+    fn check_deadlock(&self) -> Result<(), RuntimeFailure> {
+        match self.blocked() {
+            true => Err(RuntimeError::Deadlock(self.machine.waiting.clone().unwrap_or_default())
+                .into()),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parser::{parse_file, parse_str};
+    use crate::repl::repl::translate;
+    use crate::streams::streams::SharedOutput;
+
+    fn run(filepath: &str) -> Result<Machine, RuntimeError> {
+        let lines = parse_file(filepath.to_string()).unwrap();
+        let mut interpreter = Interpreter::default();
+        interpreter.run(&lines).map_err(|failure| failure.error)?;
+        Ok(interpreter.machine)
+    }
+
+    #[test]
+    fn test_interpreter_arithmetic() {
+        // Quarter notes push 4 and the whole note at the end adds the two
+        // copies of 8
+        let machine = run("testing_resources/arithmetic.inst").unwrap();
+        assert_eq!(vec![16], machine.stack);
+        assert!(!machine.halted);
+    }
+
+    #[test]
+    fn test_interpreter_skip_and_halt() {
+        // The first measure skips its last two notes and the chord in the
+        // third measure stops the piece before the final note
+        let machine = run("testing_resources/skip_and_halt.inst").unwrap();
+        assert_eq!(vec![4, 4], machine.stack);
+        assert!(machine.halted);
+    }
+
+    #[test]
+    fn test_interpreter_empty_measures() {
+        // A "||" leaves an empty measure behind, playing on and skipping a
+        // bar both go past it
+        let play = |shorthand: &str| {
+            let lines = parse_str(&translate(shorthand).unwrap()).unwrap();
+            let mut interpreter = Interpreter::default();
+            interpreter.run(&lines).unwrap();
+            interpreter.machine.stack
+        };
+        assert_eq!(vec![4, 4], play("C4q || C4q"));
+        assert_eq!(vec![4], play("C4q C4q E4q B5q C4q || C4q"));
+    }
+
+    #[test]
+    fn test_interpreter_far_addresses() {
+        // Only cells that were written are kept, so the last address there is
+        // costs one cell like any other
+        let mut machine = Machine { stack: vec![9, i64::MAX], ..Machine::default() };
+        let mut streams = Streams::new(std::io::empty(), std::io::sink());
+        let store = Operation { instruction: Instruction::Store, operand: 1 };
+        let load = Operation { instruction: Instruction::Load, operand: 1 };
+        machine.execute(store, 1, &mut streams).unwrap();
+        assert_eq!(BTreeMap::from([(i64::MAX as usize, 9)]), machine.tape);
+
+        machine.stack = vec![i64::MAX];
+        machine.execute(load, 1, &mut streams).unwrap();
+        machine.stack.push(i64::MAX - 1);
+        machine.execute(load, 1, &mut streams).unwrap();
+        assert_eq!(vec![9, 0], machine.stack);
+    }
+
+    #[test]
+    fn test_interpreter_errors() {
+        assert_eq!(Err(RuntimeError::StackUnderflow(1)),
+            run("testing_resources/cmaj_scale_quarternotes.inst"));
+
+        let mut machine = Machine { stack: vec![1, 0], ..Machine::default() };
+        let modulo = Operation { instruction: Instruction::Mod, operand: 1 };
+        let mut streams = Streams::new(std::io::empty(), std::io::sink());
+        assert_eq!(Err(RuntimeError::DivisionByZero(3)), machine.execute(modulo, 3, &mut streams));
+
+        let mut machine = Machine { stack: vec![-1], ..Machine::default() };
+        let load = Operation { instruction: Instruction::Load, operand: 1 };
+        assert_eq!(Err(RuntimeError::InvalidAddress(-1, 7)), machine.execute(load, 7, &mut streams));
+    }
+
+    #[test]
+    fn test_interpreter_tape_and_repeats() {
+        // Stores a quarter note's 4 at address 1 and loads it back, adds 1
+        // three times with a counted repeat, then counts a copy of the 7 down
+        // to 0 with a repeat that loops while the top of the stack isn't 0
+        let lines = parse_file("testing_resources/tape_and_repeats.inst".to_string()).unwrap();
+        let mut interpreter = Interpreter::default();
+        interpreter.load(&lines);
+
+        interpreter.step().unwrap();
+        assert_eq!(vec![4], interpreter.machine.stack);
+        assert_eq!(ProgramCounter { measure: 0, event: 1 }, interpreter.machine.pc);
+
+        while interpreter.step().unwrap() {}
+        assert_eq!(vec![7, 0], interpreter.machine.stack);
+        assert_eq!(BTreeMap::from([(1, 4)]), interpreter.machine.tape);
+        assert!(interpreter.finished());
+        assert!(interpreter.machine.repeats.is_empty());
+    }
+
+    #[test]
+    fn test_interpreter_undo() {
+        let lines = parse_file("testing_resources/tape_and_repeats.inst".to_string()).unwrap();
+        let mut interpreter = Interpreter::default().with_history(1000);
+        interpreter.load(&lines);
+
+        let mut machines = vec![interpreter.machine.clone()];
+        while interpreter.step().unwrap() {
+            machines.push(interpreter.machine.clone());
+        }
+        machines.pop();
+
+        // Every step undoes back to exactly the machine before it
+        while let Some(machine) = machines.pop() {
+            assert!(interpreter.undo());
+            assert_eq!(machine, interpreter.machine);
+        }
+        assert!(!interpreter.undo());
+
+        // Only the last few steps are kept with a small limit
+        let mut interpreter = Interpreter::default().with_history(3);
+        interpreter.run(&lines).unwrap();
+        assert_eq!(3, interpreter.history.len());
+    }
+
+    fn run_with_input(filepath: &str, input: &'static str) -> Result<String, RuntimeError> {
+        let lines = parse_file(filepath.to_string()).unwrap();
+        let output = SharedOutput::new();
+        let mut interpreter = Interpreter::default()
+            .with_streams(Streams::new(input.as_bytes(), output.clone()));
+        interpreter.run(&lines).map_err(|failure| failure.error)?;
+        Ok(output.text())
+    }
+
+    #[test]
+    fn test_interpreter_input_and_output() {
+        assert_eq!("Hi", run_with_input("testing_resources/hello.inst", "").unwrap());
+
+        // Doubles a number, then echoes the next two bytes as numbers
+        assert_eq!("426566", run_with_input("testing_resources/echo.inst", "21\nAB").unwrap());
+        assert_eq!(Err(RuntimeError::InvalidNumber("two".to_string(), 1)),
+            run_with_input("testing_resources/echo.inst", "two"));
+
+        let mut machine = Machine::default();
+        let mut streams = Streams::new("ok\n".as_bytes(), std::io::sink());
+        let read_line = Operation { instruction: Instruction::ReadLine, operand: 0 };
+        machine.execute(read_line, 1, &mut streams).unwrap();
+        machine.execute(read_line, 1, &mut streams).unwrap();
+        assert_eq!(vec!['k' as i64, 'o' as i64, 2, 0], machine.stack);
+    }
+
+    #[test]
+    fn test_interpreter_call_depth() {
+        let lines = parse_file("testing_resources/recursion.inst".to_string()).unwrap();
+        let mut interpreter = Interpreter::default()
+            .with_limits(Limits { calls: 10, ..Limits::default() });
+        assert_eq!(Err(RuntimeError::StackOverflow(10, 2)),
+            interpreter.run(&lines).map_err(|failure| failure.error));
+        assert_eq!(10, interpreter.machine.frames.len());
+
+        let unknown = Event::Direction(Direction { text: "call:nowhere".to_string(), column: 0 });
+        interpreter.load(&lines);
+        interpreter.programs[0].1.measures[0].events[0] = unknown;
+        let mut result = Ok(true);
+        while result == Ok(true) {
+            result = interpreter.step();
+        }
+        assert_eq!(Err(RuntimeError::UnknownCall("nowhere".to_string(), 1)), result);
+    }
+
+    #[test]
+    fn test_interpreter_failure_location() {
+        // The main piece calls scale straight away, which runs out of
+        // values on its fourth note
+        let scale = parse_file("testing_resources/cmaj_scale_quarternotes.inst".to_string())
+            .unwrap();
+        let lines = parse_file("testing_resources/recursion.inst".to_string()).unwrap();
+        let mut interpreter = Interpreter::default().with_file("main.inst");
+        interpreter.add_instrument("scale", &scale);
+        interpreter.load(&lines);
+        let call = Event::Direction(Direction { text: "call:scale".to_string(), column: 2 });
+        interpreter.programs[0].1.measures[0].events[0] = call;
+
+        let mut result = Ok(true);
+        while result == Ok(true) {
+            result = interpreter.step();
+        }
+        let failure = interpreter.locate(result.unwrap_err());
+        assert_eq!(RuntimeFailure {
+            error: RuntimeError::StackUnderflow(1),
+            location: Some(SourceLocation {
+                file: "scale.inst".to_string(),
+                measure: 1,
+                column: 4,
+            }),
+            calls: vec![SourceLocation { file: "main.inst".to_string(), measure: 1, column: 2 }],
+        }, failure);
+        assert_eq!("Stack underflow in measure 1\n  at scale.inst measure 1 column 4\n  \
+            called from main.inst measure 1 column 2", failure.to_string());
+        assert_eq!(3, failure.exit_code());
+
+        let file = "testing_resources/recursion.inst";
+        let failure = Interpreter::default().with_file(file)
+            .run(&parse_file(file.to_string()).unwrap()).unwrap_err();
+        assert_eq!(256, failure.calls.len());
+        assert_eq!("Call stack overflow, more than 256 calls deep in measure 2\n  \
+            at testing_resources/recursion.inst measure 2 column 5\n  \
+            called from testing_resources/recursion.inst measure 2 column 5 (255 times)\n  \
+            called from testing_resources/recursion.inst measure 1 column 1", failure.to_string());
+        assert_eq!(8, RuntimeFailure::from(RuntimeError::Deadlock("x".to_string())).exit_code());
+    }
+
+    #[test]
+    fn test_interpreter_limits() {
+        // A repeat with nothing to stop it, every limit on time gets it
+        let lines = parse_file("testing_resources/check/endless.inst".to_string()).unwrap();
+        let limited = |limits: Limits| Interpreter::default().with_limits(limits).run(&lines)
+            .map_err(|failure| failure.error);
+        assert_eq!(Err(RuntimeError::EventLimit(100, 1)),
+            limited(Limits { events: Some(100), ..Limits::default() }));
+        assert_eq!(Err(RuntimeError::TickLimit(1000, 1)),
+            limited(Limits { ticks: Some(1000), ..Limits::default() }));
+        assert_eq!(Err(RuntimeError::TimeLimit(20, 1)),
+            limited(Limits { millis: Some(20), ..Limits::default() }));
+
+        let limits = Limits { stack: Some(1), tape: Some(8), ..Limits::default() };
+        let mut machine = Machine { limits, ..Machine::default() };
+        let mut streams = Streams::new(std::io::empty(), std::io::sink());
+        let push = Operation { instruction: Instruction::Push, operand: 1 };
+        machine.execute(push, 1, &mut streams).unwrap();
+        assert_eq!(Err(RuntimeError::StackLimit(1, 2)), machine.execute(push, 2, &mut streams));
+
+        // Nothing is stored at an address past the limit
+        let mut machine = Machine { stack: vec![5, 1 << 40], limits, ..Machine::default() };
+        let store = Operation { instruction: Instruction::Store, operand: 1 };
+        assert_eq!(Err(RuntimeError::TapeLimit(8, 3)), machine.execute(store, 3, &mut streams));
+        assert!(machine.tape.is_empty());
+    }
+}
+}
@@ -0,0 +1,23 @@
+#![allow(clippy::module_inception)]
+
+pub mod assemble;
+pub mod bytecode;
+pub mod check;
+pub mod data_types;
+pub mod debugger;
+pub mod disasm;
+pub mod engrave;
+pub mod ensemble;
+pub mod host;
+pub mod instructions;
+pub mod interpreter;
+pub mod optimize;
+pub mod parser;
+pub mod project;
+pub mod repl;
+pub mod staff;
+pub mod streams;
+pub mod trace;
+pub mod transpile;
+pub mod visit;
+pub mod vm;
@@ -1,10 +1,330 @@
-mod data_types;
-mod parser;
-use crate::parser::parser::*;
+use std::path::Path;
+use instrument_lang::assemble::assemble::Assembler;
+use instrument_lang::bytecode::bytecode::{compile_file, Module, EXTENSION};
+use instrument_lang::data_types::dt::{Beats, ProjectError, StaffType, TimeSignature};
+use instrument_lang::check::check::{check, CheckWarning};
+use instrument_lang::parser::parser::*;
+use instrument_lang::debugger::debugger::Debugger;
+use instrument_lang::disasm::disasm::{disassemble, listing};
+use instrument_lang::instructions::instructions::{InstructionTable, OpcodeMode};
+use instrument_lang::interpreter::interpreter::{Interpreter, Limits};
+use instrument_lang::optimize::optimize::{optimize, Passes};
+use instrument_lang::project::project::Project;
+use instrument_lang::repl::repl::Repl;
+use instrument_lang::trace::trace::Tracer;
+use instrument_lang::transpile::transpile::transpile;
+use instrument_lang::vm::vm::Vm;
 
+/*
+ * The number given to a --max-name= flag, if there is one.
+ */
+fn limit_flag<T: std::str::FromStr>(args: &[String], name: &str) -> Option<T> {
+    let flag = format!("--max-{}=", name);
+    args.iter().find_map(|arg| arg.strip_prefix(flag.as_str())).map(|value| {
+        value.parse().unwrap_or_else(|_| {
+            eprintln!("--max-{} takes a number", name);
+            std::process::exit(2);
+        })
+    })
+}
+
+/*
+ * Limits with whatever --max- flags were given put over them.
+ */
+fn limits_from(args: &[String], limits: Limits) -> Limits {
+    Limits {
+        events: limit_flag(args, "events").or(limits.events),
+        stack: limit_flag(args, "stack").or(limits.stack),
+        tape: limit_flag(args, "tape").or(limits.tape),
+        calls: limit_flag(args, "calls").unwrap_or(limits.calls),
+        millis: limit_flag(args, "millis").or(limits.millis),
+        ticks: limit_flag(args, "ticks").or(limits.ticks),
+    }
+}
+
+const USAGE: &str = "\
+usage: instrument-lang --repl
+       instrument-lang --assemble [--clef=treble|bass] [--time=4/4] <file.asm>
+       instrument-lang --disasm <file.inst | project folder>
+       instrument-lang --check <file.inst | project folder>
+       instrument-lang --debug [--history=steps] [limits] <file.inst>
+       instrument-lang (--compile=out.instc | --rust=out.rs) [--no-fold] [--no-merge]
+                       [--no-dead-code] <file.inst | file.instc | project folder>
+       instrument-lang [--trace=out.jsonl] [limits] <file.inst | file.instc | project folder>
+limits: [--max-events=n] [--max-stack=n] [--max-tape=n] [--max-calls=n] [--max-millis=n]
+        [--max-ticks=n]";
+
+/*
+ * Prints the listing for a file or every file in a project.
+ */
+fn disasm_mode(path: &str) {
+    let listed = if Path::new(path).is_dir() {
+        Project::load(path).and_then(|project| project.disassemble()).map_err(|e| e.to_string())
+    } else {
+        parse_file(path.to_string()).map(|lines| disassemble(path, &lines,
+            &InstructionTable::default(), OpcodeMode::Absolute)).map_err(|e| e.to_string())
+    };
+    match listed {
+        Ok(lines) => print!("{}", listing(&lines)),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    }
+}
+
+/*
+ * Prints the score written for an assembly file.
+ */
+fn assemble_mode(args: &[String], path: &str) {
+    let clef = match args.iter().find_map(|arg| arg.strip_prefix("--clef=")) {
+        None | Some("treble") => StaffType::Treble,
+        Some("bass") => StaffType::Bass,
+        Some(_) => {
+            eprintln!("--clef is treble or bass");
+            std::process::exit(2);
+        },
+    };
+    let time_signature = args.iter()
+        .find_map(|arg| arg.strip_prefix("--time="))
+        .map(|time| {
+            let (beats, size) = time.split_once('/').unwrap_or((time, ""));
+            let beats = beats.parse::<usize>().ok().filter(|beats| *beats > 0);
+            match (beats, size.parse().ok().and_then(Beats::from_denominator)) {
+                (Some(beats), Some(beat_size)) => TimeSignature { beats, beat_size },
+                _ => {
+                    eprintln!("--time takes a time signature like 3/4");
+                    std::process::exit(2);
+                },
+            }
+        });
+    let mut assembler = Assembler { clef, ..Assembler::default() };
+    if let Some(time_signature) = time_signature {
+        assembler.time_signature = time_signature;
+    }
+    let score = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))
+        .and_then(|source| assembler.assemble(&source).map_err(|e| e.to_string()));
+    match score {
+        Ok(score) => print!("{}", score),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    }
+}
+
+/*
+ * Compiles a file, a compiled file or a project and saves it as bytecode
+ * or as Rust.
+ */
+fn compile_mode(args: &[String], path: &str, compile_to: Option<String>, rust_to: Option<String>) {
+    let passes = Passes {
+        fold: !args.iter().any(|arg| arg == "--no-fold"),
+        merge: !args.iter().any(|arg| arg == "--no-merge"),
+        dead_code: !args.iter().any(|arg| arg == "--no-dead-code"),
+    };
+    let compiled = if Path::new(path).is_dir() {
+        Project::load(path).and_then(|project| project.compile())
+            .map_err(|e| e.to_string())
+    } else if Path::new(path).extension().is_some_and(|ext| ext == EXTENSION) {
+        Module::load(path).map_err(|e| e.to_string())
+    } else {
+        parse_file(path.to_string()).map_err(|e| e.to_string())
+            .and_then(|lines| compile_file(path, &lines).map_err(|e| e.to_string()))
+    };
+    let saved = compiled.and_then(|mut module| {
+        optimize(&mut module, passes);
+        match (&compile_to, &rust_to) {
+            (Some(out), _) => module.save(out).map_err(|e| e.to_string()),
+            (_, Some(out)) => std::fs::write(out, transpile(&module))
+                .map_err(|e| format!("{}: {}", out, e)),
+            _ => Ok(()),
+        }
+    });
+    if let Err(e) = saved {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/*
+ * Prints what the checker found, exiting with 1 if it found anything.
+ */
+fn check_mode(path: &str) {
+    let warnings: Result<Vec<CheckWarning>, String> = if Path::new(path).is_dir() {
+        Project::load(path).and_then(|project| project.check()).map_err(|e| e.to_string())
+    } else {
+        parse_file(path.to_string()).map(|lines| check(path, &lines)).map_err(|e| e.to_string())
+    };
+    match warnings {
+        Ok(warnings) if warnings.is_empty() => return,
+        Ok(warnings) => {
+            for warning in warnings {
+                println!("{}", warning);
+            }
+        },
+        Err(e) => eprintln!("{}", e),
+    }
+    std::process::exit(1);
+}
+
+/*
+ * Steps through a single .inst file.
+ */
+fn debug_mode(args: &[String], path: &str) {
+    if Path::new(path).extension().is_some_and(|ext| ext == EXTENSION) {
+        eprintln!("A compiled file can only be run, use the .inst files to check or debug");
+        std::process::exit(2);
+    }
+    let history = args.iter()
+        .find_map(|arg| arg.strip_prefix("--history="))
+        .map(|limit| limit.parse::<usize>().unwrap_or_else(|_| {
+            eprintln!("--history takes a number of steps");
+            std::process::exit(2);
+        }));
+    let lines = match parse_file(path.to_string()) {
+        Ok(lines) => lines,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    };
+    let mut debugger = Debugger::new(Interpreter::default()
+        .with_limits(limits_from(args, Limits::default())));
+    if let Some(limit) = history {
+        debugger = debugger.with_history(limit);
+    }
+    debugger.load(path, &lines);
+    if let Err(e) = debugger.interact() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/*
+ * Plays a file, a compiled file or a project and gives back the exit code,
+ * 0 or the failure's own.
+ */
+fn run_mode(args: &[String], path: &str) -> i32 {
+    let tracer = match args.iter().find_map(|arg| arg.strip_prefix("--trace=")) {
+        Some(trace) => match std::fs::File::create(trace) {
+            Ok(file) => Some(Tracer::new(file, path)),
+            Err(e) => {
+                eprintln!("Can't write the trace to {}: {}", trace, e);
+                return 1;
+            },
+        },
+        None => None,
+    };
+
+    let played = if Path::new(path).extension().is_some_and(|ext| ext == EXTENSION) {
+        let module = match Module::load(path) {
+            Ok(module) => module,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            },
+        };
+        let mut vm = Vm::new(module).with_limits(limits_from(args, Limits::default()));
+        vm.tracer = tracer;
+        vm.run()
+    } else if Path::new(path).is_dir() {
+        let played = Project::load(path).and_then(|mut project| {
+            project.expression.limits = limits_from(args, project.expression.limits);
+            project.run_traced(tracer.as_ref())
+        });
+        match played {
+            Ok(_) => Ok(()),
+            Err(ProjectError::Runtime(failure)) => Err(failure),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            },
+        }
+    } else {
+        let lines = match parse_file(path.to_string()) {
+            Ok(lines) => lines,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            },
+        };
+        let mut interpreter = Interpreter::default()
+            .with_limits(limits_from(args, Limits::default()))
+            .with_file(path);
+        interpreter.tracer = tracer;
+        interpreter.run(&lines)
+    };
+
+    match played {
+        Ok(()) => 0,
+        Err(failure) => {
+            eprintln!("{}", failure);
+            failure.exit_code()
+        },
+    }
+}
 
 fn main() {
-    let lines = parse_file("testing_resources/cmaj_scale_quarternotes.inst".to_string()).unwrap();
-    println!("{:?}", lines);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--repl") {
+        if let Err(e) = Repl::default().interact() {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let flag = |name: &str| args.iter().any(|arg| arg == name);
+    let compile_to = args.iter().find_map(|arg| arg.strip_prefix("--compile=")).map(String::from);
+    let rust_to = args.iter().find_map(|arg| arg.strip_prefix("--rust=")).map(String::from);
+    let Some(path) = args.iter().find(|arg| !arg.starts_with("--")) else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+
+    if flag("--disasm") {
+        disasm_mode(path);
+    } else if flag("--assemble") {
+        assemble_mode(&args, path);
+    } else if compile_to.is_some() || rust_to.is_some() {
+        compile_mode(&args, path, compile_to, rust_to);
+    } else if flag("--debug") && Path::new(path).is_dir() {
+        eprintln!("The debugger works on a single .inst file");
+        std::process::exit(2);
+    } else if flag("--check") {
+        check_mode(path);
+    } else if flag("--debug") {
+        debug_mode(&args, path);
+    } else {
+        std::process::exit(run_mode(&args, path));
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(flags: &[&str]) -> Vec<String> {
+        flags.iter().map(|flag| flag.to_string()).collect()
+    }
+
+    #[test]
+    fn test_run_exit_codes() {
+        // A failure exits with its own code whichever way the piece is played
+        let underflow = "testing_resources/annotated_cmaj_scale.inst";
+        assert_eq!(3, run_mode(&[], underflow));
+        assert_eq!(9, run_mode(&args(&["--max-events=10"]), "testing_resources/check/endless.inst"));
+        assert_eq!(9, run_mode(&args(&["--max-calls=0"]), "testing_resources/projects/calls"));
+        assert_eq!(1, run_mode(&[], "testing_resources/missing.inst"));
+
+        let dir = std::env::temp_dir().join(format!("instrument-lang-exit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let compiled = dir.join(format!("piece.{}", EXTENSION)).to_string_lossy().into_owned();
+        compile_file(underflow, &parse_file(underflow.to_string()).unwrap()).unwrap()
+            .save(&compiled).unwrap();
+        let played = run_mode(&[], &compiled);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(3, played);
+    }
+}
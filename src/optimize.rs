@@ -0,0 +1,477 @@
+pub mod optimize {
+use crate::bytecode::bytecode::{Module, Op, Origin};
+use crate::instructions::instructions::{Instruction, Operation};
+
+// The longest section the merge pass looks for
+const MAX_SECTION: usize = 32;
+
+/*
+ * Which passes run. All of them are on by default, turning one off is
+ * useful when a compiled piece does something odd and the question is which
+ * pass did it.
+ *
+ *   fold       pushes of literals followed by arithmetic, a comparison, Not
+ *              or Pop are worked out while compiling
+ *   merge      a run of ops written out several times in a row, the way a
+ *              repeat looks once it is expanded, goes back to being played
+ *              once with a repeat
+ *   dead_code  ops that nothing can reach, after a label's return, a Halt or
+ *              in a passage nothing calls, are removed
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Passes {
+    pub fold: bool,
+    pub merge: bool,
+    pub dead_code: bool,
+}
+
+impl Default for Passes {
+    fn default() -> Self {
+        Passes { fold: true, merge: true, dead_code: true }
+    }
+}
+
+impl Passes {
+    pub fn none() -> Self {
+        Passes { fold: false, merge: false, dead_code: false }
+    }
+}
+
+/*
+ * Runs the passes that are switched on, in the order above. An optimized
+ * module plays the same as the one it came from: the same output, stacks,
+ * tapes and clocks, and every op that is left runs at the same time it did
+ * before. Folded and merged ops are never ones something jumps into, and an
+ * error in a merged section is reported in the first copy of it.
+ */
+pub fn optimize(module: &mut Module, passes: Passes) {
+    for program in 0..module.programs.len() {
+        if passes.fold {
+            fold(module, program);
+        }
+        if passes.merge {
+            merge(module, program);
+        }
+    }
+    if passes.dead_code {
+        remove_dead_code(module);
+    }
+}
+
+/*
+ * Where an op can go next, leaving out calls into other programs.
+ */
+fn successors(module: &Module, program: usize, op: usize) -> Vec<usize> {
+    let compiled = &module.programs[program];
+    let skips = || vec![op + 1, compiled.skip_target(op)];
+    match &compiled.code[op] {
+        Op::Play { operation, .. } if operation.instruction == Instruction::Halt => vec![],
+        Op::Play { operation, .. } if operation.instruction == Instruction::SkipBar => skips(),
+        // Interval mode can play anything, so may skip as well
+        Op::Interval { .. } => skips(),
+        Op::Repeat { target, .. } | Op::RepeatWhile { target } => vec![op + 1, *target],
+        Op::Return => vec![],
+        _ => vec![op + 1],
+    }
+}
+
+/*
+ * The ops of a program that can be arrived at from anywhere other than the
+ * op before: the top of the program, the targets of jumps and calls, where a
+ * SkipBar lands and the op after a call that a return comes back to.
+ */
+fn entries(module: &Module, program: usize) -> Vec<bool> {
+    let length = module.programs[program].code.len();
+    let mut entry = vec![false; length];
+    entry[0] = true;
+    for op in 0..length {
+        for next in successors(module, program, op) {
+            if next != op + 1 && next < length {
+                entry[next] = true;
+            }
+        }
+    }
+    for (caller, compiled) in module.programs.iter().enumerate() {
+        for (op, code) in compiled.code.iter().enumerate() {
+            let Op::Call { program: callee, target } = code else { continue };
+            if *callee == program {
+                entry[*target] = true;
+            }
+            if caller == program {
+                entry[op + 1] = true;
+            }
+        }
+    }
+    entry
+}
+
+/*
+ * Drops the ops of a program that keep says to, pointing everything that
+ * went to a dropped op at the next op that is kept. The last op (the Return
+ * at the end) always has to be kept.
+ */
+fn remove_ops(module: &mut Module, program: usize, keep: &[bool]) {
+    let mut moved = Vec::with_capacity(keep.len());
+    let mut kept = 0;
+    for &k in keep {
+        moved.push(kept);
+        kept += k as usize;
+    }
+
+    let compiled = &mut module.programs[program];
+    let mut index = 0;
+    compiled.code.retain(|_| { index += 1; keep[index - 1] });
+    let mut index = 0;
+    compiled.origins.retain(|_| { index += 1; keep[index - 1] });
+    for measure in compiled.measures.iter_mut() {
+        measure.start = moved[measure.start];
+    }
+
+    for (other, compiled) in module.programs.iter_mut().enumerate() {
+        for op in compiled.code.iter_mut() {
+            match op {
+                Op::Repeat { target, .. } | Op::RepeatWhile { target } if other == program => {
+                    *target = moved[*target];
+                },
+                Op::Call { program: callee, target } if *callee == program => {
+                    *target = moved[*target];
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+/*
+ * The literal an op pushes, if it is a push.
+ */
+fn literal(op: &Op) -> Option<(i64, usize)> {
+    match op {
+        Op::Play { operation, ticks } if operation.instruction == Instruction::Push => {
+            Some((operation.operand, *ticks))
+        },
+        _ => None,
+    }
+}
+
+/*
+ * What an op does to one or two literals, None if it isn't something that
+ * can be worked out ahead of time (dividing by 0 has to fail when it plays).
+ */
+fn evaluate(op: &Op, values: &[i64]) -> Option<(Option<i64>, usize)> {
+    let Op::Play { operation, ticks } = op else { return None };
+    let result = match (operation.instruction, values) {
+        (Instruction::Not, [a]) => Some((*a == 0) as i64),
+        (Instruction::Pop, [_]) => None,
+        (Instruction::Add, [a, b]) => Some(a.wrapping_add(*b)),
+        (Instruction::Sub, [a, b]) => Some(a.wrapping_sub(*b)),
+        (Instruction::Mul, [a, b]) => Some(a.wrapping_mul(*b)),
+        (Instruction::Div, [a, b]) if *b != 0 => Some(a.wrapping_div(*b)),
+        (Instruction::Mod, [a, b]) if *b != 0 => Some(a.wrapping_rem(*b)),
+        (Instruction::Eq, [a, b]) => Some((a == b) as i64),
+        (Instruction::Lt, [a, b]) => Some((a < b) as i64),
+        (Instruction::Gt, [a, b]) => Some((a > b) as i64),
+        _ => return None,
+    };
+    Some((result, *ticks))
+}
+
+/*
+ * Folds one or two pushes and the op after them into a single op that takes
+ * as long as all of them did, over and over until nothing else folds. The
+ * folded op keeps the origin of the last one.
+ */
+fn fold(module: &mut Module, program: usize) {
+    loop {
+        let entry = entries(module, program);
+        let code = &module.programs[program].code;
+        let mut keep = vec![true; code.len()];
+        let mut folded = Vec::new();
+        let mut op = 0;
+        while op + 1 < code.len() {
+            let found = [2, 1].into_iter().find_map(|width| {
+                let last = op + width;
+                if last >= code.len() || entry[op + 1..=last].contains(&true) {
+                    return None;
+                }
+                let literals: Option<Vec<(i64, usize)>> = code[op..last].iter().map(literal).collect();
+                let literals = literals?;
+                let values: Vec<i64> = literals.iter().map(|(value, _)| *value).collect();
+                let (result, ticks) = evaluate(&code[last], &values)?;
+                let ticks = ticks + literals.iter().map(|(_, ticks)| ticks).sum::<usize>();
+                Some((last, match result {
+                    Some(operand) => Op::Play {
+                        operation: Operation { instruction: Instruction::Push, operand },
+                        ticks,
+                    },
+                    None => Op::Wait { ticks },
+                }))
+            });
+
+            match found {
+                Some((last, replacement)) => {
+                    keep[op..last].iter_mut().for_each(|k| *k = false);
+                    folded.push((last, replacement));
+                    op = last + 1;
+                },
+                None => op += 1,
+            }
+        }
+
+        if folded.is_empty() {
+            return;
+        }
+        for (op, replacement) in folded {
+            module.programs[program].code[op] = replacement;
+        }
+        remove_ops(module, program, &keep);
+    }
+}
+
+/*
+ * Whether an op can go in a merged section, only ones that always go on to
+ * the next op (or stop the piece) can.
+ */
+fn mergeable(op: &Op) -> bool {
+    match op {
+        Op::Play { operation, .. } => operation.instruction != Instruction::SkipBar,
+        Op::Wait { .. } | Op::Send(_) | Op::Recv(_) => true,
+        _ => false,
+    }
+}
+
+/*
+ * Finds a section of ops followed straight away by more copies of itself and
+ * keeps only the first copy, with a repeat after it that plays it as many
+ * times as there were copies. Nothing may jump into the copies that go. The
+ * repeat runs once for every copy, so a section is only merged when more
+ * ops go than that, otherwise the piece would run more ops than before. At
+ * each op the longest saving wins.
+ */
+fn merge(module: &mut Module, program: usize) {
+    let mut start = 0;
+    loop {
+        let entry = entries(module, program);
+        let code = &module.programs[program].code;
+        let mut found = None;
+        while found.is_none() && start < code.len() {
+            let mut best: Option<(usize, usize)> = None;
+            for length in 1..=MAX_SECTION {
+                let section = match code.get(start..start + length) {
+                    Some(section) if section.iter().all(mergeable) => section,
+                    _ => break,
+                };
+                let mut copies = 1;
+                while code.get(start + copies * length..start + (copies + 1) * length) == Some(section)
+                    && !entry[start + copies * length..start + (copies + 1) * length].contains(&true) {
+                    copies += 1;
+                }
+                let saving = (copies - 1) * length;
+                if saving > copies && best.is_none_or(|(l, c)| saving > (c - 1) * l) {
+                    best = Some((length, copies));
+                }
+            }
+            match best {
+                Some(best) => found = Some(best),
+                None => start += 1,
+            }
+        }
+
+        let Some((length, copies)) = found else { return };
+        let end = start + copies * length;
+        let compiled = &mut module.programs[program];
+        // The repeat isn't an event on the score, so it isn't counted as one
+        let origin = Origin { event: None, ..compiled.origins[end - 1].clone() };
+        compiled.code[start + length] = Op::Repeat { times: copies, target: start };
+        compiled.origins[start + length] = origin;
+        let mut keep = vec![true; compiled.code.len()];
+        keep[start + length + 1..end].iter_mut().for_each(|k| *k = false);
+        remove_ops(module, program, &keep);
+        start += length + 1;
+    }
+}
+
+/*
+ * Keeps only the ops that can be reached from the top of a player by
+ * playing, jumping or calling. Programs nothing reaches end up as just their
+ * final Return.
+ */
+fn remove_dead_code(module: &mut Module) {
+    let mut reached: Vec<Vec<bool>> = module.programs.iter()
+        .map(|compiled| vec![false; compiled.code.len()])
+        .collect();
+    let mut pending: Vec<(usize, usize)> = module.players.iter().map(|&p| (p, 0)).collect();
+    while let Some((program, op)) = pending.pop() {
+        if op >= reached[program].len() || reached[program][op] {
+            continue;
+        }
+        reached[program][op] = true;
+        pending.extend(successors(module, program, op).into_iter().map(|next| (program, next)));
+        if let Op::Call { program: callee, target } = module.programs[program].code[op] {
+            pending.push((callee, target));
+        }
+    }
+
+    for (program, mut keep) in reached.into_iter().enumerate() {
+        if let Some(last) = keep.last_mut() {
+            *last = true;
+        }
+        if keep.contains(&false) {
+            remove_ops(module, program, &keep);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::bytecode::compile_file;
+    use crate::data_types::dt::*;
+    use crate::interpreter::interpreter::Limits;
+    use crate::parser::parser::{parse_file, parse_str};
+    use crate::repl::repl::translate;
+    use crate::streams::streams::{SharedOutput, Streams};
+    use crate::trace::trace::{read_trace, Tracer};
+    use crate::vm::vm::Vm;
+
+    fn module(shorthand: &str) -> Module {
+        compile_file("piece.inst", &parse_str(&translate(shorthand).unwrap()).unwrap()).unwrap()
+    }
+
+    // How a run ended, what it wrote and the machine after every op that
+    // changed it, as when the op finished and what was on the stack
+    type Run = (Result<(), RuntimeError>, String, Vec<(usize, Vec<i64>)>);
+
+    fn trace(module: Module) -> Run {
+        let trace = SharedOutput::new();
+        let output = SharedOutput::new();
+        let mut vm = Vm::new(module)
+            .with_streams(Streams::new(std::io::empty(), output.clone()))
+            .with_tracer(Tracer::new(trace.clone(), "piece.inst"));
+        let result = vm.run().map_err(|failure| failure.error);
+        let mut states: Vec<(usize, Vec<i64>)> = read_trace(&trace.text()).unwrap().into_iter()
+            .map(|record| (record.time + record.duration.unwrap_or(0), record.stack_after))
+            .collect();
+        states.dedup();
+        (result, output.text(), states)
+    }
+
+    // Optimizing can only leave states out of a trace, never add or change
+    // one, and has to end up in the same place
+    fn assert_same_results(plain: &Module, optimized: &Module) {
+        let (plain_result, plain_output, plain_states) = trace(plain.clone());
+        let (result, output, states) = trace(optimized.clone());
+        assert_eq!((plain_result, plain_output), (result, output));
+        assert_eq!(plain_states.last(), states.last());
+        let mut remaining = plain_states.iter();
+        for state in &states {
+            assert!(remaining.any(|s| s == state), "{:?}", state);
+        }
+    }
+
+    fn optimized(shorthand: &str, passes: Passes) -> Module {
+        let plain = module(shorthand);
+        let mut optimized = plain.clone();
+        optimize(&mut optimized, passes);
+        assert_same_results(&plain, &optimized);
+        optimized
+    }
+
+    #[test]
+    fn test_optimize_fold() {
+        // 4 + 4 and 4 - 4 fold to single pushes and pushing 4 just to pop it
+        // is only a wait
+        let folded = optimized("C4q C4q D4q C4q C4q E4q | C4q A4q", Passes::default());
+        let code = &folded.programs[0].code;
+        assert_eq!(Op::Play {
+            operation: Operation { instruction: Instruction::Push, operand: 8 },
+            ticks: TICKS_PER_WHOLE * 3 / 4,
+        }, code[0]);
+        assert!(matches!(code[1], Op::Play { operation: Operation {
+            instruction: Instruction::Push, operand: 0 }, .. }));
+        assert_eq!(Op::Wait { ticks: 0 }, code[2]);
+        assert_eq!(Op::Wait { ticks: TICKS_PER_WHOLE / 2 }, code[3]);
+
+        // A 32nd note pushes 0, which has to fail when it plays
+        assert_eq!(5, optimized("C4q C4t D#4q", Passes::default()).programs[0].code.len());
+        assert_eq!(5, optimized("C4q C4q D4q", Passes { fold: false, ..Passes::default() })
+            .programs[0].code.len());
+    }
+
+    #[test]
+    fn test_optimize_merge() {
+        let written_out = "C4e C4e D4e C4e D4e C4e D4e C4e D4e C4e D4e r";
+        let merged = optimized(written_out, Passes { fold: false, ..Passes::default() });
+        let code = &merged.programs[0].code;
+        assert!(code.iter().any(|op| matches!(op, Op::Repeat { times: 5, .. })));
+        assert_eq!(7, code.len());
+
+        // The repeat jumps into the second copy so it has to stay
+        let repeated = "C4e D4e | |: C4e D4e :|x2";
+        assert_eq!(module(repeated).programs[0].code,
+            optimized(repeated, Passes { fold: false, ..Passes::default() }).programs[0].code);
+        assert_eq!(module(written_out).programs[0].code,
+            optimized(written_out, Passes { dead_code: true, ..Passes::none() }).programs[0].code);
+    }
+
+    #[test]
+    fn test_optimize_dead_code() {
+        // Nothing calls the passage after the label and nothing plays after the halt
+        let unused = optimized("C4q C4q label:unused D4q | C4q", Passes::default());
+        assert!(matches!(unused.programs[0].code.as_slice(),
+            [Op::Play { .. }, Op::Play { .. }, Op::Return, Op::Return]));
+        let halted = optimized("C4q B5+D5q C4q C4q", Passes::default());
+        assert_eq!(3, halted.programs[0].code.len());
+        assert_eq!(6, optimized("C4q B5+D5q C4q C4q", Passes::none()).programs[0].code.len());
+
+        let called = "C4q call:twice C4q | label:twice C4q D4q";
+        assert_eq!(module(called).programs[0].code,
+            optimized(called, Passes::default()).programs[0].code);
+    }
+
+    #[test]
+    fn test_optimize_resources() {
+        for file in ["arithmetic", "skip_and_halt", "tape_and_repeats", "chords_and_repeats",
+            "recursion", "triplets", "rests", "hello"] {
+            let path = format!("testing_resources/{}.inst", file);
+            let plain = compile_file(&path, &parse_file(path.clone()).unwrap()).unwrap();
+            let mut optimized = plain.clone();
+            optimize(&mut optimized, Passes::default());
+            assert_same_results(&plain, &optimized);
+        }
+
+        // Echo writes a number and reads a byte twice over, merging that
+        // would run more ops than the piece has and go past the limit it
+        // just fits in
+        let path = "testing_resources/echo.inst";
+        let plain = compile_file(path, &parse_file(path.to_string()).unwrap()).unwrap();
+        let mut optimized = plain.clone();
+        optimize(&mut optimized, Passes::default());
+        let limited = |module: Module| Vm::new(module)
+            .with_streams(Streams::new("21\nAB".as_bytes(), std::io::sink()))
+            .with_limits(Limits { events: Some(10), ..Limits::default() })
+            .run();
+        assert_eq!(Ok(()), limited(plain));
+        assert_eq!(Ok(()), limited(optimized));
+    }
+
+    #[test]
+    fn test_optimize_projects() {
+        use crate::project::project::Project;
+
+        for root in ["calls", "custom_tables", "duet", "interval_melody"] {
+            let plain = Project::load(format!("testing_resources/projects/{}", root)).unwrap()
+                .compile().unwrap();
+            let mut optimized = plain.clone();
+            optimize(&mut optimized, Passes::default());
+            let stacks = |module: Module| {
+                let mut vm = Vm::new(module).with_streams(Streams::new(std::io::empty(),
+                    std::io::sink()));
+                vm.run().unwrap();
+                vm.threads.into_iter().map(|thread| thread.machine).collect::<Vec<_>>()
+            };
+            assert_eq!(stacks(plain), stacks(optimized), "{}", root);
+        }
+    }
+}
+}
@@ -1,20 +1,51 @@
 pub mod parser {
-use std::{fs::File, isize, usize};
+use std::fs::File;
 use regex::Regex;
 use std::io::prelude::*;
 use crate::data_types::dt::*;
+use crate::staff::staff::{check_staff_lines, find_staff_lines};
 
 pub fn parse_file(filepath: String) -> Result<Vec<Line>, ParsingError> {
-    let contents = get_file(filepath).unwrap();
+    let (lines, _) = parse_file_with_options(filepath, &ParseOptions::default())?;
+    Ok(lines)
+}
+
+/*
+ * Same as parse_file but lets the caller pick the parsing mode. Anything the
+ * parser had to work around is handed back as a list of diagnostics.
+ */
+pub fn parse_file_with_options(filepath: String, options: &ParseOptions)
+    -> Result<(Vec<Line>, Vec<Diagnostic>), ParsingError> {
+    let contents = get_file(filepath)?;
+    parse_rows(contents, options)
+}
+
+/*
+ * Parses music that is already in memory, like a measure typed into the REPL.
+ */
+pub fn parse_str(contents: &str) -> Result<Vec<Line>, ParsingError> {
+    let (lines, _) = parse_str_with_options(contents, &ParseOptions::default())?;
+    Ok(lines)
+}
+
+pub fn parse_str_with_options(contents: &str, options: &ParseOptions)
+    -> Result<(Vec<Line>, Vec<Diagnostic>), ParsingError> {
+    parse_rows(contents.lines().map(str::to_string).collect(), options)
+}
+
+fn parse_rows(contents: Vec<String>, options: &ParseOptions)
+    -> Result<(Vec<Line>, Vec<Diagnostic>), ParsingError> {
     let raw_lines = get_raw_lines(contents);
-    get_tokenized_lines(raw_lines)
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    let lines = get_tokenized_lines(raw_lines, options, &mut diagnostics)?;
+    Ok((lines, diagnostics))
 }
 
 fn get_file(file_path: String) -> Result<Vec<String>, ParsingError> {
     if let Ok(mut file) = File::open(file_path.clone()) {
         let mut file_contents = String::new();
 
-        if let Ok(_) = file.read_to_string(&mut file_contents) {
+        if file.read_to_string(&mut file_contents).is_ok() {
             return Ok(file_contents.lines().map(|str| str.to_string()).collect());
         }
 
@@ -25,18 +56,20 @@ fn get_file(file_path: String) -> Result<Vec<String>, ParsingError> {
 
 /*
  * This function takes the lines of code found in filepath and chunks them into
- * lines of music.
+ * lines of music, each with the row of the file it starts on.
  */
-fn get_raw_lines(code_lines: Vec<String>) -> Vec<Vec<String>> {
-    let mut music_lines: Vec<Vec<String>> = Vec::new();
+fn get_raw_lines(code_lines: Vec<String>) -> Vec<(usize, Vec<String>)> {
+    let mut music_lines: Vec<(usize, Vec<String>)> = Vec::new();
     let re = Regex::new(r"^=+").unwrap();
 
     let mut line_builder: Vec<String> = Vec::new();
-    for row in code_lines.iter() {
+    let mut first_row = 0;
+    for (row_number, row) in code_lines.iter().enumerate() {
 
         if re.is_match(row) {
-            music_lines.push(line_builder);
+            music_lines.push((first_row, line_builder));
             line_builder = Vec::new();
+            first_row = row_number + 1;
 
             continue;
         } 
@@ -49,29 +82,94 @@ fn get_raw_lines(code_lines: Vec<String>) -> Vec<Vec<String>> {
 }
 
 
-fn get_tokenized_lines(lines: Vec<Vec<String>>) -> Result<Vec<Line>, ParsingError> {
+fn get_tokenized_lines(lines: Vec<(usize, Vec<String>)>, options: &ParseOptions,
+    diagnostics: &mut Vec<Diagnostic>) -> Result<Vec<Line>, ParsingError> {
     let mut measure_count: usize = 1;
     let mut result: Vec<Line> = Vec::new();
-    for (line_number, line) in lines.iter().enumerate() {
-        if line.len() == 0 { continue; }
-        let found_clef = match get_clef_type(line, line_number) {
-            Ok(staff) => staff,
-            Err(e) => return Err(e),
+    let mut previous: Option<StaffType> = None;
+    let mut signatures: Vec<(StaffType, KeySignature, Option<TimeSignature>)> = Vec::new();
+    for (line_number, (first_row, line)) in lines.iter().enumerate() {
+        if line.is_empty() { continue; }
+        // The staff lines decide where every row sits, the clef only picks the
+        // pitch of the center line
+        let staff_lines = find_staff_lines(line);
+        if staff_lines.len() != 5 {
+            return Err(ParsingError::MissingStaff(line_number));
+        }
+        let center_index = staff_lines[2];
+        let prefix_width = line[center_index].chars()
+            .position(|c| c == 'l')
+            .unwrap_or(0);
+
+        let (line, texts) = strip_annotations(line, &staff_lines, prefix_width);
+        let line = if options.tolerant {
+            align_rows(&line, measure_count, diagnostics)
+        } else {
+            line
         };
 
-        let center_index = get_center_line(line, &found_clef);
+        diagnostics.extend(check_staff_lines(&line, measure_count));
+
+        let art = blank_signatures(&line, prefix_width);
+        let found_clef = match get_clef_type(&art, line_number) {
+            Ok(clef) => clef,
+            Err(e) => match (clef_marker(&line, &staff_lines, prefix_width),
+                guess_clef_type(&art, prefix_width), previous) {
+                (Some(marker), _, _) => marker,
+                (None, Some(guess), _) => {
+                    diagnostics.push(Diagnostic {
+                        measure: measure_count,
+                        row: 0,
+                        column: 0,
+                        message: format!("clef art does not match, reading it as a {:?} clef",
+                            guess),
+                        suggestion: Some("redraw the clef as shown in the README".to_string()),
+                    });
+                    guess
+                },
+                // A system without a clef carries on from the one before it
+                (None, None, Some(clef)) => clef,
+                (None, None, None) => return Err(e),
+            },
+        };
 
-        let measures = match get_measures(line, measure_count, center_index, &found_clef) {
-            Ok(m) => m,
-            Err(e) => return Err(e),
+        // Key and meter carry over from the last system of the same staff
+        let inherited = signatures.iter().find(|(clef, _, _)| *clef == found_clef);
+        let key_signature = match get_key_signature(&line, &staff_lines, prefix_width,
+            &found_clef) {
+            Some(key) => key,
+            None => inherited.map(|(_, key, _)| key.clone()).unwrap_or_default(),
         };
+        let time_signature = match get_time_signature(&line, &staff_lines, prefix_width,
+            line_number)? {
+            Some(meter) => Some(meter),
+            None => inherited.and_then(|(_, _, meter)| meter.clone()),
+        };
+
+        let mut staff = StaffContext {
+            center: center_index,
+            clef: found_clef,
+            key: &key_signature,
+        };
+        let (measures, measure_columns) = get_measures(&line, measure_count, &mut staff,
+            &texts, options, diagnostics)?;
         
         measure_count += measures.len();
+        previous = Some(staff.clef);
+        signatures.retain(|(c, _, _)| *c != found_clef);
+        signatures.push((found_clef, key_signature.clone(), time_signature.clone()));
 
         result.push( Line {
                 clef_type: found_clef,
-                line_height: line.len(),
-                center_line: center_index,
+                key_signature,
+                time_signature,
+                layout: Layout {
+                    line_height: line.len(),
+                    first_row: *first_row,
+                    center_line: center_index,
+                    measure_columns,
+                    rows: line,
+                },
                 contents: measures,
             });
     }
@@ -79,88 +177,511 @@ fn get_tokenized_lines(lines: Vec<Vec<String>>) -> Result<Vec<Line>, ParsingErro
     Ok(result)
 }
 
+/*
+ * Editors love to strip trailing whitespace and people are not always careful
+ * about where they put their barlines. This pads every row so that the barlines
+ * of each measure end up in the same column, noting anything it had to fix.
+ */
+fn align_rows(line: &[String], measure_count: usize, diagnostics: &mut Vec<Diagnostic>)
+    -> Vec<String> {
+    let mut split: Vec<Vec<String>> = line.iter()
+        .map(|row| row.split('l').map(|s| s.to_string()).collect())
+        .collect();
+
+    // The number of barlines most of the rows agree on is the real one
+    let mut counts: Vec<usize> = split.iter()
+        .map(|segments| segments.len() - 1)
+        .filter(|&count| count > 0)
+        .collect();
+    counts.sort();
+    let expected = counts.iter()
+        .max_by_key(|&&count| counts.iter().filter(|&&c| c == count).count())
+        .copied()
+        .unwrap_or(0);
+
+    for (row, segments) in split.iter_mut().enumerate() {
+        let count = segments.len() - 1;
+        if count == 0 { continue; }
+
+        if count > expected {
+            diagnostics.push(Diagnostic {
+                measure: measure_count + expected,
+                row,
+                column: 0,
+                message: format!("row has {} barlines but the staff has {}", count, expected),
+                suggestion: Some("remove the extra 'l' characters from this row".to_string()),
+            });
+            segments.truncate(expected + 1);
+        } else if count < expected {
+            diagnostics.push(Diagnostic {
+                measure: measure_count + count,
+                row,
+                column: 0,
+                message: format!("row stops after {} of {} barlines", count, expected),
+                suggestion: Some("add the missing barlines to this row".to_string()),
+            });
+        }
+    }
+
+    // Every segment of a column of measures gets the width of the widest one
+    let mut widths: Vec<usize> = vec![0; expected + 1];
+    for segments in split.iter().filter(|s| s.len() > 1) {
+        for (i, segment) in segments.iter().enumerate() {
+            widths[i] = widths[i].max(segment.chars().count());
+        }
+    }
+
+    let mut trimmed_rows = 0;
+    let mut aligned: Vec<String> = Vec::new();
+    for (row, segments) in split.iter().enumerate() {
+        if segments.len() == 1 {
+            aligned.push(segments[0].clone());
+            continue;
+        }
+
+        let mut joined: Vec<String> = Vec::new();
+        for (i, width) in widths.iter().enumerate() {
+            let mut segment = segments.get(i).cloned().unwrap_or_default();
+            let missing = width - segment.chars().count();
+            if missing > 0 && i == expected {
+                trimmed_rows += 1;
+            } else if missing > 0 && i != 0 && i < segments.len() {
+                diagnostics.push(Diagnostic {
+                    measure: measure_count + i - 1,
+                    row,
+                    column: segment.chars().count(),
+                    message: format!("barline is {} column(s) left of the other rows", missing),
+                    suggestion: Some(format!("add {} character(s) before the barline", missing)),
+                });
+            }
+
+            let filler = if segment.ends_with('-') { '-' } else { ' ' };
+            segment.extend(std::iter::repeat_n(filler, missing));
+            joined.push(segment);
+        }
+        aligned.push(joined.join("l"));
+    }
+
+    if trimmed_rows > 0 && trimmed_rows < split.iter().filter(|s| s.len() > 1).count() {
+        diagnostics.push(Diagnostic {
+            measure: measure_count + expected - 1,
+            row: 0,
+            column: 0,
+            message: format!("rows disagree on the whitespace after the last barline, {} of \
+                them look trimmed", trimmed_rows),
+            suggestion: Some("turn off trailing whitespace trimming for .inst files".to_string()),
+        });
+    }
+
+    aligned
+}
+
 /*
  * This function breaks a line into measures else it bricks the shit.
  *
  */
-fn get_measures(line: &Vec<String>, measure_count: usize, center: usize, clef: &StaffType) 
-    -> Result<Vec<Bar>, ParsingError> {
-        let new_measures = line.first().unwrap().match_indices('l').collect::<Vec<_>>().len();
-        let mut measures: Vec<Vec<String>> = vec![Vec::new(); new_measures];
-        for l in line.iter() {
-            for (i, m) in l.split("l").enumerate() {
+fn get_measures(line: &[String], measure_count: usize, staff: &mut StaffContext,
+    texts: &[(usize, String)], options: &ParseOptions, diagnostics: &mut Vec<Diagnostic>)
+    -> Result<(Vec<Bar>, Vec<usize>), ParsingError> {
+        let new_measures = line.iter()
+            .map(|row| row.matches('l').count())
+            .max()
+            .unwrap_or(0);
+        let barlines: Vec<usize> = line.iter()
+            .find(|row| row.matches('l').count() == new_measures)
+            .map(|row| row.chars()
+                .enumerate()
+                .filter(|(_, c)| *c == 'l')
+                .map(|(idx, _)| idx)
+                .collect())
+            .unwrap_or_default();
+
+        // Every measure keeps one string per row so row indices stay meaningful
+        let mut measures: Vec<Vec<String>> = vec![vec![String::new(); line.len()]; new_measures];
+        for (row, l) in line.iter().enumerate() {
+            for (i, m) in l.split('l').enumerate() {
                 if i == 0 { continue; }
-                measures[i-1].push(m.to_string());
+                measures[i-1][row] = m.to_string();
             }
         }
 
-        let bars: Result<Vec<Bar>, ParsingError> = measures.iter().enumerate()
-            .map(|(i, measure)| tokenize_bars(measure, measure_count + i, center, clef))
+        // Clef changes inside the line carry on into the measures after them
+        let mut bars: Vec<Bar> = Vec::new();
+        let mut starts: Vec<usize> = Vec::new();
+        for (i, measure) in measures.iter().enumerate() {
+            let width = measure.iter().map(|row| row.chars().count()).max().unwrap_or(0);
+            let closed = i + 1 < new_measures;
+            let marked = |col: usize| measure.iter().any(|row| row.chars().nth(col) == Some(':'));
+
+            starts.push(barlines.get(i).map_or(0, |&col| col + 1));
+
+            // Empty measures are still measures, they just have nothing in them
+            if measure.iter().all(|row| row.trim().is_empty()) {
+                bars.push(Bar { measure_number: measure_count + i, events: Vec::new() });
+                continue;
+            }
+
+            let mut bar = tokenize_bars(measure, measure_count + i, staff, options,
+                diagnostics)?;
+
+            if marked(0) {
+                bar.events.insert(0, Event::Barline(Barline {
+                    kind: BarlineKind::RepeatStart,
+                    column: 0,
+                }));
+            }
+
+            if closed {
+                let kind = if width > 0 && marked(width - 1) {
+                    BarlineKind::RepeatEnd { times: None }
+                } else {
+                    BarlineKind::Single
+                };
+                bar.events.push(Event::Barline(Barline { kind, column: width }));
+            }
+
+            bars.push(bar);
+        }
+
+        // Text goes to the measure it starts over, empty measures stay empty
+        let filled: Vec<usize> = (0..bars.len()).filter(|&idx| !bars[idx].events.is_empty())
             .collect();
+        for (col, text) in texts.iter() {
+            let Some(&idx) = filled.iter().rev().find(|&&idx| starts[idx] <= *col)
+                .or(filled.first()) else { continue };
+            let column = col.saturating_sub(starts[idx]);
+            let events = &mut bars[idx].events;
+
+            // "xN" over a repeat sign says how many times to play the passage
+            let times = text.strip_prefix('x').and_then(|n| n.parse::<usize>().ok());
+            if let (Some(n), Some(Event::Barline(Barline {
+                kind: BarlineKind::RepeatEnd { times }, .. }))) = (times, events.last_mut()) {
+                *times = Some(n);
+                continue;
+            }
+
+            let at = events.iter()
+                .position(|e| e.column() > column
+                    || (e.column() == column && !matches!(e, Event::Direction(_))))
+                .unwrap_or(events.len());
+            events.insert(at, Event::Direction(Direction { text: text.clone(), column }));
+        }
+
+        Ok((bars, starts))
+    }
+
+/*
+ * Looks up a character in the measure grid, anything off the edge is a space.
+ */
+fn char_at(grid: &[Vec<char>], row: isize, col: isize) -> char {
+    if row < 0 || col < 0 {
+        return ' ';
+    }
+
+    grid.get(row as usize)
+        .and_then(|r| r.get(col as usize))
+        .copied()
+        .unwrap_or(' ')
+}
+
+/*
+ * Finds the column of the stem attached to the note head at head_idx. Stems
+ * pointing up sit right of the head, stems pointing down sit left of it.
+ */
+fn find_tail(grid: &[Vec<char>], row: usize, head_idx: usize) -> Option<usize> {
+    let (row, head_idx) = (row as isize, head_idx as isize);
+
+    if char_at(grid, row, head_idx + 1) == '|' {
+        return Some(head_idx as usize + 1);
+    }
 
-        bars
+    if char_at(grid, row, head_idx - 1) == '|' {
+        return Some(head_idx as usize - 1);
     }
 
-fn find_tail(line: &String, head_idx: usize) -> isize {
-    let mut tail_side = 0;
-    if let Some(tail) = line.chars().nth(head_idx - 1) {
-            if tail == '|' {
-                tail_side = -1;
+    None
+}
+
+/*
+ * A single note or rest found in a measure. Columns are used to put the
+ * glyphs in order and to find the dots and tuplet brackets around them. Rests
+ * have no offset from the center line.
+ */
+struct Glyph {
+    column: usize,
+    last_column: usize,
+    offset: Option<isize>,
+    duration: Beats,
+}
+
+/*
+ * Follows the stem of the note head at (row, col) and works out its rhythm
+ * from the head, the flags and the beams. Returns the rhythm along with the
+ * first and last column the note takes up.
+ */
+fn read_note(grid: &[Vec<char>], row: usize, col: usize, measure_count: usize,
+    tolerant: bool, diagnostics: &mut Vec<Diagnostic>)
+    -> Result<(usize, usize, Beats), ParsingError> {
+    let hollow = grid[row][col] == 'O';
+    let stem = match find_tail(grid, row, col) {
+        Some(stem) => stem,
+        None if hollow => return Ok((col, col, Beats::Whole)),
+        None => return Err(ParsingError::InvalidNoteDeclaration(measure_count, col)),
+    };
+
+    // The heads of a stem sit at one end of it and the flags at the other, the
+    // heads of a chord can also sit in the middle
+    let (mut top, mut bottom) = (row as isize, row as isize);
+    while char_at(grid, top - 1, stem as isize) == '|' { top -= 1; }
+    while char_at(grid, bottom + 1, stem as isize) == '|' { bottom += 1; }
+    let head_at = |r: isize| [stem as isize - 1, stem as isize + 1].into_iter()
+        .any(|c| matches!(char_at(grid, r, c), '@' | 'O'));
+    let direction: isize = if !head_at(bottom) && head_at(top) { 1 } else { -1 };
+
+    let mut r = row as isize;
+    let mut s = stem as isize;
+    let mut last_column = col.max(stem);
+    let mut flags = 0;
+    loop {
+        let right = char_at(grid, r, s + 1);
+        if (direction < 0 && right == '\\') || (direction > 0 && right == '/') {
+            flags += 1;
+            last_column = last_column.max(s as usize + 1);
+        }
+        if right == '_' || char_at(grid, r, s - 1) == '_' {
+            flags += 1;
+        }
+
+        let next = r + direction;
+        if char_at(grid, next, s) == '|' {
+            r = next;
+            continue;
+        }
+
+        // A stem that jumps a column means one of the rows got shifted
+        let shifted = [s - 1, s + 1].into_iter().find(|&c| char_at(grid, next, c) == '|');
+        if let (true, Some(c)) = (tolerant, shifted) {
+            // Stepping back into line after a shifted row is not a second mistake
+            if c == stem as isize {
+                r = next;
+                s = c;
+                continue;
             }
+
+            diagnostics.push(Diagnostic {
+                measure: measure_count,
+                row: next as usize,
+                column: c as usize,
+                message: format!("stem of the note at column {} moves from column {} to {}",
+                    col, s, c),
+                suggestion: Some(format!("move the '|' on row {} one column to the {}",
+                    next, if c > s { "left" } else { "right" })),
+            });
+            r = next;
+            s = c;
+            continue;
+        }
+
+        break;
+    }
+
+    // A beam drawn just past the end of the stem
+    if char_at(grid, r + direction, s) == '_' {
+        flags += 1;
     }
 
-    if let Some(tail) = line.chars().nth(head_idx + 1) {
-            if tail == '|' {
-                tail_side = 1;
+    let duration = match (hollow, flags) {
+        (true, _) => Beats::Half,
+        (false, 0) => Beats::Quarter,
+        (false, 1) => Beats::Eighth,
+        (false, 2) => Beats::Sixteenth,
+        (false, _) => Beats::ThirtySecond,
+    };
+
+    Ok((col.min(stem), last_column, duration))
+}
+
+/*
+ * Reads the rest glyph (if any) that starts at (row, col). Eighth rests and
+ * shorter are a diagonal of "*_/" with one "*" per flag, the quarter rest is a
+ * "Z" and the half and whole rests are "/===\" and "\===/".
+ */
+fn read_rest(grid: &[Vec<char>], row: usize, col: usize) -> Option<(usize, usize, Beats)> {
+    let (r, c) = (row as isize, col as isize);
+    match grid[row][col] {
+        'Z' => Some((col, col, Beats::Quarter)),
+        '*' if char_at(grid, r - 1, c + 1) != '*' => {
+            let mut stars = 1;
+            while char_at(grid, r + stars, c - stars) == '*' {
+                stars += 1;
+            }
+            let duration = match stars {
+                1 => Beats::Eighth,
+                2 => Beats::Sixteenth,
+                _ => Beats::ThirtySecond,
+            };
+            Some((col.saturating_sub(stars as usize), col + 2, duration))
+        },
+        open @ ('/' | '\\') if char_at(grid, r, c + 1) == '=' => {
+            let mut end = c + 1;
+            while char_at(grid, r, end) == '=' {
+                end += 1;
             }
+            match (open, char_at(grid, r, end)) {
+                ('/', '\\') => Some((col, end as usize, Beats::Half)),
+                ('\\', '/') => Some((col, end as usize, Beats::Whole)),
+                _ => None,
+            }
+        },
+        _ => None,
     }
+}
 
-    tail_side
+/*
+ * What a measure needs to know about the staff it is on to turn rows into
+ * pitches. The clef changes as clef markers inside the line are passed.
+ */
+struct StaffContext<'a> {
+    center: usize,
+    clef: StaffType,
+    key: &'a KeySignature,
 }
 
-fn tokenize_bars(measure: &Vec<String>, measure_count: usize, center: usize, clef: &StaffType) 
-    -> Result<Bar, ParsingError> {
-        //GO THROUGH EACH STEP AND CHECK THE THINGS FOR PLACES THAT ARE GOOD
-        let length = measure.first().unwrap().len();
-        let mut notes: Vec<Note> = Vec::new();
-        let mut durs: Vec<Beats> = Vec::new();
+fn tokenize_bars(measure: &[String], measure_count: usize, staff: &mut StaffContext,
+    options: &ParseOptions, diagnostics: &mut Vec<Diagnostic>) -> Result<Bar, ParsingError> {
+        let length = measure.iter()
+            .map(|line| line.chars().count())
+            .find(|&l| l != 0)
+            .unwrap_or(0);
 
-        for line in measure.iter() {
-            if line.len() != length && line.len() != 0 {
-                return Err(ParsingError::InvalidMeasureLenghts(measure_count));
+        if !options.tolerant {
+            for line in measure.iter() {
+                let l = line.chars().count();
+                if l != length && l != 0 {
+                    return Err(ParsingError::InvalidMeasureLenghts(measure_count));
+                }
             }
         }
 
-        for pos in 0..length {
-            let mut this_col: Vec<char> = Vec::new();
-            for line in measure.iter() {
-                if let Some(c) = line.chars().nth(pos) {
-                    if c == '@' {
-                        find_tail(line, pos);
-                    }
-                    if c == 'O' {
-                        find_tail(line, pos);
-                    }
+        let grid: Vec<Vec<char>> = measure.iter().map(|line| line.chars().collect()).collect();
+        let mut glyphs: Vec<Glyph> = Vec::new();
+        for (row, cells) in grid.iter().enumerate() {
+            for (col, &c) in cells.iter().enumerate() {
+                if c == '@' || c == 'O' {
+                    let (column, last_column, duration) = read_note(&grid, row, col,
+                        measure_count, options.tolerant, diagnostics)?;
+                    let offset: isize = row as isize - staff.center as isize;
+
+                    glyphs.push(Glyph {
+                        column,
+                        last_column,
+                        offset: Some(offset),
+                        duration,
+                    });
+                } else if let Some((column, last_column, duration)) = read_rest(&grid, row, col) {
+                    glyphs.push(Glyph { column, last_column, offset: None, duration });
                 }
             }
+        }
+
+        // Sorting is stable so notes sharing a column stay top to bottom
+        glyphs.sort_by_key(|glyph| glyph.column);
+
+        let marks_in = |mark: char| -> Vec<usize> {
+            let mut cols: Vec<usize> = (0..length)
+                .filter(|&col| grid.iter().any(|row| row.get(col) == Some(&mark)))
+                .collect();
+            cols.sort();
+            cols
+        };
+
+        let closes = marks_in('>');
+        let tuplets: Vec<(usize, usize)> = marks_in('<').into_iter()
+            .map(|open| (open, closes.iter().find(|&&c| c > open).copied().unwrap_or(length)))
+            .collect();
+        for &(open, close) in tuplets.iter() {
+            for glyph in glyphs.iter_mut().filter(|g| g.column > open && g.column < close) {
+                glyph.duration = glyph.duration.triplet()
+                    .ok_or(ParsingError::InvalidNoteDeclaration(measure_count, glyph.column))?;
+            }
+        }
+
+        let dots = marks_in('.');
+        for glyph in glyphs.iter_mut() {
+            if dots.contains(&(glyph.last_column + 1)) {
+                glyph.duration = glyph.duration.dotted()
+                    .ok_or(ParsingError::InvalidNoteDeclaration(measure_count, glyph.column))?;
+            }
+        }
 
-            //This is ugly and only works on quarternotes we shoudl build a function
-            //to find the next note and beat so we can make this legible and extensible
-            let mut note_height: Option<isize> = None;
-            if let Some(n) = note_height {
+        // "&" and "?" inside a measure switch to the treble and bass clef
+        let mut clef_changes: Vec<(usize, StaffType)> = marks_in('&').into_iter()
+            .map(|col| (col, StaffType::Treble))
+            .chain(marks_in('?').into_iter().map(|col| (col, StaffType::Bass)))
+            .collect();
+        clef_changes.sort_by_key(|(col, _)| *col);
+
+        let mut changes = clef_changes.into_iter().peekable();
+        let mut events: Vec<Event> = Vec::new();
+        let mut last_column = None;
+        for glyph in glyphs.into_iter() {
+            while let Some((_, new_clef)) = changes.next_if(|(col, _)| *col < glyph.column) {
+                staff.clef = new_clef;
+            }
 
-                let offset: isize = n - center as isize;
+            let pitch = match glyph.offset {
+                Some(offset) => staff.key.apply(calculate_note(offset, &staff.clef)),
+                None => {
+                    events.push(Event::Rest(RestEvent {
+                        duration: glyph.duration,
+                        column: glyph.column,
+                    }));
+                    last_column = None;
+                    continue;
+                },
+            };
+
+            // Heads further down the same stem turn the note into a chord
+            let same_stem = last_column == Some((glyph.column, glyph.last_column));
+            match events.last_mut() {
+                Some(Event::Note(note)) if same_stem && note.duration == glyph.duration => {
+                    let chord = ChordEvent {
+                        pitches: vec![pitch, note.pitch.clone()],
+                        duration: glyph.duration,
+                        column: glyph.column,
+                    };
+                    *events.last_mut().unwrap() = Event::Chord(chord);
+                },
+                Some(Event::Chord(chord)) if same_stem && chord.duration == glyph.duration => {
+                    chord.pitches.insert(0, pitch);
+                },
+                _ => events.push(Event::Note(NoteEvent {
+                    pitch,
+                    duration: glyph.duration,
+                    column: glyph.column,
+                })),
+            }
+            last_column = Some((glyph.column, glyph.last_column));
+        }
+        if let Some((_, new_clef)) = changes.last() {
+            staff.clef = new_clef;
+        }
 
-                notes.push(calculate_note(offset, clef));
-            } else {
-                return Err(ParsingError::InvalidNoteDeclaration(measure_count, pos));
+        for (open, close) in tuplets {
+            let inside: Vec<usize> = events.iter()
+                .enumerate()
+                .filter(|(_, e)| e.column() > open && e.column() < close)
+                .map(|(idx, _)| idx)
+                .collect();
+            if let (Some(&first), Some(&last)) = (inside.first(), inside.last()) {
+                let group: Vec<Event> = events.drain(first..=last).collect();
+                events.insert(first, Event::Tuplet(TupletGroup { events: group, column: open }));
             }
         }
 
         Ok(Bar {
-            pitches: notes,
-            durations: durs,
             measure_number: measure_count,
+            events,
         })
     }
 
@@ -220,36 +741,37 @@ fn modulo(x: isize, y: usize) -> usize {
  * This function takes a group of Strings that is one line of music (aka several
  * bars)
  */
-fn get_clef_type(line: &Vec<String>, line_number: usize) -> Result<StaffType, ParsingError> {
+fn get_clef_type(line: &[String], line_number: usize) -> Result<StaffType, ParsingError> {
     let mut found = false;
     let mut found_type = StaffType::Treble;
     let mut strip_counter = 0;
     // Get find the top regex and then make sure that they are all in order
-    let mut treble_regex: Vec<Regex> = Vec::new();
-    treble_regex.push(Regex::new(r"^ {3}\/\\").unwrap());
-    treble_regex.push(Regex::new(r"^ {3}\| \\ l").unwrap());
-    treble_regex.push(Regex::new(r"^ {3}\| \/ l").unwrap());
-    treble_regex.push(Regex::new(r"^ {3}\|\/  l").unwrap());
-    treble_regex.push(Regex::new(r"^  \/\| {3}l").unwrap());
-    treble_regex.push(Regex::new(r"^ \/ \| {3}l").unwrap());
-    treble_regex.push(Regex::new(r"^\|  \|\\  l").unwrap());
-    treble_regex.push(Regex::new(r"^ \\ \| \| l").unwrap());
-    treble_regex.push(Regex::new(r"^  \\\|\/  l").unwrap());
-    treble_regex.push(Regex::new(r"^\/@ \| {3}l").unwrap());
-    treble_regex.push(Regex::new(r"^\\_\/").unwrap());
-
-    let mut bass_regex: Vec<Regex> = Vec::new();
-    bass_regex.push(Regex::new(r"^ __").unwrap());
-    bass_regex.push(Regex::new(r"^").unwrap());
-    bass_regex.push(Regex::new(r"^\/  \\ {4}l").unwrap());
-    bass_regex.push(Regex::new(r"^\| {3}\\ @ l").unwrap());
-    bass_regex.push(Regex::new(r"^\\@@ \| {3}l").unwrap());
-    bass_regex.push(Regex::new(r"^ @@ \/ @ l").unwrap());
-    bass_regex.push(Regex::new(r"^ {3}\/ {4}l").unwrap());
-    bass_regex.push(Regex::new(r"^ {2}\/ {5}l").unwrap());
-    bass_regex.push(Regex::new(r"^ \/ {6}l").unwrap());
-    bass_regex.push(Regex::new(r"^\/ {7}l").unwrap());
-    bass_regex.push(Regex::new(r"^ {8}l").unwrap());
+    let treble_regex: Vec<Regex> = vec![
+        Regex::new(r"^ {3}\/\\").unwrap(),
+        Regex::new(r"^ {3}\| \\ +l").unwrap(),
+        Regex::new(r"^ {3}\| \/ +l").unwrap(),
+        Regex::new(r"^ {3}\|\/ +l").unwrap(),
+        Regex::new(r"^  \/\| +l").unwrap(),
+        Regex::new(r"^ \/ \| +l").unwrap(),
+        Regex::new(r"^\|  \|\\ +l").unwrap(),
+        Regex::new(r"^ \\ \| \| +l").unwrap(),
+        Regex::new(r"^  \\\|\/ +l").unwrap(),
+        Regex::new(r"^\/@ \| +l").unwrap(),
+        Regex::new(r"^\\_\/").unwrap(),
+    ];
+
+    let bass_regex: Vec<Regex> = vec![
+        Regex::new(r"^ __").unwrap(),
+        Regex::new(r"^\/  \\ +l").unwrap(),
+        Regex::new(r"^\| {3}\\ @ +l").unwrap(),
+        Regex::new(r"^\\@@ \| +l").unwrap(),
+        Regex::new(r"^ @@ \/ @ +l").unwrap(),
+        Regex::new(r"^ {3}\/ +l").unwrap(),
+        Regex::new(r"^ {2}\/ +l").unwrap(),
+        Regex::new(r"^ \/ +l").unwrap(),
+        Regex::new(r"^\/ +l").unwrap(),
+        Regex::new(r"^ +l").unwrap(),
+    ];
 
     for strip in line.iter() {
         if !found {
@@ -267,22 +789,16 @@ fn get_clef_type(line: &Vec<String>, line_number: usize) -> Result<StaffType, Pa
             continue;
         }
 
-        let correct = match found_type {
-            StaffType::Bass => bass_regex.iter()
-                .nth(strip_counter)
-                .unwrap()
-                .is_match(strip),
-            StaffType::Treble => treble_regex.iter()
-                .nth(strip_counter)
-                .unwrap()
-                .is_match(strip),
+        let expected = match found_type {
+            StaffType::Bass => &bass_regex,
+            StaffType::Treble => &treble_regex,
         };
 
-        if !correct {
+        if !expected[strip_counter].is_match(strip) {
             return Err(ParsingError::InvalidStaffDeclaration(line_number))
         }
 
-        if strip_counter == 10 {
+        if strip_counter == expected.len() - 1 {
             return Ok(found_type)
         }
 
@@ -293,27 +809,183 @@ fn get_clef_type(line: &Vec<String>, line_number: usize) -> Result<StaffType, Pa
 }
 
 /*
- * This function takes a group of strings that is one line of music and returns
- * the index of the center line (the note B4 for treble and D3 for bass)
+ * When the clef art does not match exactly we still only need to know which
+ * clef it was meant to be. The two dots of the bass clef and the curl at the
+ * top or bottom of the treble clef are hard to smudge beyond recognition.
  */
-fn get_center_line(line: &Vec<String>, clef_type: &StaffType) -> usize {
-    let center_finder = match clef_type {
-        StaffType::Bass => Regex::new(r"^\ {3}\/\ {4}l"),
-        StaffType::Treble => Regex::new(r"^\ \/\ \|\ {3}l"),
-    }.unwrap();
-
-    let mut center_index = 0;
-    for (idx, row) in line.iter().enumerate() {
-        if center_finder.is_match(row) {
-            center_index = idx;
-            break;
+fn guess_clef_type(line: &[String], prefix_width: usize) -> Option<StaffType> {
+    let clef_area: Vec<String> = line.iter()
+        .map(|row| row.chars().take(prefix_width).collect())
+        .collect();
+
+    if clef_area.iter().any(|row| row.contains("@@")) {
+        Some(StaffType::Bass)
+    } else if clef_area.iter().any(|row| row.contains("/\\") || row.contains("\\_/")
+        || row.contains("/@")) {
+        Some(StaffType::Treble)
+    } else {
+        None
+    }
+}
+
+/*
+ * Key and time signatures sit between the clef and the first barline. They are
+ * blanked out before the clef art gets checked.
+ */
+fn blank_signatures(line: &[String], prefix_width: usize) -> Vec<String> {
+    line.iter()
+        .map(|row| row.chars()
+            .enumerate()
+            .map(|(idx, c)| match c {
+                '#' | 'b' | '0'..='9' if idx < prefix_width => ' ',
+                _ => c,
+            })
+            .collect())
+        .collect()
+}
+
+/*
+ * The rows of a line that can hold clef markers and signatures: the staff and
+ * the space just above and below it.
+ */
+fn clef_area<'a>(line: &'a [String], staff_lines: &[usize], prefix_width: usize)
+    -> impl Iterator<Item = (usize, Vec<char>)> + 'a {
+    let (top, bottom) = (staff_lines[0].saturating_sub(1), staff_lines[4] + 1);
+
+    line.iter()
+        .enumerate()
+        .filter(move |(idx, _)| *idx >= top && *idx <= bottom)
+        .map(move |(idx, row)| (idx, row.chars().take(prefix_width).collect()))
+}
+
+/*
+ * Continuation lines can swap the full clef drawing for "&" (treble) or "?"
+ * (bass), the symbols music fonts use for the two clefs.
+ */
+fn clef_marker(line: &[String], staff_lines: &[usize], prefix_width: usize)
+    -> Option<StaffType> {
+    clef_area(line, staff_lines, prefix_width)
+        .flat_map(|(_, row)| row.into_iter())
+        .find_map(|c| match c {
+            '&' => Some(StaffType::Treble),
+            '?' => Some(StaffType::Bass),
+            _ => None,
+        })
+}
+
+/*
+ * A "#" or "b" left of the first barline makes every note with the letter of
+ * the row it sits on sharp or flat.
+ */
+fn get_key_signature(line: &[String], staff_lines: &[usize], prefix_width: usize,
+    clef: &StaffType) -> Option<KeySignature> {
+    let mut found: Vec<(usize, &'static str, Accidental)> = Vec::new();
+    for (idx, row) in clef_area(line, staff_lines, prefix_width) {
+        for (col, c) in row.into_iter().enumerate() {
+            let accidental = match c {
+                '#' => Accidental::Sharp,
+                'b' => Accidental::Flat,
+                _ => continue,
+            };
+            let offset: isize = idx as isize - staff_lines[2] as isize;
+            found.push((col, calculate_note(offset, clef).note_name, accidental));
+        }
+    }
+
+    if found.is_empty() {
+        return None;
+    }
+
+    found.sort_by_key(|(col, _, _)| *col);
+    Some(KeySignature {
+        accidentals: found.into_iter().map(|(_, name, acc)| (name, acc)).collect(),
+    })
+}
+
+/*
+ * The time signature is written as two numbers left of the first barline, one
+ * above the middle line and one below it.
+ */
+fn get_time_signature(line: &[String], staff_lines: &[usize], prefix_width: usize,
+    line_number: usize) -> Result<Option<TimeSignature>, ParsingError> {
+    let mut top: Option<String> = None;
+    let mut bottom: Option<String> = None;
+    for (idx, row) in clef_area(line, staff_lines, prefix_width) {
+        let digits: String = row.into_iter().filter(|c| c.is_ascii_digit()).collect();
+        if digits.is_empty() {
+            continue;
+        }
+
+        if idx < staff_lines[2] {
+            top.get_or_insert(digits);
+        } else if idx > staff_lines[2] {
+            bottom.get_or_insert(digits);
+        } else {
+            return Err(ParsingError::InvalidTimeSignature(line_number));
         }
     }
 
-    center_index
+    match (top, bottom) {
+        (None, None) => Ok(None),
+        (Some(top), Some(bottom)) => {
+            let beats = top.parse::<usize>().ok().filter(|&beats| beats > 0);
+            let beat_size = bottom.parse::<usize>().ok().and_then(Beats::from_denominator);
+            match (beats, beat_size) {
+                (Some(beats), Some(beat_size)) => Ok(Some(TimeSignature { beats, beat_size })),
+                _ => Err(ParsingError::InvalidTimeSignature(line_number)),
+            }
+        },
+        _ => Err(ParsingError::InvalidTimeSignature(line_number)),
+    }
+}
+
+/*
+ * Rows of lyrics, directions and other text can sit above and below the staff.
+ * They are not part of the grid of notes so everything right of the clef is
+ * dropped from them and handed back as words with the column they start at.
+ * Rows inside the staff are always kept.
+ */
+fn strip_annotations(line: &[String], staff_lines: &[usize], prefix_width: usize)
+    -> (Vec<String>, Vec<(usize, String)>) {
+    let mut texts: Vec<(usize, String)> = Vec::new();
+    let rows = line.iter()
+        .enumerate()
+        .map(|(idx, row)| {
+            let inside = idx >= staff_lines[0] && idx <= staff_lines[4];
+            if inside || !is_annotation_row(row, prefix_width) {
+                return row.clone();
+            }
+
+            let mut word = String::new();
+            for (col, c) in row.chars().chain(std::iter::once(' ')).enumerate() {
+                if !c.is_whitespace() {
+                    word.push(c);
+                } else if !word.is_empty() {
+                    texts.push((col - word.chars().count(), std::mem::take(&mut word)));
+                }
+            }
+
+            row.chars().take(prefix_width).collect()
+        })
+        .collect();
+
+    texts.sort_by_key(|(col, _)| *col);
+    (rows, texts)
 }
 
+/*
+ * A row is text rather than music when it has a word in it, that is two or
+ * more letters in a row that are not just barlines, note heads or rests, or a
+ * repeat count like "x2".
+ */
+fn is_annotation_row(row: &str, prefix_width: usize) -> bool {
+    let body: Vec<char> = row.chars().skip(prefix_width).collect();
+    let repeat_count = row.split_whitespace().any(|word| word.len() >= 2
+        && word.starts_with('x') && word[1..].chars().all(|c| c.is_ascii_digit()));
 
+    repeat_count || body.split(|c| !c.is_alphabetic())
+        .any(|word| word.len() >= 2 && word.iter().any(|c| !"lOZC".contains(*c)))
+}
 
 #[cfg(test)]
 mod tests {
@@ -513,44 +1185,56 @@ mod tests {
 
         let contents = get_file("testing_resources/cmaj_scale_quarternotes.inst".to_string()).unwrap();
         let raw_lines = get_raw_lines(contents);
-        let lines = get_tokenized_lines(raw_lines).unwrap();
+        let lines = get_tokenized_lines(raw_lines, &ParseOptions::default(), &mut Vec::new())
+            .unwrap();
+
+        let quarters = |pitches: Vec<Note>, columns: [usize; 4]| {
+            let mut events: Vec<Event> = pitches.into_iter()
+                .zip(columns)
+                .map(|(pitch, column)| Event::Note(NoteEvent {
+                    pitch,
+                    duration: Beats::Quarter,
+                    column,
+                }))
+                .collect();
+            events.push(Event::Barline(Barline { kind: BarlineKind::Single, column: 13 }));
+            events
+        };
 
         let mut expected: Vec<Line> = Vec::new();
-        let mut expected_bars: Vec<Bar> = Vec::new();
-        expected_bars.push(Bar {
-            pitches: vec![c4.clone(), d4.clone(), e4.clone(), f4.clone()],
-            durations: vec![Beats::Quarter; 4],
+        let expected_bars: Vec<Bar> = vec![
+        Bar {
+            events: quarters(vec![c4.clone(), d4.clone(), e4.clone(), f4.clone()],
+                [1, 4, 7, 10]),
             measure_number: 1,
-        });
-        expected_bars.push(Bar {
-            pitches: vec![g4.clone(), a5.clone(), b5.clone(), c5.clone()],
-            durations: vec![Beats::Quarter; 4],
+        },
+        Bar {
+            events: quarters(vec![g4.clone(), a5.clone(), b5.clone(), c5.clone()],
+                [1, 4, 7, 10]),
             measure_number: 2,
-        });
-        expected_bars.push(Bar {
-            pitches: vec![c5, b5, a5, g4],
-            durations: vec![Beats::Quarter; 4],
+        },
+        Bar {
+            events: quarters(vec![c5, b5, a5, g4], [2, 5, 8, 11]),
             measure_number: 3,
-        });
-        expected_bars.push(Bar {
-            pitches: vec![f4, e4, d4, c4],
-            durations: vec![Beats::Quarter; 4],
+        },
+        Bar {
+            events: quarters(vec![f4, e4, d4, c4], [1, 4, 7, 10]),
             measure_number: 4,
-        });
-        expected_bars.push(Bar {
-            pitches: Vec::new(),
-            durations: Vec::new(),
+        },
+        Bar {
+            events: Vec::new(),
             measure_number: 5,
-        });
-        expected_bars.push(Bar {
-            pitches: Vec::new(),
-            durations: Vec::new(),
+        },
+        Bar {
+            events: Vec::new(),
             measure_number: 6,
-        });
+        },
+        ];
         let expected_line = Line {
             clef_type: StaffType::Treble,
-            line_height: 12,
-            center_line: 5,
+            key_signature: KeySignature::default(),
+            time_signature: None,
+            layout: Layout::default(),
             contents: expected_bars,
         };
 
@@ -558,5 +1242,178 @@ mod tests {
 
         assert_eq!(expected, lines);
     }
+
+    fn durations_of(filepath: &str) -> Vec<Vec<Beats>> {
+        parse_file(filepath.to_string()).unwrap()[0].contents.iter()
+            .map(|bar| bar.durations())
+            .collect()
+    }
+
+    #[test]
+    fn test_parser_rhythms_and_rests() {
+        let notes = durations_of("testing_resources/rhythms.inst");
+        assert_eq!(notes[0], vec![Beats::ThirtySecond; 4]);
+        assert_eq!(notes[1], vec![Beats::Sixteenth; 4]);
+        assert_eq!(notes[2], vec![Beats::Eighth; 4]);
+        assert_eq!(notes[3], vec![Beats::Quarter; 2]);
+        assert_eq!(notes[4], vec![Beats::Half; 2]);
+        assert_eq!(notes[5], vec![Beats::Whole]);
+
+        let rests = parse_file("testing_resources/rests.inst".to_string()).unwrap();
+        let expected = [Beats::ThirtySecond, Beats::Sixteenth, Beats::Eighth, Beats::Quarter,
+            Beats::Half, Beats::Whole];
+        for (bar, duration) in rests[0].contents.iter().zip(expected) {
+            assert_eq!(bar.pitches(), vec![REST]);
+            assert_eq!(bar.durations(), vec![duration]);
+        }
+    }
+
+    #[test]
+    fn test_parser_dots_and_triplets() {
+        let dotted = durations_of("testing_resources/dotted_rhythms.inst");
+        assert_eq!(dotted[0], vec![Beats::DottedQuarter, Beats::DottedQuarter,
+            Beats::DottedEighth, Beats::Sixteenth]);
+
+        let triplets = durations_of("testing_resources/triplets.inst");
+        assert_eq!(triplets[0], vec![Beats::EighthTriplet; 3]);
+        assert_eq!(triplets[1], vec![Beats::QuarterTriplet; 3]);
+        assert_eq!(triplets[2], vec![Beats::HalfTriplet; 3]);
+    }
+
+    #[test]
+    fn test_parser_tolerant_alignment() {
+        let ragged = "testing_resources/ragged_cmaj_scale.inst".to_string();
+        assert!(matches!(parse_file(ragged.clone()),
+            Err(ParsingError::InvalidMeasureLenghts(2))));
+
+        let options = ParseOptions { tolerant: true };
+        let (lines, diagnostics) = parse_file_with_options(ragged, &options).unwrap();
+        let clean = parse_file("testing_resources/cmaj_scale_quarternotes.inst".to_string())
+            .unwrap();
+        assert_eq!(clean, lines);
+
+        assert!(diagnostics.iter().any(|d| d.measure == 2 && d.row == 2
+            && d.message.contains("barline")));
+        assert!(diagnostics.contains(&Diagnostic {
+            measure: 6,
+            row: 0,
+            column: 0,
+            message: "rows disagree on the whitespace after the last barline, 6 of them look \
+                trimmed".to_string(),
+            suggestion: Some("turn off trailing whitespace trimming for .inst files".to_string()),
+        }));
+
+        let stem = diagnostics.iter()
+            .find(|d| d.message.contains("stem"))
+            .unwrap();
+        assert_eq!((stem.measure, stem.row, stem.column), (3, 8, 9));
+        assert!(stem.suggestion.as_ref().unwrap().contains("left"));
+    }
+
+    #[test]
+    fn test_parser_layout_from_staff_lines() {
+        let clean = parse_file("testing_resources/cmaj_scale_quarternotes.inst".to_string())
+            .unwrap();
+        let (lines, diagnostics) = parse_file_with_options(
+            "testing_resources/annotated_cmaj_scale.inst".to_string(),
+            &ParseOptions::default()).unwrap();
+
+        // The text rows become directions, the notes are the same as without them
+        for (clean_bar, bar) in clean[0].contents.iter().zip(lines[0].contents.iter()) {
+            assert_eq!(clean_bar.timed_events(), bar.timed_events());
+        }
+        let first: Vec<&str> = lines[0].contents[0].directions().iter()
+            .map(|d| d.text.as_str())
+            .collect();
+        assert_eq!(vec!["Allegro", "Do", "re", "(play", "mi", "fa"], first);
+        assert_eq!(8, lines[0].layout.center_line);
+        assert_eq!(vec![8, 22, 36, 50, 64, 65], lines[0].layout.measure_columns);
+        assert!(diagnostics.iter().any(|d| d.message.contains("Treble")));
+    }
+
+    #[test]
+    fn test_parser_bass_clef() {
+        let e2 = Note { accidental: Accidental::Natural, note_name: "E", octave: 2, rest: false };
+        let f2 = Note { accidental: Accidental::Natural, note_name: "F", octave: 2, rest: false };
+        let g2 = Note { accidental: Accidental::Natural, note_name: "G", octave: 2, rest: false };
+        let a3 = Note { accidental: Accidental::Natural, note_name: "A", octave: 3, rest: false };
+
+        let lines = parse_file("testing_resources/bass_cmaj_scale.inst".to_string()).unwrap();
+        assert!(matches!(lines[0].clef_type, StaffType::Bass));
+        assert_eq!(vec![e2, f2, g2, a3], lines[0].contents[0].pitches());
+    }
+
+    #[test]
+    fn test_parser_continuation_systems() {
+        let note = |note_name: &'static str, octave: usize, accidental: Accidental| Note {
+            accidental,
+            note_name,
+            octave,
+            rest: false,
+        };
+        let natural = |note_name: &'static str, octave: usize| {
+            note(note_name, octave, Accidental::Natural)
+        };
+
+        let lines = parse_file("testing_resources/continuation_systems.inst".to_string())
+            .unwrap();
+        let four_four = Some(TimeSignature { beats: 4, beat_size: Beats::Quarter });
+        let one_flat = KeySignature { accidentals: vec![("B", Accidental::Flat)] };
+
+        assert_eq!(3, lines.len());
+        for line in lines.iter() {
+            assert_eq!(StaffType::Treble, line.clef_type);
+            assert_eq!(one_flat, line.key_signature);
+            assert_eq!(four_four, line.time_signature);
+        }
+
+        assert_eq!(vec![natural("G", 4), natural("A", 5), note("B", 5, Accidental::Flat),
+            natural("C", 5)], lines[1].contents[1].pitches());
+
+        // The "?" in measure 14 switches the rest of the line to the bass clef
+        assert_eq!(vec![natural("C", 4), natural("D", 4), natural("E", 4), natural("F", 4)],
+            lines[2].contents[0].pitches());
+        assert_eq!(14, lines[2].contents[1].measure_number);
+        assert_eq!(vec![note("B", 3, Accidental::Flat), natural("C", 3), natural("D", 3),
+            natural("E", 3)], lines[2].contents[1].pitches());
+        assert_eq!(vec![natural("E", 3), natural("D", 3), natural("C", 3),
+            note("B", 3, Accidental::Flat)], lines[2].contents[2].pitches());
+    }
+
+    #[test]
+    fn test_parser_chords_and_repeats() {
+        let natural = |note_name: &'static str, octave: usize| Note {
+            accidental: Accidental::Natural,
+            note_name,
+            octave,
+            rest: false,
+        };
+
+        let lines = parse_file("testing_resources/chords_and_repeats.inst".to_string())
+            .unwrap();
+        let bars = &lines[0].contents;
+        // The "ll" at the end is two empty measures
+        assert_eq!(4, bars.len());
+        assert!(bars[2..].iter().all(|bar| bar.events.is_empty()));
+
+        assert_eq!(Event::Barline(Barline { kind: BarlineKind::RepeatStart, column: 0 }),
+            bars[0].events[0]);
+        assert_eq!(Event::Chord(ChordEvent {
+            pitches: vec![natural("C", 4), natural("E", 4), natural("G", 4)],
+            duration: Beats::Quarter,
+            column: 2,
+        }), bars[0].events[1]);
+
+        // The "x2" above the repeat sign is the repeat count, not a direction
+        assert_eq!(Some(&Event::Barline(Barline {
+            kind: BarlineKind::RepeatEnd { times: Some(2) },
+            column: 13,
+        })), bars[1].events.last());
+        assert!(bars[1].directions().is_empty());
+        assert_eq!(vec![natural("G", 4), natural("B", 5)], match &bars[1].events[3] {
+            Event::Chord(chord) => chord.pitches.clone(),
+            _ => Vec::new(),
+        });
+    }
 }
 }
@@ -0,0 +1,359 @@
+pub mod project {
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::bytecode::bytecode::{compile, Module};
+use crate::check::check::{CheckWarning, Checker};
+use crate::data_types::dt::*;
+use crate::disasm::disasm::{disassemble, ListingLine};
+use crate::ensemble::ensemble::{Ensemble, DEFAULT_TEMPO};
+use crate::instructions::instructions::*;
+use crate::interpreter::interpreter::{Interpreter, Limits, Machine, Program};
+use crate::parser::parser::parse_file;
+use crate::trace::trace::Tracer;
+
+/*
+ * The settings in a project's expression.toml. Anything left out keeps its
+ * default so an empty file (or no file at all) is fine. Ensemble lists the
+ * instruments that play when the project is run, by name, leaving it out
+ * plays all of them. The others can still be called. Instructions changes
+ * which pitch plays which instruction for the whole project and instruments
+ * can change it again for one instrument at a time. Limits stops every
+ * instrument that runs too long or takes too much memory.
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Expression {
+    pub mode: OpcodeMode,
+    pub tempo: usize,
+    pub ensemble: Vec<String>,
+    pub instructions: TableSpec,
+    pub instruments: BTreeMap<String, InstrumentSettings>,
+    pub limits: Limits,
+}
+
+/*
+ * The settings for a single instrument, under [instruments.name].
+ */
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct InstrumentSettings {
+    pub instructions: TableSpec,
+}
+
+impl Default for Expression {
+    fn default() -> Self {
+        Expression {
+            mode: OpcodeMode::default(),
+            tempo: DEFAULT_TEMPO,
+            ensemble: Vec::new(),
+            instructions: TableSpec::default(),
+            instruments: BTreeMap::new(),
+            limits: Limits::default(),
+        }
+    }
+}
+
+/*
+ * A piece laid out the way the README describes, an expression.toml next to
+ * an instruments folder of .inst files. The instruction tables are worked
+ * out when the project is loaded, tables holds one for every instrument.
+ */
+#[derive(Clone, Debug)]
+pub struct Project {
+    pub root: PathBuf,
+    pub expression: Expression,
+    pub instruments: Vec<PathBuf>,
+    pub table: InstructionTable,
+    pub tables: BTreeMap<String, InstructionTable>,
+}
+
+impl Expression {
+    pub fn from_toml(contents: &str) -> Result<Expression, ProjectError> {
+        toml::from_str(contents).map_err(|e| ProjectError::InvalidExpression(e.message()
+            .to_string()))
+    }
+}
+
+impl Project {
+    pub fn load(root: impl AsRef<Path>) -> Result<Project, ProjectError> {
+        let root = root.as_ref().to_path_buf();
+        let unreadable = |path: &Path| ProjectError::FailedProjectRead(path.display()
+            .to_string());
+
+        let expression_path = root.join("expression.toml");
+        let expression = if expression_path.exists() {
+            let contents = fs::read_to_string(&expression_path)
+                .map_err(|_| unreadable(&expression_path))?;
+            Expression::from_toml(&contents)?
+        } else {
+            Expression::default()
+        };
+
+        let instruments_path = root.join("instruments");
+        let mut instruments: Vec<PathBuf> = fs::read_dir(&instruments_path)
+            .map_err(|_| unreadable(&instruments_path))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "inst"))
+            .collect();
+        instruments.sort();
+
+        let table = expression.instructions.resolve(&InstructionTable::default(),
+            "instructions")?;
+        let names: Vec<String> = instruments.iter().map(|path| Self::instrument_name(path)).collect();
+        if let Some(unknown) = expression.instruments.keys().find(|name| !names.contains(name)) {
+            return Err(ProjectError::UnknownInstrument(unknown.clone()));
+        }
+        let tables = names.into_iter()
+            .map(|name| {
+                let table = match expression.instruments.get(&name) {
+                    Some(settings) => settings.instructions.resolve(&table,
+                        &format!("instruments.{}.instructions", name))?,
+                    None => table.clone(),
+                };
+                Ok((name, table))
+            })
+            .collect::<Result<_, ProjectError>>()?;
+
+        Ok(Project { root, expression, instruments, table, tables })
+    }
+
+    /*
+     * An interpreter set up the way this project asks for, playing with the
+     * table of the named instrument (the project's if there is no such
+     * instrument). Calls to other instruments play with theirs.
+     */
+    pub fn interpreter(&self, name: &str) -> Interpreter {
+        let table = self.tables.get(name).unwrap_or(&self.table).clone();
+        let mut interpreter = Interpreter::new(table, self.expression.mode)
+            .with_limits(self.expression.limits)
+            .with_file(&format!("{}.inst", name));
+        interpreter.tables = self.tables.clone();
+        interpreter
+    }
+
+    /*
+     * The name other instruments call an instrument by, its file name without
+     * the .inst.
+     */
+    pub fn instrument_name(path: &Path) -> String {
+        path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default()
+    }
+
+    /*
+     * Parses every instrument, keeping the file name each came from.
+     */
+    pub fn parse_instruments(&self) -> Result<Vec<(PathBuf, Vec<Line>)>, ProjectError> {
+        self.instruments.iter()
+            .map(|path| Ok((path.clone(), parse_file(path.display().to_string())?)))
+            .collect()
+    }
+
+    /*
+     * Whether an instrument plays when the project is run.
+     */
+    pub fn plays(&self, path: &Path) -> bool {
+        self.expression.ensemble.is_empty()
+            || self.expression.ensemble.contains(&Self::instrument_name(path))
+    }
+
+    /*
+     * Every instrument that plays, loaded into an ensemble in file name
+     * order. Each of them can call any of the others.
+     */
+    pub fn ensemble(&self) -> Result<(Ensemble, Vec<PathBuf>), ProjectError> {
+        let parsed = self.parse_instruments()?;
+        let mut ensemble = Ensemble { tempo: self.expression.tempo, ..Ensemble::default() };
+
+        let mut paths = Vec::new();
+        for (path, lines) in parsed.iter().filter(|(path, _)| self.plays(path)) {
+            let mut interpreter = self.interpreter(&Self::instrument_name(path));
+            for (other, other_lines) in parsed.iter() {
+                interpreter.add_instrument(&Self::instrument_name(other), other_lines);
+            }
+            interpreter.load(lines);
+            ensemble.add_player(&Self::instrument_name(path), interpreter);
+            paths.push(path.clone());
+        }
+
+        Ok((ensemble, paths))
+    }
+
+    /*
+     * Checks every instrument that plays, each with its own table and able to
+     * call all of the others.
+     */
+    pub fn check(&self) -> Result<Vec<CheckWarning>, ProjectError> {
+        let parsed = self.parse_instruments()?;
+        let mut warnings = Vec::new();
+        for (path, lines) in parsed.iter().filter(|(path, _)| self.plays(path)) {
+            let table = self.tables.get(&Self::instrument_name(path)).unwrap_or(&self.table);
+            let mut checker = Checker::new(table.clone(), self.expression.mode);
+            checker.tables = self.tables.clone();
+            for (other, other_lines) in parsed.iter() {
+                checker.add_instrument(&Self::instrument_name(other), &other.display().to_string(),
+                    other_lines);
+            }
+            warnings.extend(checker.check(&path.display().to_string(), lines));
+        }
+        Ok(warnings)
+    }
+
+    /*
+     * A listing of every instrument, each decoded with its own table.
+     */
+    pub fn disassemble(&self) -> Result<Vec<ListingLine>, ProjectError> {
+        Ok(self.parse_instruments()?.iter()
+            .flat_map(|(path, lines)| {
+                let table = self.tables.get(&Self::instrument_name(path)).unwrap_or(&self.table);
+                disassemble(&path.display().to_string(), lines, table, self.expression.mode)
+            })
+            .collect())
+    }
+
+    /*
+     * Compiles every instrument with its own table into one module, with the
+     * ones that play as its players.
+     */
+    pub fn compile(&self) -> Result<Module, ProjectError> {
+        let parsed = self.parse_instruments()?;
+        let mut programs = vec![(String::new(), Program::default())];
+        let mut sources = Vec::new();
+        let mut players = Vec::new();
+        for (index, (path, lines)) in parsed.iter().enumerate() {
+            let name = Self::instrument_name(path);
+            if self.plays(path) {
+                players.push(index);
+            }
+            // Named the way the ensemble names them, so errors and traces
+            // say the same thing compiled or not
+            sources.push((format!("{}.inst", name),
+                self.tables.get(&name).unwrap_or(&self.table).clone()));
+            programs.push((name, Program::from_lines(lines)));
+        }
+
+        let programs = compile(&programs, 1, &sources, self.expression.mode)?;
+        Ok(Module { tempo: self.expression.tempo, programs, players })
+    }
+
+    /*
+     * Plays the instruments together and hands back the machine each one
+     * finished with.
+     */
+    pub fn run(&self) -> Result<Vec<(PathBuf, Machine)>, ProjectError> {
+        self.run_traced(None)
+    }
+
+    /*
+     * Same as run, writing a trace of every instrument as it goes.
+     */
+    pub fn run_traced(&self, tracer: Option<&Tracer>)
+        -> Result<Vec<(PathBuf, Machine)>, ProjectError> {
+        let (mut ensemble, paths) = self.ensemble()?;
+        if let Some(tracer) = tracer {
+            ensemble.trace(tracer);
+        }
+        ensemble.run()?;
+        Ok(paths.into_iter()
+            .zip(ensemble.players.into_iter().map(|player| player.interpreter.machine))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stacks(root: &str) -> Vec<Vec<i64>> {
+        Project::load(root).unwrap().run().unwrap().into_iter()
+            .map(|(_, machine)| machine.stack)
+            .collect()
+    }
+
+    #[test]
+    fn test_project_interval_mode_survives_transposition() {
+        let project = Project::load("testing_resources/projects/interval_melody").unwrap();
+        assert_eq!(OpcodeMode::Interval, project.expression.mode);
+        assert_eq!(1, project.instruments.len());
+
+        // The octave leap at the end pushes 2
+        assert_eq!(vec![vec![4, 2]], stacks("testing_resources/projects/interval_melody"));
+        assert_eq!(vec![vec![4, 2]],
+            stacks("testing_resources/projects/interval_melody_transposed"));
+    }
+
+    #[test]
+    fn test_project_expression() {
+        assert_eq!(Expression::default(), Expression::from_toml("").unwrap());
+        assert_eq!(OpcodeMode::Absolute,
+            Expression::from_toml("mode = \"absolute\"").unwrap().mode);
+        assert!(matches!(Expression::from_toml("mode = \"relative\""),
+            Err(ProjectError::InvalidExpression(_))));
+
+        let limits = Expression::from_toml("[limits]\nevents = 1000\nmillis = 50").unwrap().limits;
+        assert_eq!(Limits { events: Some(1000), millis: Some(50), ..Limits::default() }, limits);
+        assert!(matches!(Expression::from_toml("[limits]\nloops = 3"),
+            Err(ProjectError::InvalidExpression(_))));
+    }
+
+    #[test]
+    fn test_project_calls() {
+        // main pushes 4, calls double.inst and then its own triple passage,
+        // double.inst also gets played on its own and has nothing to double
+        let project = Project::load("testing_resources/projects/calls").unwrap();
+        let lines = parse_file(project.instruments[1].display().to_string()).unwrap();
+        let mut interpreter = project.interpreter("main");
+        for (path, other) in project.parse_instruments().unwrap() {
+            interpreter.add_instrument(&Project::instrument_name(&path), &other);
+        }
+        interpreter.run(&lines).unwrap();
+
+        assert_eq!(vec![24], interpreter.machine.stack);
+        assert!(interpreter.machine.frames.is_empty());
+        // Only main is in the ensemble, double.inst is just there to be called
+        assert_eq!(vec![vec![24]], stacks("testing_resources/projects/calls"));
+        assert_eq!(Vec::<CheckWarning>::new(), project.check().unwrap());
+    }
+
+    #[test]
+    fn test_project_instruction_tables() {
+        // main pushes 4 twice and multiplies, then helper pushes 4 and subtracts
+        let project = Project::load("testing_resources/projects/custom_tables").unwrap();
+        assert_eq!(Instruction::Mul, project.table.notes[2]);
+        assert_eq!(Instruction::Sub, project.tables["helper"].notes[2]);
+        assert_eq!(vec![vec![12]], stacks("testing_resources/projects/custom_tables"));
+        let listing = project.disassemble().unwrap();
+        assert!(listing.iter().any(|line| line.file.ends_with("helper.inst") && line.text == "sub"));
+        assert!(listing.iter().any(|line| line.file.ends_with("main.inst") && line.text == "mul"));
+
+        let table = |toml: &str| Expression::from_toml(toml).unwrap().instructions
+            .resolve(&InstructionTable::default(), "instructions");
+        let shipped: TableSpec = toml::from_str(DEFAULT_TABLE).unwrap();
+        assert_eq!(InstructionTable::default(),
+            shipped.resolve(&InstructionTable::default(), "default").unwrap());
+        assert_eq!(Instruction::Halt, table("[instructions.notes]\nCb = \"halt\"").unwrap()
+            .notes[11]);
+
+        assert!(matches!(table("[instructions.notes]\n\"C#\" = \"pop\"\nDb = \"dup\""),
+            Err(ProjectError::ConflictingPitches(a, b, _)) if a == "C#" && b == "Db"));
+        assert!(matches!(table("[instructions.notes]\nH = \"pop\""),
+            Err(ProjectError::InvalidPitchName(..))));
+        assert!(matches!(table("[instructions]\nbase = \"empty\"\n[instructions.notes]\nC = \"push\""),
+            Err(ProjectError::TableGap(..))));
+        assert!(matches!(Expression::from_toml("[instructions.notes]\nC = \"jump\""),
+            Err(ProjectError::InvalidExpression(_))));
+    }
+
+    #[test]
+    fn test_project_ensemble() {
+        let project = Project::load("testing_resources/projects/duet").unwrap();
+        assert_eq!(90, project.expression.tempo);
+        assert_eq!(DEFAULT_TEMPO, Expression::default().tempo);
+
+        let (ensemble, paths) = project.ensemble().unwrap();
+        assert_eq!(2, paths.len());
+        assert_eq!(2.0, ensemble.seconds(TICKS_PER_WHOLE * 3 / 4));
+    }
+}
+}
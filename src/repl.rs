@@ -0,0 +1,345 @@
+pub mod repl {
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use regex::Regex;
+use crate::data_types::dt::*;
+use crate::engrave::engrave::{engrave, key_for};
+use crate::interpreter::interpreter::{Interpreter, Machine};
+use crate::parser::parser::parse_str;
+
+const HELP: &str = "\
+Type a measure in shorthand or paste a line of music between two rows of =.
+  C4q D#5h. E4e      notes: letter, # or b, octave, then w h q e s t and a dot
+  C4+E4+G4q r rh.    a chord and rests
+  |: ... :|x3 |      repeats and barlines, | starts a new measure
+  call:name          directions are any word with a : in it
+:staff [shorthand]   print the staff for shorthand, or for what ran last
+:stack :tape         print the machine
+:reset               start again with an empty machine
+:quit";
+
+/*
+ * Reads the shorthand for a few measures. Every measure ends with a
+ * barline, a measure that is only directions is fine.
+ */
+pub fn parse_shorthand(shorthand: &str) -> Result<Vec<Bar>, ReplError> {
+    let note = Regex::new(r"^([A-G])([#b]?)(\d+)$").unwrap();
+    let rhythm = |token: &str| -> Option<(String, Beats)> {
+        let (body, dotted) = match token.strip_suffix('.') {
+            Some(body) => (body, true),
+            None => (token, false),
+        };
+        let (body, duration) = match body.chars().last() {
+            Some(c @ ('w' | 'h' | 'q' | 'e' | 's' | 't')) => (&body[..body.len() - 1], c),
+            _ => (body, 'q'),
+        };
+        let duration = match duration {
+            'w' => Beats::Whole,
+            'h' => Beats::Half,
+            'q' => Beats::Quarter,
+            'e' => Beats::Eighth,
+            's' => Beats::Sixteenth,
+            _ => Beats::ThirtySecond,
+        };
+        let duration = if dotted { duration.dotted()? } else { duration };
+        Some((body.to_string(), duration))
+    };
+    let pitch = |text: &str| -> Option<Note> {
+        let parts = note.captures(text)?;
+        let note_name = NOTE_ORDER.iter().find(|&&name| name == &parts[1])?;
+        Some(Note {
+            accidental: match &parts[2] {
+                "#" => Accidental::Sharp,
+                "b" => Accidental::Flat,
+                _ => Accidental::Natural,
+            },
+            note_name,
+            octave: parts[3].parse().ok()?,
+            rest: false,
+        })
+    };
+
+    let mut bars = Vec::new();
+    let mut events = Vec::new();
+    let mut end_bar = |events: &mut Vec<Event>, kind: BarlineKind| {
+        events.push(Event::Barline(Barline { kind, column: 0 }));
+        bars.push(Bar { measure_number: bars.len() + 1, events: std::mem::take(events) });
+    };
+
+    for token in shorthand.split_whitespace() {
+        let invalid = || ReplError::InvalidShorthand(token.to_string());
+        match token {
+            "|:" => events.push(Event::Barline(Barline {
+                kind: BarlineKind::RepeatStart,
+                column: 0,
+            })),
+            // A repeat already closed the measure
+            "|" | "||" if events.is_empty() => {},
+            "|" => end_bar(&mut events, BarlineKind::Single),
+            "||" => end_bar(&mut events, BarlineKind::Final),
+            _ if token.starts_with(":|") => {
+                let times = match &token[2..] {
+                    "" => None,
+                    count => Some(count.strip_prefix('x').and_then(|n| n.parse().ok())
+                        .ok_or_else(invalid)?),
+                };
+                end_bar(&mut events, BarlineKind::RepeatEnd { times });
+            },
+            _ if token.contains(':') => events.push(Event::Direction(Direction {
+                text: token.to_string(),
+                column: 0,
+            })),
+            _ if token.starts_with('r') => {
+                let (body, duration) = rhythm(&token[1..]).ok_or_else(invalid)?;
+                if !body.is_empty() {
+                    return Err(invalid());
+                }
+                events.push(Event::Rest(RestEvent { duration, column: 0 }));
+            },
+            _ => {
+                let (body, duration) = rhythm(token).ok_or_else(invalid)?;
+                let mut pitches = body.split('+')
+                    .map(pitch)
+                    .collect::<Option<Vec<Note>>>()
+                    .ok_or_else(invalid)?;
+                events.push(if pitches.len() == 1 {
+                    Event::Note(NoteEvent { pitch: pitches.remove(0), duration, column: 0 })
+                } else {
+                    pitches.sort_by_key(|p| p.octave * 7 + NOTE_ORDER.iter()
+                        .position(|&name| name == p.note_name).unwrap_or(0));
+                    Event::Chord(ChordEvent { pitches, duration, column: 0 })
+                });
+            },
+        }
+    }
+
+    if !events.is_empty() {
+        end_bar(&mut events, BarlineKind::Single);
+    }
+    Ok(bars)
+}
+
+/*
+ * The staff that shorthand stands for, ready to be parsed or printed. It is
+ * always on the treble clef with whatever key signature the notes need.
+ */
+pub fn translate(shorthand: &str) -> Result<String, ReplError> {
+    let contents = parse_shorthand(shorthand)?;
+    let line = Line {
+        clef_type: StaffType::Treble,
+        key_signature: key_for(&contents)?,
+        time_signature: None,
+        layout: Layout::default(),
+        contents,
+    };
+    Ok(engrave(&[line])?)
+}
+
+/*
+ * Remembers whether the last thing written ended a line so the REPL can
+ * start its own output on a fresh one.
+ */
+struct LineTracker {
+    inner: Box<dyn Write>,
+    fresh_line: Rc<Cell<bool>>,
+}
+
+impl Write for LineTracker {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if written > 0 {
+            self.fresh_line.set(buf[written - 1] == b'\n');
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/*
+ * Runs music as it is typed in, one measure (or a few) at a time, on a
+ * machine that keeps its stack and tape between inputs. Each input is played
+ * as a piece of its own so labels and repeats only reach within it.
+ */
+#[derive(Debug)]
+pub struct Repl {
+    pub interpreter: Interpreter,
+    last: Vec<Line>,
+    fresh_line: Rc<Cell<bool>>,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new(Interpreter::default())
+    }
+}
+
+impl Repl {
+    pub fn new(mut interpreter: Interpreter) -> Self {
+        let fresh_line = Rc::new(Cell::new(true));
+        let output = std::mem::replace(&mut interpreter.streams.output, Box::new(io::sink()));
+        interpreter.streams.output = Box::new(LineTracker {
+            inner: output,
+            fresh_line: fresh_line.clone(),
+        });
+
+        Repl {
+            interpreter,
+            last: Vec::new(),
+            fresh_line,
+        }
+    }
+
+    /*
+     * What the stack (and the tape, once something is on it) look like.
+     */
+    pub fn state(&self) -> String {
+        let machine = &self.interpreter.machine;
+        if machine.tape.is_empty() {
+            format!("stack {:?}", machine.stack)
+        } else {
+            format!("stack {:?} tape {:?}", machine.stack, machine.tape)
+        }
+    }
+
+    /*
+     * Plays shorthand or a pasted line of music and says what the machine
+     * looks like afterwards.
+     */
+    pub fn eval(&mut self, input: &str) -> Result<String, ReplError> {
+        let lines = if input.trim_start().starts_with('=') {
+            parse_str(input)?
+        } else {
+            parse_str(&translate(input)?)?
+        };
+
+        self.last = lines.clone();
+        self.interpreter.machine.halted = false;
+        self.interpreter.run(&lines)?;
+        Ok(self.state())
+    }
+
+    /*
+     * Runs a REPL command (anything starting with a : and a letter) or plays
+     * the input.
+     */
+    pub fn execute(&mut self, input: &str) -> Result<String, ReplError> {
+        let trimmed = input.trim();
+        if !trimmed.starts_with(':') || !trimmed[1..].starts_with(char::is_alphabetic) {
+            return self.eval(input);
+        }
+
+        let (command, argument) = trimmed.split_once(' ').unwrap_or((trimmed, ""));
+        Ok(match command {
+            ":staff" if argument.trim().is_empty() => engrave(&self.last)?,
+            ":staff" => translate(argument)?,
+            ":stack" => format!("{:?}", self.interpreter.machine.stack),
+            ":tape" => format!("{:?}", self.interpreter.machine.tape),
+            ":reset" => {
+                let limits = self.interpreter.machine.limits;
+                self.interpreter.machine = Machine { limits, ..Machine::default() };
+                self.state()
+            },
+            ":help" => HELP.to_string(),
+            _ => return Err(ReplError::UnknownCommand(command.to_string())),
+        }.trim_end().to_string())
+    }
+
+    fn say(&mut self, text: &str) -> io::Result<()> {
+        let output = &mut self.interpreter.streams.output;
+        if !self.fresh_line.get() {
+            writeln!(output)?;
+        }
+        writeln!(output, "{}", text)
+    }
+
+    /*
+     * Reads inputs from the piece's input until it runs out or ":quit" is
+     * read. A row starting with = begins a pasted line of music, which goes
+     * on up to the next row starting with =.
+     */
+    pub fn interact(&mut self) -> io::Result<()> {
+        loop {
+            write!(self.interpreter.streams.output, "> ")?;
+            self.interpreter.streams.output.flush()?;
+            let Some(mut input) = self.interpreter.streams.read_line()? else { break };
+            if input.starts_with('=') {
+                while let Some(row) = self.interpreter.streams.read_line()? {
+                    input.push('\n');
+                    input.push_str(&row);
+                    if row.starts_with('=') {
+                        break;
+                    }
+                }
+            }
+            self.fresh_line.set(true);
+
+            if matches!(input.trim(), ":quit" | ":q") {
+                break;
+            }
+            let reply = match self.execute(&input) {
+                Ok(reply) => reply,
+                Err(e) => e.to_string(),
+            };
+            if !reply.is_empty() {
+                self.say(&reply)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streams::streams::{SharedOutput, Streams};
+
+    #[test]
+    fn test_repl_shorthand() {
+        let score = translate("|: C4q F#4e. r E4+G4+C5h :|x2 | call:twice B5w").unwrap();
+        let lines = parse_str(&score).unwrap();
+        let bars = &lines[0].contents;
+        assert_eq!(4, bars.len());
+        assert_eq!(vec![Beats::Quarter, Beats::DottedEighth, Beats::Quarter, Beats::Half],
+            bars[0].durations());
+        assert_eq!(Accidental::Sharp, bars[0].pitches()[1].accidental);
+        assert!(matches!(bars[0].events[4], Event::Chord(_)));
+        assert!(matches!(bars[0].events.last(), Some(Event::Barline(Barline {
+            kind: BarlineKind::RepeatEnd { times: Some(2) }, .. }))));
+        assert_eq!(Some("twice"), bars[1].directions()[0].marker("call"));
+
+        assert!(matches!(translate("C4q H4q"), Err(ReplError::InvalidShorthand(t)) if t == "H4q"));
+        assert!(matches!(translate("F4q F#4q"), Err(ReplError::Engrave(_))));
+    }
+
+    #[test]
+    fn test_repl_keeps_state() {
+        let mut repl = Repl::default();
+        assert_eq!("stack [4, 4]", repl.eval("C4q C4q").unwrap());
+        assert_eq!("stack [8]", repl.execute("D4q").unwrap());
+        // Stores the 8 at address 4
+        assert_eq!("stack [] tape {4: 8}", repl.eval("C4q A#5q").unwrap());
+
+        let pasted = std::fs::read_to_string("testing_resources/arithmetic.inst").unwrap();
+        assert_eq!("stack [16] tape {4: 8}", repl.eval(&pasted).unwrap());
+        assert!(repl.execute(":staff").unwrap().contains("O"));
+        assert_eq!("stack []", repl.execute(":reset").unwrap());
+        assert!(matches!(repl.execute(":jump"), Err(ReplError::UnknownCommand(_))));
+    }
+
+    #[test]
+    fn test_repl_interact() {
+        let output = SharedOutput::new();
+        let pasted = std::fs::read_to_string("testing_resources/arithmetic.inst").unwrap();
+        let input = format!("C4q C4q D4q C4+E4q\n{}:stack\n:quit\nC4q\n", pasted);
+        let mut repl = Repl::new(Interpreter::default()
+            .with_streams(Streams::new(io::Cursor::new(input), output.clone())));
+        repl.interact().unwrap();
+
+        assert_eq!("> 8\nstack []\n> stack [16]\n> [16]\n> ", output.text());
+    }
+}
+}
@@ -0,0 +1,215 @@
+pub mod staff {
+use crate::data_types::dt::*;
+
+/*
+ * Splits every row of a line of music into its measures. Index 0 of each row
+ * is whatever sits left of the first barline (usually the clef).
+ */
+fn measure_cells(line: &[String]) -> Vec<Vec<Vec<char>>> {
+    line.iter()
+        .map(|row| row.split('l').map(|m| m.chars().collect()).collect())
+        .collect()
+}
+
+fn cell(cells: &[Vec<Vec<char>>], row: usize, measure: usize, col: usize) -> char {
+    cells.get(row)
+        .and_then(|r| r.get(measure))
+        .and_then(|m| m.get(col))
+        .copied()
+        .unwrap_or(' ')
+}
+
+/*
+ * A row counts as a staff line when most of what sits between its barlines is
+ * drawn with "-". Notes and stems crossing the line are fine.
+ */
+fn is_line_row(row: &str) -> bool {
+    let body: String = row.split('l').skip(1).collect();
+    let dashes = body.chars().filter(|&c| c == '-').count();
+    let spaces = body.chars().filter(|&c| c == ' ').count();
+
+    dashes > 0 && dashes > spaces && dashes * 3 >= body.chars().count()
+}
+
+/*
+ * Finds the rows of the five staff lines. When more rows look like lines (a
+ * ledger line drawn all the way across for example) the five evenly spaced
+ * rows are picked. Returns an empty vec when there is no staff to be found.
+ */
+pub fn find_staff_lines(line: &[String]) -> Vec<usize> {
+    let candidates: Vec<usize> = line.iter()
+        .enumerate()
+        .filter(|(_, row)| is_line_row(row))
+        .map(|(idx, _)| idx)
+        .collect();
+
+    for (i, &top) in candidates.iter().enumerate() {
+        let staff: Vec<usize> = (0..5).map(|n| top + 2 * n).collect();
+        if staff.iter().all(|row| candidates[i..].contains(row)) {
+            return staff;
+        }
+    }
+
+    candidates
+}
+
+/*
+ * Checks that the five staff lines are drawn as unbroken runs of "-" through
+ * every measure with a row of space between each of them. Anything out of
+ * place is reported with the measure and the column inside that measure.
+ */
+pub fn check_staff_lines(line: &[String], measure_count: usize) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    let lines = find_staff_lines(line);
+
+    if lines.len() != 5 || lines.windows(2).any(|w| w[1] - w[0] != 2) {
+        diagnostics.push(Diagnostic {
+            measure: measure_count,
+            row: lines.first().copied().unwrap_or(0),
+            column: 0,
+            message: format!("found {} staff lines on rows {:?}, expected 5 with a space \
+                between each", lines.len(), lines),
+            suggestion: Some("draw the staff as five rows of '-' separated by blank rows"
+                .to_string()),
+        });
+        return diagnostics;
+    }
+
+    let cells = measure_cells(line);
+    let measures = cells.iter().map(|row| row.len()).max().unwrap_or(1);
+    let spaces: Vec<usize> = (lines[0] + 1..lines[4]).step_by(2).collect();
+
+    for measure in 1..measures {
+        let width = cells.iter()
+            .map(|row| row.get(measure).map_or(0, |m| m.len()))
+            .max()
+            .unwrap_or(0);
+        let mut moved: Vec<usize> = Vec::new();
+
+        for &row in lines.iter() {
+            let mut col = 0;
+            while col < width {
+                if cell(&cells, row, measure, col) != ' ' || !drawn_elsewhere(&cells, &lines,
+                    row, measure, col) {
+                    col += 1;
+                    continue;
+                }
+
+                let start = col;
+                while col < width && cell(&cells, row, measure, col) == ' '
+                    && drawn_elsewhere(&cells, &lines, row, measure, col) {
+                    col += 1;
+                }
+
+                // A big enough gap with dashes right above or below it is a line
+                // that ended up on the wrong row
+                let neighbour = [row.checked_sub(1), Some(row + 1)].into_iter().flatten().find(|&r| {
+                    col - start >= 3 && (start..col).all(|c| cell(&cells, r, measure, c) == '-')
+                });
+
+                diagnostics.push(match neighbour {
+                    Some(wrong) => {
+                        moved.push(wrong);
+                        Diagnostic {
+                            measure: measure_count + measure - 1,
+                            row: wrong,
+                            column: start,
+                            message: format!("staff line drawn on row {} instead of row {}",
+                                wrong, row),
+                            suggestion: Some(format!("move the '-' run at columns {}-{} from \
+                                row {} to row {}", start, col - 1, wrong, row)),
+                        }
+                    },
+                    None => gap_diagnostic(&cells, measure_count, measure, row, start, col),
+                });
+            }
+        }
+
+        // Dashes in a space that are not a misplaced line we already reported
+        for &row in spaces.iter().filter(|r| !moved.contains(r)) {
+            let run_start = (0..width).find(|&c| {
+                (c..c + 3).all(|c| cell(&cells, row, measure, c) == '-')
+            });
+
+            if let Some(start) = run_start {
+                diagnostics.push(Diagnostic {
+                    measure: measure_count + measure - 1,
+                    row,
+                    column: start,
+                    message: format!("'-' run in the space between staff lines on row {}", row),
+                    suggestion: Some("replace the '-' characters with spaces".to_string()),
+                });
+            }
+        }
+    }
+
+    diagnostics
+}
+
+/*
+ * A blank on a staff line is only a gap if the other lines are drawn in that
+ * column, otherwise it is just margin around the staff.
+ */
+fn drawn_elsewhere(cells: &[Vec<Vec<char>>], lines: &[usize], row: usize, measure: usize,
+    col: usize) -> bool {
+    let drawn = lines.iter()
+        .filter(|&&r| r != row && cell(cells, r, measure, col) != ' ')
+        .count();
+
+    drawn * 2 >= lines.len() - 1
+}
+
+fn gap_diagnostic(cells: &[Vec<Vec<char>>], measure_count: usize, measure: usize, row: usize,
+    start: usize, end: usize) -> Diagnostic {
+    let head = (start.saturating_sub(1)..=end)
+        .find(|&c| matches!(cell(cells, row, measure, c), '@' | 'O'));
+
+    let message = match head {
+        Some(c) => format!("'-' missing next to the note head at column {}", c),
+        None if end - start == 1 => format!("gap in the staff line at column {}", start),
+        None => format!("gap in the staff line at columns {}-{}", start, end - 1),
+    };
+
+    Diagnostic {
+        measure: measure_count + measure - 1,
+        row,
+        column: start,
+        message,
+        suggestion: Some(format!("fill columns {}-{} of row {} with '-'", start, end - 1, row)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(text: &str) -> Vec<String> {
+        text.lines().map(|row| row.to_string()).collect()
+    }
+
+    #[test]
+    fn test_staff_clean_staff() {
+        let line = rows(include_str!("../testing_resources/cmaj_scale_quarternotes.inst"));
+        let line = line[1..line.len() - 1].to_vec();
+
+        assert_eq!(vec![1, 3, 5, 7, 9], find_staff_lines(&line));
+        assert_eq!(Vec::<Diagnostic>::new(), check_staff_lines(&line, 1));
+    }
+
+    #[test]
+    fn test_staff_broken_lines() {
+        let line = rows(include_str!("../testing_resources/broken_staff.inst"));
+        let line = line[1..line.len() - 1].to_vec();
+        let diagnostics = check_staff_lines(&line, 1);
+
+        let gap = diagnostics.iter().find(|d| d.message.contains("note head")).unwrap();
+        assert_eq!((gap.measure, gap.row, gap.column), (1, 9, 6));
+
+        let moved = diagnostics.iter().find(|d| d.message.contains("instead")).unwrap();
+        assert_eq!((moved.measure, moved.row), (4, 2));
+        assert!(moved.suggestion.as_ref().unwrap().contains("to row 1"));
+
+        assert_eq!(2, diagnostics.len());
+    }
+}
+}
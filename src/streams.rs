@@ -0,0 +1,138 @@
+pub mod streams {
+use std::cell::RefCell;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::rc::Rc;
+
+/*
+ * Where a piece reads its input from and writes its output to. By default
+ * that is stdin and stdout but anything that reads or writes bytes will do,
+ * which is how tests feed a piece from a string.
+ */
+pub struct Streams {
+    pub input: Box<dyn BufRead>,
+    pub output: Box<dyn Write>,
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        Streams {
+            input: Box::new(BufReader::new(io::stdin())),
+            output: Box::new(io::stdout()),
+        }
+    }
+}
+
+impl std::fmt::Debug for Streams {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Streams")
+    }
+}
+
+impl Streams {
+    pub fn new(input: impl BufRead + 'static, output: impl Write + 'static) -> Self {
+        Streams {
+            input: Box::new(input),
+            output: Box::new(output),
+        }
+    }
+
+    /*
+     * Reads the next byte or None at the end of the input.
+     */
+    pub fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0u8];
+        match self.input.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /*
+     * Reads one line without its line ending, None at the end of the input.
+     */
+    pub fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let trimmed = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(trimmed);
+        Ok(Some(line))
+    }
+
+    /*
+     * Skips whitespace and reads the word after it, None at the end of the
+     * input.
+     */
+    pub fn read_word(&mut self) -> io::Result<Option<String>> {
+        let mut word = String::new();
+        loop {
+            let buffer = self.input.fill_buf()?;
+            if buffer.is_empty() {
+                break;
+            }
+
+            let byte = buffer[0];
+            if byte.is_ascii_whitespace() {
+                self.input.consume(1);
+                if word.is_empty() { continue; }
+                break;
+            }
+
+            word.push(byte as char);
+            self.input.consume(1);
+        }
+
+        Ok(if word.is_empty() { None } else { Some(word) })
+    }
+}
+
+/*
+ * An output buffer that can be handed to Streams and still read afterwards.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl SharedOutput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_streams_reading() {
+        let mut streams = Streams::new("  12 -3\nsecond line\r\nx".as_bytes(), io::sink());
+        assert_eq!(Some("12".to_string()), streams.read_word().unwrap());
+        assert_eq!(Some("-3".to_string()), streams.read_word().unwrap());
+        assert_eq!(Some("second line".to_string()), streams.read_line().unwrap());
+        assert_eq!(Some(b'x'), streams.read_byte().unwrap());
+        assert_eq!(None, streams.read_byte().unwrap());
+        assert_eq!(None, streams.read_word().unwrap());
+        assert_eq!(None, streams.read_line().unwrap());
+    }
+}
+}
@@ -0,0 +1,214 @@
+pub mod trace {
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use serde::{Deserialize, Serialize};
+use crate::data_types::dt::*;
+use crate::instructions::instructions::Operation;
+
+/*
+ * One event that was played. A trace is one of these per line as JSON, in
+ * the order the events played, with the fields always in this order and
+ * always present (null when they don't apply):
+ *
+ *   file          the .inst file the event is in
+ *   measure       the measure number printed on the score
+ *   column        the column of the event, counted from the start of its measure
+ *   event         "note", "chord", "rest", "barline" or "direction", compiled
+ *                 files write the event each op came from (see Origin)
+ *   pitch         "C#4", chords list every note low to high "C4+E4+G4"
+ *   duration      the length of the event in ticks, 192 to a whole note
+ *   text          what a direction says
+ *   instruction   the instruction the event decoded to, "Push"
+ *   operand       the number it was decoded with
+ *   stack_before  the stack before the event, bottom first
+ *   stack_after   the stack after it
+ *   time          the musical time the event started at in ticks
+ *
+ * A "recv:" that has to wait is only written once it receives something.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    pub file: String,
+    pub measure: usize,
+    pub column: usize,
+    pub event: String,
+    pub pitch: Option<String>,
+    pub duration: Option<usize>,
+    pub text: Option<String>,
+    pub instruction: Option<String>,
+    pub operand: Option<i64>,
+    pub stack_before: Vec<i64>,
+    pub stack_after: Vec<i64>,
+    pub time: usize,
+}
+
+impl TraceRecord {
+    pub fn new(file: &str, measure: usize, event: &Event, operation: Option<Operation>) -> Self {
+        let (kind, text) = match event {
+            Event::Note(_) => ("note", None),
+            Event::Chord(_) => ("chord", None),
+            Event::Rest(_) => ("rest", None),
+            Event::Barline(_) => ("barline", None),
+            Event::Direction(direction) => ("direction", Some(direction.text.clone())),
+            Event::Tuplet(_) => ("tuplet", None),
+        };
+
+        TraceRecord {
+            file: file.to_string(),
+            measure,
+            column: event.column(),
+            event: kind.to_string(),
+            pitch: pitch_of(event),
+            duration: event.duration().map(|duration| duration.ticks()),
+            text,
+            instruction: operation.map(|o| format!("{:?}", o.instruction)),
+            operand: operation.map(|o| o.operand),
+            stack_before: Vec::new(),
+            stack_after: Vec::new(),
+            time: 0,
+        }
+    }
+}
+
+/*
+ * How a pitch is written in a trace, the note name, # or b and the octave.
+ */
+pub fn pitch_name(note: &Note) -> String {
+    let accidental = match note.accidental {
+        Accidental::Sharp => "#",
+        Accidental::Flat => "b",
+        Accidental::Natural => "",
+    };
+    format!("{}{}{}", note.note_name, accidental, note.octave)
+}
+
+/*
+ * The pitch a trace shows for an event, chords list every note low to high.
+ */
+pub fn pitch_of(event: &Event) -> Option<String> {
+    match event {
+        Event::Note(note) => Some(pitch_name(&note.pitch)),
+        Event::Chord(chord) => Some(chord.pitches.iter()
+            .map(pitch_name)
+            .collect::<Vec<_>>()
+            .join("+")),
+        _ => None,
+    }
+}
+
+/*
+ * Reads a trace back, skipping blank lines.
+ */
+pub fn read_trace(text: &str) -> Result<Vec<TraceRecord>, serde_json::Error> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect()
+}
+
+/*
+ * Writes trace records for an interpreter. Clones made with for_file write
+ * to the same output, which is how every player in an ensemble ends up in
+ * one trace. Each record goes out in a single write so a trace is complete
+ * up to the event that failed even if the program stops there.
+ */
+#[derive(Clone)]
+pub struct Tracer {
+    output: Rc<RefCell<Box<dyn Write>>>,
+    // The file of the piece loaded into the interpreter, called instruments
+    // are written as their name plus .inst
+    pub file: String,
+}
+
+impl std::fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Tracer({})", self.file)
+    }
+}
+
+impl Tracer {
+    pub fn new(output: impl Write + 'static, file: &str) -> Self {
+        Tracer {
+            output: Rc::new(RefCell::new(Box::new(output))),
+            file: file.to_string(),
+        }
+    }
+
+    pub fn for_file(&self, file: &str) -> Self {
+        Tracer {
+            output: self.output.clone(),
+            file: file.to_string(),
+        }
+    }
+
+    pub fn record(&self, record: &TraceRecord) -> io::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        self.output.borrow_mut().write_all(line.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::interpreter::Interpreter;
+    use crate::parser::parser::parse_file;
+    use crate::streams::streams::SharedOutput;
+
+    fn trace(file: &str) -> (String, Vec<TraceRecord>) {
+        let output = SharedOutput::new();
+        let mut interpreter = Interpreter::default()
+            .with_tracer(Tracer::new(output.clone(), "piece.inst"));
+        interpreter.run(&parse_file(file.to_string()).unwrap()).unwrap();
+        let records = read_trace(&output.text()).unwrap();
+        (output.text(), records)
+    }
+
+    #[test]
+    fn test_trace_records() {
+        let (text, records) = trace("testing_resources/arithmetic.inst");
+        assert_eq!(text.lines().count(), records.len());
+        assert!(text.lines().next().unwrap().starts_with(concat!(
+            r#"{"file":"piece.inst","measure":1,"column":1,"event":"note","pitch":"C4","#,
+            r#""duration":48,"text":null,"instruction":"Push","operand":4,"#,
+            r#""stack_before":[],"stack_after":[4],"time":0}"#)));
+
+        // Every step picks up where the last left off
+        for (before, after) in records.iter().zip(records.iter().skip(1)) {
+            assert_eq!(before.stack_after, after.stack_before);
+            assert_eq!(before.time + before.duration.unwrap_or(0), after.time);
+        }
+        let last = records.iter().rev().find(|r| r.instruction.is_some()).unwrap();
+        assert_eq!(vec![16], last.stack_after);
+        assert_eq!("barline", records.last().unwrap().event);
+    }
+
+    #[test]
+    fn test_trace_project() {
+        use crate::project::project::Project;
+
+        let output = SharedOutput::new();
+        let project = Project::load("testing_resources/projects/duet").unwrap();
+        let (mut ensemble, _) = project.ensemble().unwrap();
+        ensemble.streams.output = Box::new(io::sink());
+        ensemble.trace(&Tracer::new(output.clone(), "unused"));
+        ensemble.run().unwrap();
+
+        let records = read_trace(&output.text()).unwrap();
+        assert_eq!("b_sender.inst", records[0].file);
+        // The receiver waited, but its recv only shows up once it got the value
+        let received: Vec<_> = records.iter()
+            .filter(|r| r.text.as_deref() == Some("recv:x"))
+            .collect();
+        assert_eq!(1, received.len());
+        assert_eq!("a_receiver.inst", received[0].file);
+    }
+
+    #[test]
+    fn test_trace_is_repeatable() {
+        assert_eq!(trace("testing_resources/tape_and_repeats.inst").0,
+            trace("testing_resources/tape_and_repeats.inst").0);
+    }
+}
+}
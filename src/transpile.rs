@@ -0,0 +1,211 @@
+pub mod transpile {
+use std::fmt::Write;
+use crate::bytecode::bytecode::{CompiledProgram, Module, Op};
+use crate::instructions::instructions::Instruction;
+use crate::interpreter::interpreter::DEFAULT_CALL_DEPTH;
+
+// Everything a transpiled piece needs besides its own code
+const RUNTIME: &str = include_str!("transpiled_runtime.rs");
+
+/*
+ * The Rust that plays one instruction on the thread's machine, the runtime
+ * names its methods after the instructions.
+ */
+fn instruction_code(instruction: Instruction, operand: i64, measure: usize, skip_to: usize)
+    -> String {
+    let name = match instruction.name() {
+        "mod" => "r#mod",
+        name => name,
+    };
+    match instruction {
+        Instruction::Nop => String::new(),
+        Instruction::Push => format!("t.machine.push({});", operand),
+        Instruction::Halt => "t.machine.halt();".to_string(),
+        Instruction::SkipBar => {
+            format!("if t.machine.skip_bar({})? {{ t.pc = {}; }}", measure, skip_to)
+        },
+        Instruction::ReadByte | Instruction::ReadNumber | Instruction::ReadLine
+            | Instruction::WriteChar | Instruction::WriteNumber => {
+            format!("t.machine.{}({}, io)?;", name, measure)
+        },
+        _ => format!("t.machine.{}({})?;", name, measure),
+    }
+}
+
+/*
+ * One op as the body of its match arm. Pc is where the op is, the thread has
+ * already moved past it.
+ */
+fn op_code(program: &CompiledProgram, pc: usize, op: &Op) -> String {
+    let measure = program.measure_number(pc);
+    let clock = |ticks: usize| format!("t.tick({});", ticks);
+    match op {
+        Op::Play { operation, ticks } => {
+            let code = instruction_code(operation.instruction, operation.operand, measure,
+                program.skip_target(pc));
+            format!("{} {}", code, clock(*ticks)).trim_start().to_string()
+        },
+        Op::Interval { semitone, chord, ticks } => format!("t.interval({}, {}, {}, {}, io)?; {}",
+            semitone, chord, measure, program.skip_target(pc), clock(*ticks)),
+        Op::Wait { ticks } => clock(*ticks),
+        Op::Repeat { times, target } => format!("t.repeat({}, {}, {});", pc, times, target),
+        Op::RepeatWhile { target } => {
+            format!("if t.machine.take({})? != 0 {{ t.pc = {}; }}", measure, target)
+        },
+        Op::Call { program, target } => format!("t.call({}, {}, {})?;", program, target, measure),
+        Op::Return => "t.ret();".to_string(),
+        Op::Send(channel) => format!("t.send({:?}, {}, channels)?;", channel, measure),
+        Op::Recv(channel) => format!("t.recv({}, {:?}, channels);", pc, channel),
+    }
+}
+
+// What an op was in the score, for the comment next to it
+fn describe(op: &Op) -> String {
+    match op {
+        Op::Play { operation, .. } if operation.instruction == Instruction::Push => {
+            format!("push {}", operation.operand)
+        },
+        Op::Play { operation, .. } => operation.instruction.name().to_string(),
+        Op::Repeat { times, .. } => format!(":|x{}", times),
+        Op::RepeatWhile { .. } => ":|".to_string(),
+        Op::Send(channel) => format!("send:{}", channel),
+        Op::Recv(channel) => format!("recv:{}", channel),
+        op => op.name().to_string(),
+    }
+}
+
+/*
+ * A compiled module as a Rust program of its own that needs nothing but std,
+ * so `rustc -O piece.rs` gives a binary that plays the piece. Each program
+ * becomes a function with an arm for every op, grouped under a comment saying
+ * which measure of which file it came from. It reads stdin and writes stdout
+ * like the interpreter does and fails the same way, with the message on
+ * stderr and exit code 1.
+ */
+pub fn transpile(module: &Module) -> String {
+    let mut out = String::new();
+    let files: Vec<&str> = module.programs.iter()
+        .map(|program| program.file.as_str())
+        .filter(|file| !file.is_empty())
+        .collect();
+    let _ = writeln!(out, "// Transpiled by instrument-lang from {}", files.join(", "));
+    let _ = writeln!(out, "#![allow(unused)]\n");
+    out.push_str(RUNTIME);
+
+    let table = |slots: &[Instruction; 12]| format!("{:?}",
+        slots.iter().map(|instruction| instruction.code()).collect::<Vec<u8>>());
+    let count = module.programs.len();
+    let _ = writeln!(out, "\nconst MAX_CALL_DEPTH: usize = {};", DEFAULT_CALL_DEPTH);
+    let _ = writeln!(out, "const PLAYERS: [usize; {}] = {:?};", module.players.len(),
+        module.players);
+    let _ = writeln!(out, "const CODE_LENGTHS: [usize; {}] = {:?};", count,
+        module.programs.iter().map(|program| program.code.len()).collect::<Vec<_>>());
+    let _ = writeln!(out, "const NOTES: [[u8; 12]; {}] = [{}];", count,
+        module.programs.iter().map(|p| table(&p.table.notes)).collect::<Vec<_>>().join(", "));
+    let _ = writeln!(out, "const CHORDS: [[u8; 12]; {}] = [{}];", count,
+        module.programs.iter().map(|p| table(&p.table.chords)).collect::<Vec<_>>().join(", "));
+
+    let _ = writeln!(out, "\nfn step(t: &mut Thread, channels: &mut Channels, io: &mut Io) \
+        -> Result<(), String> {{");
+    let _ = writeln!(out, "    match t.program {{");
+    for index in 0..count {
+        let _ = writeln!(out, "        {} => program_{}(t, channels, io),", index, index);
+    }
+    let _ = writeln!(out, "        _ => unreachable!(),\n    }}\n}}");
+
+    for (index, program) in module.programs.iter().enumerate() {
+        let _ = writeln!(out, "\n// {} ({})", program.name, match program.file.as_str() {
+            "" => "nothing to play",
+            file => file,
+        });
+        let _ = writeln!(out, "fn program_{}(t: &mut Thread, channels: &mut Channels, \
+            io: &mut Io) -> Result<(), String> {{", index);
+        let _ = writeln!(out, "    let pc = t.pc;\n    t.pc += 1;\n    match pc {{");
+        let mut measure = None;
+        for (pc, op) in program.code.iter().enumerate() {
+            let number = program.measure_number(pc);
+            if measure != Some(number) {
+                let _ = writeln!(out, "        // {} measure {}", program.file, number);
+                measure = Some(number);
+            }
+            let _ = writeln!(out, "        {} => {{ {} }}, // {}", pc, op_code(program, pc, op),
+                describe(op));
+        }
+        let _ = writeln!(out, "        _ => unreachable!(),\n    }}\n    Ok(())\n}}");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Command, Stdio};
+    use std::io::Write as _;
+    use crate::bytecode::bytecode::compile_file;
+    use crate::interpreter::interpreter::Interpreter;
+    use crate::parser::parser::parse_file;
+    use crate::project::project::Project;
+    use crate::streams::streams::{SharedOutput, Streams};
+
+    // Builds the Rust with rustc and runs it, giving back stdout and stderr
+    fn build_and_run(name: &str, source: &str, input: &str) -> (String, String) {
+        let dir = std::env::temp_dir().join(format!("instrument-lang-{}-{}", name,
+            std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (file, binary) = (dir.join("piece.rs"), dir.join("piece"));
+        std::fs::write(&file, source).unwrap();
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+        let built = Command::new(rustc)
+            .arg("-o")
+            .arg(&binary)
+            .arg(&file)
+            .output()
+            .unwrap();
+        assert!(built.status.success(), "{}", String::from_utf8_lossy(&built.stderr));
+
+        let mut child = Command::new(&binary)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+        let ran = child.wait_with_output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        (String::from_utf8_lossy(&ran.stdout).into_owned(),
+            String::from_utf8_lossy(&ran.stderr).into_owned())
+    }
+
+    #[test]
+    fn test_transpiled_pieces_play_like_the_interpreter() {
+        for (file, input) in [("echo.inst", "21\nAB"), ("hello.inst", ""),
+            ("recursion.inst", ""), ("skip_and_halt.inst", ""), ("tape_and_repeats.inst", "")] {
+            let path = format!("testing_resources/{}", file);
+            let lines = parse_file(path.clone()).unwrap();
+            let output = SharedOutput::new();
+            let mut interpreter = Interpreter::default()
+                .with_streams(Streams::new(input.as_bytes(), output.clone()));
+            let error = interpreter.run(&lines).err()
+                .map_or(String::new(), |failure| format!("{}\n", failure.error));
+
+            let source = transpile(&compile_file(&path, &lines).unwrap());
+            assert!(source.contains(&format!("// {} measure 1", path)));
+            assert_eq!((output.text(), error), build_and_run(file, &source, input), "{}", file);
+        }
+    }
+
+    #[test]
+    fn test_transpiled_projects() {
+        for root in ["duet", "interval_melody"] {
+            let project = Project::load(format!("testing_resources/projects/{}", root)).unwrap();
+            let (mut ensemble, _) = project.ensemble().unwrap();
+            let output = SharedOutput::new();
+            ensemble.streams = Streams::new(std::io::empty(), output.clone());
+            ensemble.run().unwrap();
+
+            let source = transpile(&project.compile().unwrap());
+            assert_eq!((output.text(), String::new()), build_and_run(root, &source, ""), "{}", root);
+        }
+    }
+}
+}
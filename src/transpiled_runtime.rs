@@ -0,0 +1,427 @@
+// The machine a transpiled piece runs on. This file isn't part of the crate,
+// transpile pastes it into every file it writes ahead of the code for the
+// score, so it can only use std. It plays the same way as the VM in vm.rs
+// with the instructions from Machine::execute in interpreter.rs, and errors
+// read the same as a RuntimeError.
+
+use std::collections::{BTreeMap, VecDeque};
+// Plain rustc builds for the 2015 edition, which doesn't have this in scope
+use std::convert::TryFrom;
+use std::io::{self, BufRead, BufReader, Read, Stdin, Write};
+
+struct Io {
+    input: BufReader<Stdin>,
+    output: io::Stdout,
+}
+
+impl Io {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0u8];
+        match self.input.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let trimmed = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(trimmed);
+        Ok(Some(line))
+    }
+
+    fn read_word(&mut self) -> io::Result<Option<String>> {
+        let mut word = String::new();
+        loop {
+            let buffer = self.input.fill_buf()?;
+            if buffer.is_empty() {
+                break;
+            }
+
+            let byte = buffer[0];
+            if byte.is_ascii_whitespace() {
+                self.input.consume(1);
+                if word.is_empty() { continue; }
+                break;
+            }
+
+            word.push(byte as char);
+            self.input.consume(1);
+        }
+
+        Ok(if word.is_empty() { None } else { Some(word) })
+    }
+
+    fn write(&mut self, text: &str, measure: usize) -> Result<(), String> {
+        write!(self.output, "{}", text).and_then(|_| self.output.flush())
+            .map_err(|e| io_error(e, measure))
+    }
+}
+
+fn io_error(e: io::Error, measure: usize) -> String {
+    format!("Input or output failed in measure {}: {}", measure, e)
+}
+
+#[derive(Default)]
+struct Channels(BTreeMap<&'static str, VecDeque<(usize, i64)>>);
+
+impl Channels {
+    fn send(&mut self, name: &'static str, time: usize, value: i64) {
+        self.0.entry(name).or_default().push_back((time, value));
+    }
+
+    fn receive(&mut self, name: &'static str) -> Option<(usize, i64)> {
+        self.0.get_mut(name).and_then(|queue| queue.pop_front())
+    }
+
+    fn has_message(&self, name: &'static str) -> bool {
+        self.0.get(name).is_some_and(|queue| !queue.is_empty())
+    }
+}
+
+#[derive(Default)]
+struct Machine {
+    stack: Vec<i64>,
+    tape: BTreeMap<usize, i64>,
+    halted: bool,
+    clock: usize,
+    waiting: Option<&'static str>,
+}
+
+// One method per instruction, named after it. The ones that can fail take
+// the measure for the error and skip_bar says whether to skip.
+impl Machine {
+    fn take(&mut self, measure: usize) -> Result<i64, String> {
+        self.stack.pop().ok_or_else(|| format!("Stack underflow in measure {}", measure))
+    }
+
+    fn take_pair(&mut self, measure: usize) -> Result<(i64, i64), String> {
+        let b = self.take(measure)?;
+        let a = self.take(measure)?;
+        Ok((a, b))
+    }
+
+    fn address(&mut self, measure: usize) -> Result<usize, String> {
+        let address = self.take(measure)?;
+        usize::try_from(address)
+            .map_err(|_| format!("Invalid memory address {} in measure {}", address, measure))
+    }
+
+    fn push(&mut self, value: i64) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self, measure: usize) -> Result<(), String> {
+        self.take(measure).map(|_| ())
+    }
+
+    fn dup(&mut self, measure: usize) -> Result<(), String> {
+        let a = self.take(measure)?;
+        self.stack.extend([a, a]);
+        Ok(())
+    }
+
+    fn swap(&mut self, measure: usize) -> Result<(), String> {
+        let (a, b) = self.take_pair(measure)?;
+        self.stack.extend([b, a]);
+        Ok(())
+    }
+
+    fn over(&mut self, measure: usize) -> Result<(), String> {
+        let (a, b) = self.take_pair(measure)?;
+        self.stack.extend([a, b, a]);
+        Ok(())
+    }
+
+    fn rot(&mut self, measure: usize) -> Result<(), String> {
+        let (b, c) = self.take_pair(measure)?;
+        let a = self.take(measure)?;
+        self.stack.extend([b, c, a]);
+        Ok(())
+    }
+
+    fn add(&mut self, measure: usize) -> Result<(), String> {
+        let (a, b) = self.take_pair(measure)?;
+        self.stack.push(a.wrapping_add(b));
+        Ok(())
+    }
+
+    fn sub(&mut self, measure: usize) -> Result<(), String> {
+        let (a, b) = self.take_pair(measure)?;
+        self.stack.push(a.wrapping_sub(b));
+        Ok(())
+    }
+
+    fn mul(&mut self, measure: usize) -> Result<(), String> {
+        let (a, b) = self.take_pair(measure)?;
+        self.stack.push(a.wrapping_mul(b));
+        Ok(())
+    }
+
+    fn div(&mut self, measure: usize) -> Result<(), String> {
+        let (a, b) = self.take_pair(measure)?;
+        if b == 0 {
+            return Err(format!("Division by zero in measure {}", measure));
+        }
+        self.stack.push(a.wrapping_div(b));
+        Ok(())
+    }
+
+    fn r#mod(&mut self, measure: usize) -> Result<(), String> {
+        let (a, b) = self.take_pair(measure)?;
+        if b == 0 {
+            return Err(format!("Division by zero in measure {}", measure));
+        }
+        self.stack.push(a.wrapping_rem(b));
+        Ok(())
+    }
+
+    fn eq(&mut self, measure: usize) -> Result<(), String> {
+        let (a, b) = self.take_pair(measure)?;
+        self.stack.push((a == b) as i64);
+        Ok(())
+    }
+
+    fn lt(&mut self, measure: usize) -> Result<(), String> {
+        let (a, b) = self.take_pair(measure)?;
+        self.stack.push((a < b) as i64);
+        Ok(())
+    }
+
+    fn gt(&mut self, measure: usize) -> Result<(), String> {
+        let (a, b) = self.take_pair(measure)?;
+        self.stack.push((a > b) as i64);
+        Ok(())
+    }
+
+    fn not(&mut self, measure: usize) -> Result<(), String> {
+        let a = self.take(measure)?;
+        self.stack.push((a == 0) as i64);
+        Ok(())
+    }
+
+    fn load(&mut self, measure: usize) -> Result<(), String> {
+        let address = self.address(measure)?;
+        self.stack.push(self.tape.get(&address).copied().unwrap_or(0));
+        Ok(())
+    }
+
+    fn store(&mut self, measure: usize) -> Result<(), String> {
+        let address = self.address(measure)?;
+        let value = self.take(measure)?;
+        self.tape.insert(address, value);
+        Ok(())
+    }
+
+    fn read_byte(&mut self, measure: usize, io: &mut Io) -> Result<(), String> {
+        let byte = io.read_byte().map_err(|e| io_error(e, measure))?;
+        self.stack.push(byte.map_or(-1, i64::from));
+        Ok(())
+    }
+
+    fn read_number(&mut self, measure: usize, io: &mut Io) -> Result<(), String> {
+        let number = match io.read_word().map_err(|e| io_error(e, measure))? {
+            Some(word) => word.parse::<i64>().map_err(|_| {
+                format!("Expected a number but read \"{}\" in measure {}", word, measure)
+            })?,
+            None => 0,
+        };
+        self.stack.push(number);
+        Ok(())
+    }
+
+    fn read_line(&mut self, measure: usize, io: &mut Io) -> Result<(), String> {
+        let line = io.read_line().map_err(|e| io_error(e, measure))?.unwrap_or_default();
+        let chars: Vec<i64> = line.chars().map(|c| c as i64).collect();
+        self.stack.extend(chars.iter().rev());
+        self.stack.push(chars.len() as i64);
+        Ok(())
+    }
+
+    fn write_char(&mut self, measure: usize, io: &mut Io) -> Result<(), String> {
+        let value = self.take(measure)?;
+        let c = u32::try_from(value).ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| format!("{} is not a character in measure {}", value, measure))?;
+        io.write(&c.to_string(), measure)
+    }
+
+    fn write_number(&mut self, measure: usize, io: &mut Io) -> Result<(), String> {
+        let value = self.take(measure)?;
+        io.write(&value.to_string(), measure)
+    }
+
+    fn skip_bar(&mut self, measure: usize) -> Result<bool, String> {
+        Ok(self.take(measure)? == 0)
+    }
+
+    fn halt(&mut self) {
+        self.halted = true;
+    }
+
+    // Any instruction by its number, for interval mode notes that are only
+    // decoded as they play
+    fn execute(&mut self, instruction: u8, operand: i64, measure: usize, io: &mut Io)
+        -> Result<bool, String> {
+        match instruction {
+            0 => {},
+            1 => self.push(operand),
+            2 => self.pop(measure)?,
+            3 => self.dup(measure)?,
+            4 => self.swap(measure)?,
+            5 => self.over(measure)?,
+            6 => self.rot(measure)?,
+            7 => self.add(measure)?,
+            8 => self.sub(measure)?,
+            9 => self.mul(measure)?,
+            10 => self.div(measure)?,
+            11 => self.r#mod(measure)?,
+            12 => self.eq(measure)?,
+            13 => self.lt(measure)?,
+            14 => self.gt(measure)?,
+            15 => self.not(measure)?,
+            16 => self.load(measure)?,
+            17 => self.store(measure)?,
+            18 => self.read_byte(measure, io)?,
+            19 => self.read_number(measure, io)?,
+            20 => self.read_line(measure, io)?,
+            21 => self.write_char(measure, io)?,
+            22 => self.write_number(measure, io)?,
+            23 => return self.skip_bar(measure),
+            _ => self.halt(),
+        }
+        Ok(false)
+    }
+}
+
+// One player: which program it is in, the next op and what calls left behind
+#[derive(Default)]
+struct Thread {
+    machine: Machine,
+    program: usize,
+    pc: usize,
+    repeats: Vec<(usize, usize)>,
+    frames: Vec<(usize, usize, Vec<(usize, usize)>)>,
+    previous: Option<isize>,
+}
+
+impl Thread {
+    fn finished(&self) -> bool {
+        self.machine.halted || (self.frames.is_empty() && self.pc >= CODE_LENGTHS[self.program])
+    }
+
+    fn blocked(&self, channels: &Channels) -> bool {
+        self.machine.waiting.is_some_and(|channel| !channels.has_message(channel))
+    }
+
+    fn repeat(&mut self, at: usize, times: usize, target: usize) {
+        let left = match self.repeats.iter().position(|(end, _)| *end == at) {
+            Some(idx) => self.repeats.remove(idx).1,
+            None => times.saturating_sub(1),
+        };
+        if left > 0 {
+            self.repeats.push((at, left - 1));
+            self.pc = target;
+        }
+    }
+
+    fn call(&mut self, program: usize, target: usize, measure: usize) -> Result<(), String> {
+        if self.frames.len() >= MAX_CALL_DEPTH {
+            return Err(format!("Call stack overflow, more than {} calls deep in measure {}",
+                MAX_CALL_DEPTH, measure));
+        }
+        self.frames.push((self.program, self.pc, std::mem::take(&mut self.repeats)));
+        self.program = program;
+        self.pc = target;
+        Ok(())
+    }
+
+    fn ret(&mut self) {
+        match self.frames.pop() {
+            Some((program, pc, repeats)) => {
+                self.program = program;
+                self.pc = pc;
+                self.repeats = repeats;
+            },
+            None => self.pc = CODE_LENGTHS[self.program],
+        }
+    }
+
+    fn send(&mut self, channel: &'static str, measure: usize, channels: &mut Channels)
+        -> Result<(), String> {
+        let value = self.machine.take(measure)?;
+        channels.send(channel, self.machine.clock, value);
+        Ok(())
+    }
+
+    fn recv(&mut self, at: usize, channel: &'static str, channels: &mut Channels) {
+        match channels.receive(channel) {
+            Some((sent, value)) => {
+                self.machine.stack.push(value);
+                self.machine.clock = self.machine.clock.max(sent);
+                self.machine.waiting = None;
+            },
+            None => {
+                self.pc = at;
+                self.machine.waiting = Some(channel);
+            },
+        }
+    }
+
+    // Moves the clock on, stopping at the end of time like the VM does
+    fn tick(&mut self, ticks: usize) {
+        self.machine.clock = self.machine.clock.saturating_add(ticks);
+    }
+
+    // An interval mode note, skip_to is where a skip_bar would go
+    fn interval(&mut self, semitone: isize, chord: bool, measure: usize, skip_to: usize,
+        io: &mut Io) -> Result<(), String> {
+        if let Some(previous) = self.previous.replace(semitone) {
+            let interval = semitone - previous;
+            let slots = if chord { &CHORDS[self.program] } else { &NOTES[self.program] };
+            let operand = (interval.abs() / 12) as i64 + 1;
+            if self.machine.execute(slots[interval.rem_euclid(12) as usize], operand, measure, io)? {
+                self.pc = skip_to;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1)
+}
+
+// Always steps whichever player is furthest behind, the first one on a tie
+fn main() {
+    let mut io = Io { input: BufReader::new(io::stdin()), output: io::stdout() };
+    let mut channels = Channels::default();
+    let mut threads: Vec<Thread> = PLAYERS.iter()
+        .map(|&program| Thread { program, ..Thread::default() })
+        .collect();
+
+    loop {
+        let next = threads.iter()
+            .enumerate()
+            .filter(|(_, thread)| !thread.finished() && !thread.blocked(&channels))
+            .min_by_key(|(index, thread)| (thread.machine.clock, *index))
+            .map(|(index, _)| index);
+        let Some(index) = next else {
+            if threads.iter().all(Thread::finished) {
+                break;
+            }
+            let waiting: Vec<&str> = threads.iter()
+                .filter_map(|thread| thread.machine.waiting)
+                .collect();
+            fail(format!("Every instrument is waiting for a message on {}", waiting.join(", ")));
+        };
+        if let Err(e) = step(&mut threads[index], &mut channels, &mut io) {
+            fail(e);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+pub mod visit {
+use crate::data_types::dt::*;
+
+/*
+ * Walks the parsed music without changing it. Every method has a default that
+ * keeps walking, so a visitor only needs to write the ones it cares about. To
+ * still walk the children of something you override, call the walk_ function
+ * of the same name.
+ */
+pub trait Visitor {
+    fn visit_line(&mut self, line: &Line) {
+        walk_line(self, line);
+    }
+
+    fn visit_bar(&mut self, bar: &Bar) {
+        walk_bar(self, bar);
+    }
+
+    fn visit_event(&mut self, event: &Event) {
+        walk_event(self, event);
+    }
+
+    fn visit_note(&mut self, _note: &NoteEvent) {}
+
+    fn visit_rest(&mut self, _rest: &RestEvent) {}
+
+    fn visit_chord(&mut self, _chord: &ChordEvent) {}
+
+    fn visit_barline(&mut self, _barline: &Barline) {}
+
+    fn visit_direction(&mut self, _direction: &Direction) {}
+
+    fn visit_tuplet(&mut self, tuplet: &TupletGroup) {
+        walk_tuplet(self, tuplet);
+    }
+}
+
+pub fn walk_lines<V: Visitor + ?Sized>(visitor: &mut V, lines: &[Line]) {
+    for line in lines {
+        visitor.visit_line(line);
+    }
+}
+
+pub fn walk_line<V: Visitor + ?Sized>(visitor: &mut V, line: &Line) {
+    for bar in line.contents.iter() {
+        visitor.visit_bar(bar);
+    }
+}
+
+pub fn walk_bar<V: Visitor + ?Sized>(visitor: &mut V, bar: &Bar) {
+    for event in bar.events.iter() {
+        visitor.visit_event(event);
+    }
+}
+
+pub fn walk_event<V: Visitor + ?Sized>(visitor: &mut V, event: &Event) {
+    match event {
+        Event::Note(note) => visitor.visit_note(note),
+        Event::Rest(rest) => visitor.visit_rest(rest),
+        Event::Chord(chord) => visitor.visit_chord(chord),
+        Event::Barline(barline) => visitor.visit_barline(barline),
+        Event::Direction(direction) => visitor.visit_direction(direction),
+        Event::Tuplet(tuplet) => visitor.visit_tuplet(tuplet),
+    }
+}
+
+pub fn walk_tuplet<V: Visitor + ?Sized>(visitor: &mut V, tuplet: &TupletGroup) {
+    for event in tuplet.events.iter() {
+        visitor.visit_event(event);
+    }
+}
+
+/*
+ * Changes the parsed music in place. Each event is handed over and replaced
+ * with whatever comes back, so a pass can drop an event (return nothing),
+ * keep it, or split it into several. The events inside a tuplet group are
+ * rewritten before the group itself.
+ */
+pub trait Rewriter {
+    fn rewrite_event(&mut self, event: Event) -> Vec<Event> {
+        vec![event]
+    }
+
+    fn rewrite_bar(&mut self, bar: &mut Bar) {
+        let events = std::mem::take(&mut bar.events);
+        bar.events = self.rewrite_events(events);
+    }
+
+    fn rewrite_events(&mut self, events: Vec<Event>) -> Vec<Event> {
+        events.into_iter()
+            .flat_map(|event| match event {
+                Event::Tuplet(mut tuplet) => {
+                    tuplet.events = self.rewrite_events(tuplet.events);
+                    self.rewrite_event(Event::Tuplet(tuplet))
+                },
+                event => self.rewrite_event(event),
+            })
+            .collect()
+    }
+
+    fn rewrite_lines(&mut self, lines: &mut [Line]) {
+        for line in lines.iter_mut() {
+            for bar in line.contents.iter_mut() {
+                self.rewrite_bar(bar);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parser::parse_file;
+
+    #[derive(Default)]
+    struct Counter {
+        notes: usize,
+        chords: usize,
+        barlines: Vec<BarlineKind>,
+        directions: Vec<String>,
+    }
+
+    impl Visitor for Counter {
+        fn visit_note(&mut self, _note: &NoteEvent) {
+            self.notes += 1;
+        }
+
+        fn visit_chord(&mut self, _chord: &ChordEvent) {
+            self.chords += 1;
+        }
+
+        fn visit_barline(&mut self, barline: &Barline) {
+            self.barlines.push(barline.kind.clone());
+        }
+
+        fn visit_direction(&mut self, direction: &Direction) {
+            self.directions.push(direction.text.clone());
+        }
+    }
+
+    #[test]
+    fn test_visit_counts_events() {
+        let lines = parse_file("testing_resources/chords_and_repeats.inst".to_string()).unwrap();
+        let mut counter = Counter::default();
+        walk_lines(&mut counter, &lines);
+
+        assert_eq!(6, counter.notes);
+        assert_eq!(2, counter.chords);
+        assert_eq!(vec![BarlineKind::RepeatStart, BarlineKind::Single,
+            BarlineKind::RepeatEnd { times: Some(2) }], counter.barlines);
+        assert!(counter.directions.is_empty());
+
+        let triplets = parse_file("testing_resources/triplets.inst".to_string()).unwrap();
+        let mut counter = Counter::default();
+        walk_lines(&mut counter, &triplets);
+        assert_eq!(9, counter.notes);
+    }
+
+    // Splits every chord into its notes, lowest first
+    struct Arpeggiate;
+
+    impl Rewriter for Arpeggiate {
+        fn rewrite_event(&mut self, event: Event) -> Vec<Event> {
+            match event {
+                Event::Chord(chord) => chord.pitches.into_iter()
+                    .map(|pitch| Event::Note(NoteEvent {
+                        pitch,
+                        duration: chord.duration.clone(),
+                        column: chord.column,
+                    }))
+                    .collect(),
+                event => vec![event],
+            }
+        }
+    }
+
+    #[test]
+    fn test_visit_rewrite_chords() {
+        let mut lines = parse_file("testing_resources/chords_and_repeats.inst".to_string())
+            .unwrap();
+        let before: Vec<Note> = lines[0].contents[0].pitches();
+        Arpeggiate.rewrite_lines(&mut lines);
+
+        let mut counter = Counter::default();
+        walk_lines(&mut counter, &lines);
+        assert_eq!(0, counter.chords);
+        assert_eq!(11, counter.notes);
+        assert_eq!(before, lines[0].contents[0].pitches());
+    }
+}
+}
@@ -0,0 +1,506 @@
+pub mod vm {
+use crate::bytecode::bytecode::{EventKind, Module, Op};
+use crate::data_types::dt::*;
+use crate::instructions::instructions::{Operation, MAX_SEMITONE};
+use crate::interpreter::interpreter::{Channels, Flow, Limits, Machine};
+use crate::streams::streams::Streams;
+use crate::trace::trace::{TraceRecord, Tracer};
+use std::time::Instant;
+
+/*
+ * What a call leaves behind, the same as an interpreter Frame but with ops
+ * instead of program counters.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VmFrame {
+    pub program: usize,
+    pub pc: usize,
+    pub repeats: Vec<(usize, usize)>,
+}
+
+/*
+ * One player of a compiled module. The machine holds the stack, tape, clock
+ * and whether it halted or is waiting, where the thread is in the code lives
+ * here instead: pc is the next op of programs[program]. Previous is the last
+ * pitch an interval mode note played, in semitones. Events is how many ops
+ * that came from an event on the score the thread has run, see Origin.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Thread {
+    pub machine: Machine,
+    pub program: usize,
+    pub pc: usize,
+    pub repeats: Vec<(usize, usize)>,
+    pub frames: Vec<VmFrame>,
+    pub previous: Option<isize>,
+    pub events: u64,
+}
+
+/*
+ * Runs a compiled module. It plays exactly the way the interpreter and an
+ * ensemble would have played the score it was compiled from, one thread per
+ * player, always stepping whichever is furthest behind on its clock.
+ */
+#[derive(Debug)]
+pub struct Vm {
+    pub module: Module,
+    pub threads: Vec<Thread>,
+    pub channels: Channels,
+    pub streams: Streams,
+    // When the first op was run
+    started: Option<Instant>,
+    // The program and op being run, or last run
+    playing: (usize, usize),
+    // Where a record of every event played goes, if anywhere
+    pub tracer: Option<Tracer>,
+}
+
+impl Vm {
+    pub fn new(module: Module) -> Self {
+        let threads = module.players.iter()
+            .map(|&program| Thread { program, ..Thread::default() })
+            .collect();
+        Vm {
+            module,
+            threads,
+            channels: Channels::default(),
+            streams: Streams::default(),
+            started: None,
+            playing: (0, 0),
+            tracer: None,
+        }
+    }
+
+    pub fn with_streams(mut self, streams: Streams) -> Self {
+        self.streams = streams;
+        self
+    }
+
+    pub fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /*
+     * Sets the limits of every thread's machine.
+     */
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        for thread in &mut self.threads {
+            thread.machine.limits = limits;
+        }
+        self
+    }
+
+    pub fn thread_finished(&self, thread: &Thread) -> bool {
+        thread.machine.halted || (thread.frames.is_empty()
+            && thread.pc >= self.module.programs[thread.program].code.len())
+    }
+
+    /*
+     * True while a thread's "recv:" has nothing to receive.
+     */
+    pub fn blocked(&self, thread: &Thread) -> bool {
+        thread.machine.waiting.as_ref().is_some_and(|channel| !self.channels.has_message(channel))
+    }
+
+    pub fn finished(&self) -> bool {
+        self.threads.iter().all(|thread| self.thread_finished(thread))
+    }
+
+    /*
+     * The thread that goes next, None once nobody can.
+     */
+    pub fn next_thread(&self) -> Option<usize> {
+        self.threads.iter()
+            .enumerate()
+            .filter(|(_, thread)| !self.thread_finished(thread) && !self.blocked(thread))
+            .min_by_key(|(index, thread)| (thread.machine.clock, *index))
+            .map(|(index, _)| index)
+    }
+
+    /*
+     * Where an op is on the score.
+     */
+    fn location(&self, program: usize, pc: usize) -> SourceLocation {
+        let program = &self.module.programs[program];
+        SourceLocation {
+            file: program.file.clone(),
+            measure: program.measure_number(pc),
+            column: program.origins.get(pc).map_or(0, |origin| origin.column),
+        }
+    }
+
+    /*
+     * Puts an error from the last step of a thread together with the op it
+     * happened at and the calls that led there. A call is the op just
+     * before where its frame goes back to.
+     */
+    pub fn locate(&self, thread: usize, error: RuntimeError) -> RuntimeFailure {
+        if let RuntimeError::Deadlock(_) = error {
+            return error.into();
+        }
+        let (program, pc) = self.playing;
+        RuntimeFailure {
+            error,
+            location: Some(self.location(program, pc)),
+            calls: self.threads[thread].frames.iter()
+                .rev()
+                .map(|frame| self.location(frame.program, frame.pc.saturating_sub(1)))
+                .collect(),
+        }
+    }
+
+    /*
+     * Runs one op of the next thread. Returns false once every thread is
+     * done and fails if the ones left are all waiting on each other.
+     */
+    pub fn step(&mut self) -> Result<bool, RuntimeError> {
+        let Some(index) = self.next_thread() else {
+            if self.finished() {
+                return Ok(false);
+            }
+
+            let waiting: Vec<String> = self.threads.iter()
+                .filter_map(|thread| thread.machine.waiting.clone())
+                .collect();
+            return Err(RuntimeError::Deadlock(waiting.join(", ")));
+        };
+
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let thread = &mut self.threads[index];
+        let program = &self.module.programs[thread.program];
+        let pc = thread.pc;
+        if program.origins[pc].event.is_some() {
+            thread.events += 1;
+        }
+        let measure = program.measure_number(pc);
+        self.playing = (thread.program, pc);
+        thread.pc += 1;
+        let before = self.tracer.as_ref().map(|_| (thread.machine.stack.clone(), thread.machine.clock));
+        let mut decoded = None;
+
+        let ticks = match &program.code[pc] {
+            Op::Play { operation, ticks } => {
+                decoded = Some(*operation);
+                if thread.machine.execute(*operation, measure, &mut self.streams)? == Flow::SkipBar {
+                    thread.pc = program.skip_target(pc);
+                }
+                *ticks
+            },
+            Op::Interval { semitone, chord, ticks } => {
+                // Files are checked when they are read but a Module built in
+                // Rust could hold any pitch
+                if !(-MAX_SEMITONE..=MAX_SEMITONE).contains(&(*semitone as i64)) {
+                    return Err(RuntimeError::InvalidInstruction(
+                        format!("a note {} semitones from C0", semitone), measure));
+                }
+                if let Some(previous) = thread.previous.replace(*semitone) {
+                    let interval = semitone - previous;
+                    let slots = if *chord { &program.table.chords } else { &program.table.notes };
+                    let operation = Operation {
+                        instruction: slots[interval.rem_euclid(12) as usize],
+                        operand: (interval.abs() / 12) as i64 + 1,
+                    };
+                    decoded = Some(operation);
+                    if thread.machine.execute(operation, measure, &mut self.streams)? == Flow::SkipBar {
+                        thread.pc = program.skip_target(pc);
+                    }
+                }
+                *ticks
+            },
+            Op::Wait { ticks } => *ticks,
+            Op::Repeat { times, target } => {
+                let left = match thread.repeats.iter().position(|(at, _)| *at == pc) {
+                    Some(idx) => thread.repeats.remove(idx).1,
+                    None => times.saturating_sub(1),
+                };
+                if left > 0 {
+                    thread.repeats.push((pc, left - 1));
+                    thread.pc = *target;
+                }
+                0
+            },
+            Op::RepeatWhile { target } => {
+                if thread.machine.pop(measure)? != 0 {
+                    thread.pc = *target;
+                }
+                0
+            },
+            Op::Call { program, target } => {
+                let limit = thread.machine.limits.calls;
+                if thread.frames.len() >= limit {
+                    return Err(RuntimeError::StackOverflow(limit, measure));
+                }
+                thread.frames.push(VmFrame {
+                    program: thread.program,
+                    pc: thread.pc,
+                    repeats: std::mem::take(&mut thread.repeats),
+                });
+                thread.program = *program;
+                thread.pc = *target;
+                0
+            },
+            Op::Return => {
+                match thread.frames.pop() {
+                    Some(frame) => {
+                        thread.program = frame.program;
+                        thread.pc = frame.pc;
+                        thread.repeats = frame.repeats;
+                    },
+                    None => thread.pc = program.code.len(),
+                }
+                0
+            },
+            Op::Send(channel) => {
+                let value = thread.machine.pop(measure)?;
+                self.channels.send(channel, thread.machine.clock, value);
+                0
+            },
+            Op::Recv(channel) => {
+                match self.channels.receive(channel) {
+                    Some((sent, value)) => {
+                        thread.machine.stack.push(value);
+                        thread.machine.check_stack(measure)?;
+                        thread.machine.clock = thread.machine.clock.max(sent);
+                        thread.machine.waiting = None;
+                    },
+                    None => {
+                        thread.pc = pc;
+                        thread.machine.waiting = Some(channel.clone());
+                    },
+                }
+                0
+            },
+        };
+        // Ticks come from the file, which could hold anything
+        thread.machine.clock = thread.machine.clock.saturating_add(ticks);
+
+        // Records are written for the events on the score the way the
+        // interpreter writes them, so ops that didn't come from one have none
+        let origin = &program.origins[pc];
+        if let (Some(tracer), Some((stack_before, time)), Some(kind)) =
+            (&self.tracer, before, origin.event) {
+            if thread.machine.waiting.is_none() {
+                let timed = matches!(kind, EventKind::Note | EventKind::Chord | EventKind::Rest);
+                let record = TraceRecord {
+                    file: program.file.clone(),
+                    measure,
+                    column: origin.column,
+                    event: kind.name().to_string(),
+                    pitch: origin.pitch.clone(),
+                    duration: program.code[pc].ticks().filter(|_| timed),
+                    text: origin.text.clone(),
+                    instruction: decoded.map(|o| format!("{:?}", o.instruction)),
+                    operand: decoded.map(|o| o.operand),
+                    stack_before,
+                    stack_after: thread.machine.stack.clone(),
+                    time,
+                };
+                tracer.record(&record).map_err(|e| RuntimeError::Io(e.to_string(), measure))?;
+            }
+        }
+        thread.machine.limits.check_progress(thread.events, started, thread.machine.clock, measure)?;
+        Ok(true)
+    }
+
+    pub fn run(&mut self) -> Result<(), RuntimeFailure> {
+        loop {
+            let thread = self.next_thread();
+            match self.step() {
+                Ok(true) => {},
+                Ok(false) => return Ok(()),
+                Err(error) => return Err(match thread {
+                    Some(index) => self.locate(index, error),
+                    None => error.into(),
+                }),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::bytecode::compile_file;
+    use crate::interpreter::interpreter::Interpreter;
+    use crate::optimize::optimize::{optimize, Passes};
+    use crate::parser::parser::parse_file;
+    use crate::project::project::Project;
+    use crate::streams::streams::SharedOutput;
+
+    // How a run ended, the machine it left and what it wrote
+    type Played = (Result<(), RuntimeError>, Machine, String);
+
+    // Plays a file both ways
+    fn both(file: &str, input: &'static str) -> (Played, Played) {
+        let lines = parse_file(file.to_string()).unwrap();
+        let output = SharedOutput::new();
+        let mut interpreter = Interpreter::default()
+            .with_streams(Streams::new(input.as_bytes(), output.clone()));
+        let interpreted = interpreter.run(&lines).map_err(|failure| failure.error);
+        let mut machine = interpreter.machine;
+        machine.pc = Default::default();
+        machine.program = 0;
+        machine.repeats.clear();
+        machine.frames.clear();
+
+        let vm_output = SharedOutput::new();
+        let mut vm = Vm::new(compile_file(file, &lines).unwrap())
+            .with_streams(Streams::new(input.as_bytes(), vm_output.clone()));
+        let compiled = vm.run().map_err(|failure| failure.error);
+        ((interpreted, machine, output.text()),
+            (compiled, vm.threads.remove(0).machine, vm_output.text()))
+    }
+
+    #[test]
+    fn test_vm_plays_like_the_interpreter() {
+        for (file, input) in [("arithmetic.inst", ""), ("skip_and_halt.inst", ""),
+            ("tape_and_repeats.inst", ""), ("chords_and_repeats.inst", ""),
+            ("cmaj_scale_quarternotes.inst", ""), ("echo.inst", "21\nAB"), ("hello.inst", ""),
+            ("triplets.inst", ""), ("rests.inst", ""), ("recursion.inst", "")] {
+            let (interpreted, compiled) = both(&format!("testing_resources/{}", file), input);
+            assert_eq!(interpreted, compiled, "{}", file);
+        }
+    }
+
+    #[test]
+    fn test_vm_traces_like_the_interpreter() {
+        // Without optimizing, every event gets the same record compiled or not
+        for (file, input) in [("arithmetic.inst", ""), ("skip_and_halt.inst", ""),
+            ("tape_and_repeats.inst", ""), ("chords_and_repeats.inst", ""), ("echo.inst", "21\nAB"),
+            ("hello.inst", ""), ("rhythms.inst", ""), ("rests.inst", ""),
+            ("annotated_cmaj_scale.inst", ""), ("recursion.inst", "")] {
+            let file = format!("testing_resources/{}", file);
+            let lines = parse_file(file.clone()).unwrap();
+            let (trace, vm_trace) = (SharedOutput::new(), SharedOutput::new());
+            let mut interpreter = Interpreter::default()
+                .with_streams(Streams::new(input.as_bytes(), std::io::sink()));
+            interpreter.tracer = Some(Tracer::new(trace.clone(), &file));
+            let _ = interpreter.run(&lines);
+
+            let module = Module::from_bytes(&compile_file(&file, &lines).unwrap().to_bytes())
+                .unwrap();
+            let _ = Vm::new(module)
+                .with_streams(Streams::new(input.as_bytes(), std::io::sink()))
+                .with_tracer(Tracer::new(vm_trace.clone(), &file))
+                .run();
+            assert!(!trace.text().is_empty(), "{}", file);
+            assert_eq!(trace.text(), vm_trace.text(), "{}", file);
+        }
+    }
+
+    #[test]
+    fn test_vm_projects() {
+        for root in ["calls", "clock", "custom_tables", "duet", "interval_melody",
+            "interval_melody_transposed"] {
+            let project = Project::load(format!("testing_resources/projects/{}", root)).unwrap();
+            let (mut ensemble, _) = project.ensemble().unwrap();
+            let output = SharedOutput::new();
+            ensemble.streams = Streams::new(std::io::empty(), output.clone());
+            ensemble.run().unwrap();
+
+            let trace = SharedOutput::new();
+            project.run_traced(Some(&Tracer::new(trace.clone(), root))).unwrap();
+
+            let (vm_output, vm_trace) = (SharedOutput::new(), SharedOutput::new());
+            let mut vm = Vm::new(project.compile().unwrap())
+                .with_streams(Streams::new(std::io::empty(), vm_output.clone()))
+                .with_tracer(Tracer::new(vm_trace.clone(), root));
+            vm.run().unwrap();
+
+            assert_eq!(output.text(), vm_output.text(), "{}", root);
+            assert_eq!(trace.text(), vm_trace.text(), "{}", root);
+            for (player, thread) in ensemble.players.iter().zip(&vm.threads) {
+                assert_eq!(player.interpreter.machine.stack, thread.machine.stack, "{}", root);
+                assert_eq!(player.interpreter.machine.clock, thread.machine.clock, "{}", root);
+            }
+        }
+    }
+
+    #[test]
+    fn test_vm_limits() {
+        let limited = |file: &str, limits: Limits| {
+            let lines = parse_file(file.to_string()).unwrap();
+            Vm::new(compile_file(file, &lines).unwrap()).with_limits(limits).run()
+                .map_err(|failure| failure.error)
+        };
+        assert_eq!(Err(RuntimeError::EventLimit(100, 1)),
+            limited("testing_resources/check/endless.inst",
+                Limits { events: Some(100), ..Limits::default() }));
+        assert_eq!(Err(RuntimeError::StackOverflow(10, 2)),
+            limited("testing_resources/recursion.inst", Limits { calls: 10, ..Limits::default() }));
+
+        // Events are counted the same way compiled or not, barlines and
+        // directions included, and optimizing can only make a piece need fewer
+        let file = "testing_resources/hello.inst";
+        let lines = parse_file(file.to_string()).unwrap();
+        let plain = compile_file(file, &lines).unwrap();
+        let mut optimized = plain.clone();
+        optimize(&mut optimized, Passes::default());
+        for (events, played) in [(17, Ok(())), (16, Err(RuntimeError::EventLimit(16, 3)))] {
+            let limits = Limits { events: Some(events), ..Limits::default() };
+            let run = |module: &Module| Vm::new(module.clone()).with_limits(limits)
+                .with_streams(Streams::new(std::io::empty(), std::io::sink()))
+                .run().map_err(|failure| failure.error);
+            let interpreted = Interpreter::default().with_limits(limits)
+                .with_streams(Streams::new(std::io::empty(), std::io::sink()))
+                .run(&lines).map_err(|failure| failure.error);
+            assert_eq!(played, interpreted);
+            assert_eq!(played, run(&plain));
+            assert_eq!(Ok(()), run(&optimized));
+        }
+    }
+
+    #[test]
+    fn test_vm_long_ops() {
+        // The clock stops at the end of time instead of wrapping around
+        let file = "testing_resources/arithmetic.inst";
+        let mut module = compile_file(file, &parse_file(file.to_string()).unwrap()).unwrap();
+        for op in module.programs[0].code.iter_mut() {
+            if let Op::Play { ticks, .. } = op {
+                *ticks = usize::MAX;
+            }
+        }
+        let mut vm = Vm::new(module);
+        vm.run().unwrap();
+        assert_eq!(usize::MAX, vm.threads[0].machine.clock);
+    }
+
+    #[test]
+    fn test_vm_failure_location() {
+        // Recursion calls itself from measure 2 until the call limit stops
+        // it, the first call is the one in measure 1
+        let file = "testing_resources/recursion.inst";
+        let lines = parse_file(file.to_string()).unwrap();
+        let limits = Limits { calls: 2, ..Limits::default() };
+        let failure = Vm::new(compile_file(file, &lines).unwrap()).with_limits(limits).run()
+            .unwrap_err();
+        let at = |measure: usize, column: usize| SourceLocation {
+            file: file.to_string(),
+            measure,
+            column,
+        };
+        assert_eq!(RuntimeFailure {
+            error: RuntimeError::StackOverflow(2, 2),
+            location: Some(at(2, 5)),
+            calls: vec![at(2, 5), at(1, 1)],
+        }, failure);
+        assert_eq!(9, failure.exit_code());
+        assert_eq!(Err(failure), Interpreter::default().with_limits(limits).with_file(file)
+            .run(&lines));
+
+        // A pitch too far out for the table to measure a leap from
+        let project = Project::load("testing_resources/projects/interval_melody").unwrap();
+        let mut module = project.compile().unwrap();
+        let interval = module.programs[0].code.iter_mut()
+            .find(|op| matches!(op, Op::Interval { .. }))
+            .unwrap();
+        *interval = Op::Interval { semitone: isize::MAX, chord: false, ticks: 0 };
+        let failure = Vm::new(module).with_streams(Streams::new(std::io::empty(), std::io::sink()))
+            .run().unwrap_err();
+        assert_eq!(RuntimeError::InvalidInstruction(
+            format!("a note {} semitones from C0", isize::MAX), 1), failure.error);
+        assert_eq!(11, failure.exit_code());
+    }
+}
+}